@@ -0,0 +1,78 @@
+//! Times threads hammering the cache with lookups of their own names, with
+//! one shard against the default count. Run with `cargo bench`.
+
+use dns_server_example::{Cache, QueryClass, QueryType, Record};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 8;
+const NAMES_PER_THREAD: usize = 256;
+const ROUNDS: usize = 200;
+
+fn name(thread: usize, i: usize) -> String {
+    format!("host{}-{}.example.com", thread, i)
+}
+
+fn run(shards: usize) -> Duration {
+    let cache = Arc::new(Cache::with_shards(shards));
+    let now = Instant::now();
+
+    for thread in 0..THREADS {
+        for i in 0..NAMES_PER_THREAD {
+            let domain = name(thread, i);
+            let record = Record::A {
+                domain: domain.clone(),
+                address: Ipv4Addr::new(192, 0, 2, 1),
+                class: QueryClass::IN,
+                ttl: 3600,
+            };
+
+            cache.insert(&domain, QueryType::A, vec![record], now);
+        }
+    }
+
+    let started = Instant::now();
+
+    let workers = (0..THREADS)
+        .map(|thread| {
+            let cache = Arc::clone(&cache);
+            // built up front so that the timed loop is all cache work
+            let names = (0..NAMES_PER_THREAD)
+                .map(|i| name(thread, i))
+                .collect::<Vec<_>>();
+
+            thread::spawn(move || {
+                for _ in 0..ROUNDS {
+                    for name in &names {
+                        assert!(cache.get(name, QueryType::A, now).is_some());
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    started.elapsed()
+}
+
+fn main() {
+    let lookups = THREADS * NAMES_PER_THREAD * ROUNDS;
+
+    for shards in [1, 16] {
+        let elapsed = run(shards);
+
+        println!(
+            "{:>2} shard(s): {} lookups on {} threads in {:?} ({:.0} lookups/s)",
+            shards,
+            lookups,
+            THREADS,
+            elapsed,
+            lookups as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
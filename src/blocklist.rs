@@ -0,0 +1,83 @@
+use crate::utils::{suffixes, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Blocklist {
+    /// lowercase names answered with NXDOMAIN
+    pub names: HashSet<String>,
+    /// whether subdomains of a blocked name are blocked as well
+    pub wildcard: bool,
+}
+
+impl Blocklist {
+    pub fn new(names: HashSet<String>, wildcard: bool) -> Self {
+        Self { names, wildcard }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, wildcard: bool) -> Result<Self> {
+        Ok(Self::from_lines(&fs::read_to_string(path)?, wildcard))
+    }
+
+    pub fn from_lines(contents: &str, wildcard: bool) -> Self {
+        let names = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.trim_end_matches('.').to_lowercase())
+            .collect();
+
+        Self::new(names, wildcard)
+    }
+
+    pub fn is_blocked(&self, qname: &str) -> bool {
+        let name = qname.trim_end_matches('.').to_lowercase();
+
+        if !self.wildcard {
+            return self.names.contains(&name);
+        }
+
+        let blocked = suffixes(&name).any(|suffix| self.names.contains(suffix));
+
+        blocked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIST: &str = "# ads\nads.example.com\n\ntracker.example.net.\n";
+
+    #[test]
+    fn exact_names_are_blocked() {
+        let blocklist = Blocklist::from_lines(LIST, false);
+
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("tracker.example.net"));
+        assert!(!blocklist.is_blocked("www.ads.example.com"));
+        assert!(!blocklist.is_blocked("example.com"));
+    }
+
+    #[test]
+    fn wildcard_blocks_subdomains() {
+        let blocklist = Blocklist::from_lines(LIST, true);
+
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("www.ads.example.com"));
+        assert!(blocklist.is_blocked("a.b.tracker.example.net."));
+        assert!(!blocklist.is_blocked("example.com"));
+        assert!(!blocklist.is_blocked("www.example.com"));
+    }
+
+    #[test]
+    fn matching_is_label_wise_and_case_insensitive() {
+        let blocklist = Blocklist::from_lines("Ample.COM\n", true);
+
+        assert!(blocklist.is_blocked("AMPLE.com"));
+        assert!(blocklist.is_blocked("Www.Ample.Com."));
+        assert!(!blocklist.is_blocked("example.com"));
+        assert!(!blocklist.is_blocked("www.example.com"));
+    }
+}
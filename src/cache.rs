@@ -0,0 +1,789 @@
+use crate::dns::{QueryType, Record};
+use crate::utils::lock;
+use crate::SHUTDOWN_POLL_INTERVAL;
+use log::debug;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_NEGATIVE_TTL: u32 = 3600;
+const STALE_TTL: u32 = 30;
+pub const DEFAULT_SHARDS: usize = 16;
+pub const DEFAULT_MAX_ENTRIES: usize = 100_000;
+pub const DEFAULT_MAX_STALE: Duration = Duration::from_secs(86400);
+// empty AAAA results in a row after which a name is taken to be IPv4-only
+const IPV4_ONLY_AFTER: u32 = 2;
+const IPV4_ONLY_SECS: u64 = 60;
+
+type Entries = HashMap<(String, QueryType), Vec<CachedRecord>>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedRecord {
+    /// the record as received, with the TTL it was cached with
+    pub record: Record,
+    /// when the record was cached
+    pub inserted: Instant,
+    /// seconds the record may be served for from insertion
+    pub ttl: u32,
+}
+
+impl CachedRecord {
+    pub fn new(record: Record, inserted: Instant) -> Self {
+        Self {
+            ttl: record.ttl(),
+            record,
+            inserted,
+        }
+    }
+
+    pub fn expiry(&self) -> Instant {
+        self.inserted + Duration::from_secs(self.ttl as u64)
+    }
+
+    /// Whole seconds left before expiry, or zero once expired.
+    pub fn remaining(&self, now: Instant) -> u32 {
+        if now >= self.expiry() {
+            return 0;
+        }
+
+        let elapsed = now.saturating_duration_since(self.inserted).as_secs();
+
+        self.ttl - elapsed as u32
+    }
+
+    fn served_with(&self, ttl: u32) -> Record {
+        let mut record = self.record.clone();
+        record.set_ttl(ttl);
+        record
+    }
+}
+
+// an entry expires with the first of its records to expire
+fn expiry(records: &[CachedRecord]) -> Option<Instant> {
+    records.iter().map(CachedRecord::expiry).min()
+}
+
+// makes room for the key if the map is full, by dropping the entry that runs
+// out first; an expired entry is always among the first to go
+fn make_room<K, V>(
+    map: &mut HashMap<K, V>,
+    key: &K,
+    capacity: usize,
+    expiry: impl Fn(&V) -> Option<Instant>,
+) where
+    K: Clone + Eq + Hash + std::fmt::Debug,
+{
+    while map.len() >= capacity && !map.contains_key(key) {
+        let first = map
+            .iter()
+            .min_by_key(|(_, value)| expiry(value))
+            .map(|(key, _)| key.clone());
+
+        match first {
+            Some(first) => {
+                debug!("cache full, evicting {:?}", first);
+                map.remove(&first);
+            }
+            None => return,
+        }
+    }
+}
+
+fn served_stale(records: &[CachedRecord]) -> Vec<Record> {
+    records
+        .iter()
+        .map(|cached| cached.served_with(cached.ttl.min(STALE_TTL)))
+        .collect()
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: Entries,
+    nxdomains: HashMap<String, CachedRecord>,
+    /// empty AAAA results in a row for each name, and when the last one came
+    ipv4_only: HashMap<String, (u32, Instant)>,
+}
+
+pub struct Cache {
+    /// names are spread across the shards by hash, each locked on its own so
+    /// that lookups of different names rarely wait on one another
+    shards: Vec<Mutex<Shard>>,
+    /// expired entries served while waiting to be looked up again
+    refreshes: Mutex<Vec<(String, QueryType)>>,
+    /// entries held across all shards, past which the first to run out is
+    /// dropped to make room
+    max_entries: usize,
+    /// how long past expiry an entry is kept to be served stale
+    max_stale: Duration,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+}
+
+impl Cache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_shards(count: usize) -> Self {
+        Self {
+            shards: (0..count.max(1)).map(|_| Mutex::default()).collect(),
+            refreshes: Mutex::default(),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            max_stale: DEFAULT_MAX_STALE,
+        }
+    }
+
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries;
+        self
+    }
+
+    pub fn with_max_stale(mut self, max_stale: Duration) -> Self {
+        self.max_stale = max_stale;
+        self
+    }
+
+    // split evenly, so a full shard evicts even while others have room
+    fn shard_capacity(&self) -> usize {
+        self.max_entries.div_ceil(self.shards.len()).max(1)
+    }
+
+    // every entry for a name is kept in the same shard, so that ANY and
+    // flushing a name only look in one
+    fn shard(&self, qname: &str) -> MutexGuard<'_, Shard> {
+        let mut hasher = DefaultHasher::new();
+        qname.hash(&mut hasher);
+
+        lock(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+
+    pub fn get(&self, qname: &str, qtype: QueryType, now: Instant) -> Option<Vec<Record>> {
+        let key = (qname.to_lowercase(), qtype);
+        let mut shard = self.shard(&key.0);
+
+        let records = shard.entries.get(&key)?;
+        let expiry = expiry(records)?;
+
+        if now >= expiry {
+            debug!("cache entry expired: {:?}", key);
+
+            if now >= expiry + self.max_stale {
+                shard.entries.remove(&key);
+            }
+
+            return None;
+        }
+
+        Some(
+            records
+                .iter()
+                .map(|cached| cached.served_with(cached.remaining(now)))
+                .collect(),
+        )
+    }
+
+    pub fn get_any(&self, qname: &str, now: Instant) -> Option<Vec<Record>> {
+        let qname = qname.to_lowercase();
+
+        let qtypes = self
+            .shard(&qname)
+            .entries
+            .keys()
+            .filter(|(name, _)| *name == qname)
+            .map(|(_, qtype)| *qtype)
+            .collect::<Vec<_>>();
+
+        let mut records = Vec::new();
+
+        for record in qtypes
+            .into_iter()
+            .filter_map(|qtype| self.get(&qname, qtype, now))
+            .flatten()
+        {
+            if !records.contains(&record) {
+                records.push(record);
+            }
+        }
+
+        (!records.is_empty()).then_some(records)
+    }
+
+    pub fn get_stale(&self, qname: &str, qtype: QueryType, now: Instant) -> Option<Vec<Record>> {
+        let key = (qname.to_lowercase(), qtype);
+        let mut shard = self.shard(&key.0);
+
+        let records = shard.entries.get(&key)?;
+
+        if now >= expiry(records)? + self.max_stale {
+            debug!("stale cache entry expired: {:?}", key);
+            shard.entries.remove(&key);
+            return None;
+        }
+
+        Some(served_stale(records))
+    }
+
+    /// Serves an entry that expired less than `window` ago, to answer with
+    /// while it is looked up again.
+    pub fn get_revalidating(
+        &self,
+        qname: &str,
+        qtype: QueryType,
+        now: Instant,
+        window: Duration,
+    ) -> Option<Vec<Record>> {
+        let key = (qname.to_lowercase(), qtype);
+        let shard = self.shard(&key.0);
+
+        let records = shard.entries.get(&key)?;
+        let expiry = expiry(records)?;
+
+        if now < expiry || now >= expiry + window.min(self.max_stale) {
+            return None;
+        }
+
+        Some(served_stale(records))
+    }
+
+    // queued at most once until taken, however often the entry is served
+    pub fn queue_refresh(&self, qname: &str, qtype: QueryType) {
+        let key = (qname.to_lowercase(), qtype);
+        let mut refreshes = lock(&self.refreshes);
+
+        if !refreshes.contains(&key) {
+            refreshes.push(key);
+        }
+    }
+
+    pub fn take_refreshes(&self) -> Vec<(String, QueryType)> {
+        std::mem::take(&mut *lock(&self.refreshes))
+    }
+
+    pub fn insert(&self, qname: &str, qtype: QueryType, records: Vec<Record>, now: Instant) {
+        if records.is_empty() || records.iter().any(|record| record.ttl() == 0) {
+            return;
+        }
+
+        let records = records
+            .into_iter()
+            .map(|record| CachedRecord::new(record, now))
+            .collect();
+        let key = (qname.to_lowercase(), qtype);
+        let capacity = self.shard_capacity();
+        let mut shard = self.shard(&key.0);
+
+        make_room(&mut shard.entries, &key, capacity, |records| {
+            expiry(records)
+        });
+        shard.entries.insert(key, records);
+    }
+
+    pub fn remove(&self, qname: &str, qtype: QueryType) -> bool {
+        let key = (qname.trim_end_matches('.').to_lowercase(), qtype);
+
+        self.shard(&key.0).entries.remove(&key).is_some()
+    }
+
+    /// Drops every type cached for the name, along with any negative entry,
+    /// returning how many entries were dropped.
+    pub fn remove_name(&self, qname: &str) -> usize {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let mut shard = self.shard(&qname);
+
+        let before = shard.entries.len();
+        shard.entries.retain(|(name, _), _| *name != qname);
+        let removed = before - shard.entries.len();
+
+        shard.ipv4_only.remove(&qname);
+
+        removed + usize::from(shard.nxdomains.remove(&qname).is_some())
+    }
+
+    pub fn get_nxdomain(&self, qname: &str, now: Instant) -> Option<Record> {
+        let key = qname.to_lowercase();
+        let mut shard = self.shard(&key);
+
+        let soa = shard.nxdomains.get(&key)?;
+
+        if now >= soa.expiry() {
+            debug!("negative cache entry expired: {:?}", key);
+            shard.nxdomains.remove(&key);
+            return None;
+        }
+
+        Some(soa.served_with(soa.remaining(now)))
+    }
+
+    pub fn insert_nxdomain(&self, qname: &str, mut soa: Record, now: Instant) {
+        // the SOA is served with the negative TTL, the lesser of its own TTL
+        // and its minimum field (RFC 2308)
+        let ttl = match soa {
+            Record::SOA { minimum, ttl, .. } => minimum.min(ttl).min(MAX_NEGATIVE_TTL),
+            _ => return,
+        };
+
+        if ttl == 0 {
+            return;
+        }
+
+        soa.set_ttl(ttl);
+        let qname = qname.to_lowercase();
+        let capacity = self.shard_capacity();
+        let mut shard = self.shard(&qname);
+
+        make_room(&mut shard.nxdomains, &qname, capacity, |soa| {
+            Some(soa.expiry())
+        });
+        shard.nxdomains.insert(qname, CachedRecord::new(soa, now));
+    }
+}
+
+impl Cache {
+    /// Drops every entry that has run out, stale entries once they are past
+    /// serving stale, returning how many were dropped.
+    pub fn sweep(&self, now: Instant) -> usize {
+        let mut removed = 0;
+
+        for shard in &self.shards {
+            let mut shard = lock(shard);
+            let before = shard.entries.len() + shard.nxdomains.len();
+
+            shard.entries.retain(|_, records| {
+                expiry(records).is_some_and(|expiry| now < expiry + self.max_stale)
+            });
+            shard.nxdomains.retain(|_, soa| now < soa.expiry());
+            shard.ipv4_only.retain(|_, (_, last)| {
+                now.saturating_duration_since(*last).as_secs() < IPV4_ONLY_SECS
+            });
+
+            removed += before - (shard.entries.len() + shard.nxdomains.len());
+        }
+
+        removed
+    }
+
+    /// Sweeps the cache every `interval` until `running` is cleared, so that
+    /// names never asked for again do not hold on to memory.
+    pub fn run_sweeper(&self, interval: Duration, running: &AtomicBool) {
+        let mut swept = Instant::now();
+
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(interval.min(SHUTDOWN_POLL_INTERVAL));
+
+            if swept.elapsed() >= interval {
+                swept = Instant::now();
+
+                let removed = self.sweep(swept);
+                if removed > 0 {
+                    debug!("swept {} expired cache entries", removed);
+                }
+            }
+        }
+    }
+
+    pub fn is_ipv4_only(&self, qname: &str, now: Instant) -> bool {
+        let key = qname.to_lowercase();
+        let mut shard = self.shard(&key);
+
+        let (empty, last) = match shard.ipv4_only.get(&key) {
+            Some(&entry) => entry,
+            None => return false,
+        };
+
+        if now.saturating_duration_since(last).as_secs() >= IPV4_ONLY_SECS {
+            shard.ipv4_only.remove(&key);
+            return false;
+        }
+
+        empty >= IPV4_ONLY_AFTER
+    }
+
+    pub fn record_aaaa_result(&self, qname: &str, empty: bool, now: Instant) {
+        let key = qname.to_lowercase();
+        let capacity = self.shard_capacity();
+        let mut shard = self.shard(&key);
+
+        if !empty {
+            shard.ipv4_only.remove(&key);
+            return;
+        }
+
+        make_room(&mut shard.ipv4_only, &key, capacity, |(_, last)| {
+            Some(*last + Duration::from_secs(IPV4_ONLY_SECS))
+        });
+
+        let (count, last) = shard.ipv4_only.entry(key).or_insert((0, now));
+
+        // empty results too far apart are not counted as a run
+        if now.saturating_duration_since(*last).as_secs() >= IPV4_ONLY_SECS {
+            *count = 0;
+        }
+
+        *count += 1;
+        *last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a_record, soa_record, txt_record};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    #[test]
+    fn nxdomain_serves_soa_with_decremented_ttl() {
+        let cache = Cache::new();
+        let now = Instant::now();
+
+        cache.insert_nxdomain("nx.example.com", soa_record("example.com", 3600, 300), now);
+
+        let cached = cache.get_nxdomain("NX.example.com", now + Duration::from_secs(100));
+        assert_eq!(cached, Some(soa_record("example.com", 200, 300)));
+
+        assert_eq!(
+            cache.get_nxdomain("nx.example.com", now + Duration::from_secs(300)),
+            None
+        );
+    }
+
+    #[test]
+    fn repeated_empty_aaaa_marks_name_ipv4_only_for_a_while() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        cache.record_aaaa_result("v4.example.com", true, at(0));
+        assert!(!cache.is_ipv4_only("v4.example.com", at(1)));
+
+        cache.record_aaaa_result("v4.example.com", true, at(10));
+        assert!(cache.is_ipv4_only("V4.example.com", at(11)));
+        assert!(!cache.is_ipv4_only("v4.example.com", at(70)));
+
+        // an address clears the mark
+        cache.record_aaaa_result("v4.example.com", true, at(100));
+        cache.record_aaaa_result("v4.example.com", true, at(101));
+        cache.record_aaaa_result("v4.example.com", false, at(102));
+        assert!(!cache.is_ipv4_only("v4.example.com", at(103)));
+    }
+
+    #[test]
+    fn full_cache_evicts_the_negative_answer_that_runs_out_first() {
+        let cache = Cache::with_shards(1).with_max_entries(2);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        cache.insert_nxdomain("a.example.com", soa_record("example.com", 3600, 300), start);
+        cache.insert_nxdomain("b.example.com", soa_record("example.com", 3600, 60), start);
+        cache.insert_nxdomain("c.example.com", soa_record("example.com", 3600, 600), start);
+        assert!(cache.get_nxdomain("a.example.com", start).is_some());
+        assert!(cache.get_nxdomain("b.example.com", start).is_none());
+        assert!(cache.get_nxdomain("c.example.com", start).is_some());
+
+        // the oldest run of empty AAAA results is the first to go
+        cache.record_aaaa_result("a.example.com", true, at(0));
+        cache.record_aaaa_result("b.example.com", true, at(10));
+        cache.record_aaaa_result("b.example.com", true, at(11));
+        cache.record_aaaa_result("c.example.com", true, at(12));
+        cache.record_aaaa_result("c.example.com", true, at(13));
+        assert!(cache.is_ipv4_only("b.example.com", at(14)));
+        assert!(cache.is_ipv4_only("c.example.com", at(14)));
+        assert!(!lock(&cache.shards[0])
+            .ipv4_only
+            .contains_key("a.example.com"));
+        assert_eq!(lock(&cache.shards[0]).ipv4_only.len(), 2);
+    }
+
+    #[test]
+    fn remaining_counts_down_to_zero_at_expiry() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let cached = CachedRecord::new(a_record("example.com", ADDRESS, 300), start);
+
+        assert_eq!(cached.expiry(), at(300_000));
+        assert_eq!(cached.remaining(start), 300);
+        // partial seconds are not counted until they complete
+        assert_eq!(cached.remaining(at(999)), 300);
+        assert_eq!(cached.remaining(at(100_000)), 200);
+        assert_eq!(cached.remaining(at(299_500)), 1);
+        assert_eq!(cached.remaining(at(300_000)), 0);
+        assert_eq!(cached.remaining(at(1_000_000)), 0);
+        // a clock read before insertion sees the full TTL
+        assert_eq!(cached.remaining(start - Duration::from_secs(5)), 300);
+    }
+
+    #[test]
+    fn names_spread_across_shards_are_all_found() {
+        let cache = Cache::with_shards(4);
+        let now = Instant::now();
+        let name = |i| format!("host{}.example.com", i);
+
+        for i in 0..64 {
+            cache.insert(
+                &name(i),
+                QueryType::A,
+                vec![a_record(&name(i), ADDRESS, 300)],
+                now,
+            );
+            cache.insert(
+                &name(i),
+                QueryType::TXT,
+                vec![txt_record(&name(i), vec![name(i)])],
+                now,
+            );
+        }
+
+        assert!(cache
+            .shards
+            .iter()
+            .all(|shard| !lock(shard).entries.is_empty()));
+
+        for i in 0..64 {
+            assert_eq!(
+                cache.get(&name(i).to_uppercase(), QueryType::A, now),
+                Some(vec![a_record(&name(i), ADDRESS, 300)])
+            );
+            assert_eq!(
+                cache.get_any(&name(i), now).map(|records| records.len()),
+                Some(2)
+            );
+        }
+
+        assert_eq!(cache.remove_name(&name(7)), 2);
+        assert_eq!(cache.get_any(&name(7), now), None);
+        assert!(cache.get(&name(8), QueryType::A, now).is_some());
+
+        // a shard count of zero still leaves somewhere to cache
+        let cache = Cache::with_shards(0);
+        cache.insert(
+            "example.com",
+            QueryType::A,
+            vec![a_record("example.com", ADDRESS, 300)],
+            now,
+        );
+        assert!(cache.get("example.com", QueryType::A, now).is_some());
+    }
+
+    fn cached_entries(cache: &Cache) -> usize {
+        cache
+            .shards
+            .iter()
+            .map(|shard| {
+                let shard = lock(shard);
+                shard.entries.len() + shard.nxdomains.len()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn sweep_drops_only_entries_past_serving() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        cache.insert(
+            "short.example.com",
+            QueryType::A,
+            vec![a_record("short.example.com", ADDRESS, 60)],
+            start,
+        );
+        cache.insert(
+            "long.example.com",
+            QueryType::A,
+            vec![a_record("long.example.com", ADDRESS, 86400)],
+            start,
+        );
+        cache.insert_nxdomain(
+            "nx.example.com",
+            soa_record("example.com", 3600, 300),
+            start,
+        );
+
+        assert_eq!(cache.sweep(at(120)), 0);
+        // the negative entry is gone once expired, the short one once it is
+        // too old to be served stale as well
+        assert_eq!(cache.sweep(at(300)), 1);
+        assert_eq!(cache.sweep(at(86400 + 60)), 1);
+        assert_eq!(cached_entries(&cache), 1);
+        assert!(cache
+            .get("long.example.com", QueryType::A, at(86400 + 60))
+            .is_none());
+        assert_eq!(cached_entries(&cache), 1);
+    }
+
+    #[test]
+    fn stale_entry_is_kept_only_within_the_stale_window() {
+        let cache = Cache::with_shards(1).with_max_stale(Duration::from_secs(10));
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        cache.insert(
+            "example.com",
+            QueryType::A,
+            vec![a_record("example.com", ADDRESS, 60)],
+            start,
+        );
+
+        assert!(cache
+            .get_stale("example.com", QueryType::A, at(65))
+            .is_some());
+        assert!(cache
+            .get_revalidating("example.com", QueryType::A, at(65), Duration::from_secs(30))
+            .is_some());
+        assert!(cache
+            .get_revalidating("example.com", QueryType::A, at(70), Duration::from_secs(30))
+            .is_none());
+        assert_eq!(cache.sweep(at(69)), 0);
+        assert_eq!(cache.sweep(at(70)), 1);
+        assert!(cache
+            .get_stale("example.com", QueryType::A, at(70))
+            .is_none());
+
+        // a full cache drops stale entries before live ones
+        let cache = Cache::with_shards(1).with_max_entries(1);
+        let insert = |name: &str, ttl, now| {
+            cache.insert(name, QueryType::A, vec![a_record(name, ADDRESS, ttl)], now)
+        };
+
+        insert("stale.example.com", 60, start);
+        insert("live.example.com", 60, at(100));
+        assert!(cache
+            .get_stale("stale.example.com", QueryType::A, at(100))
+            .is_none());
+        assert!(cache
+            .get("live.example.com", QueryType::A, at(100))
+            .is_some());
+    }
+
+    #[test]
+    fn sweeper_drops_expired_entry_never_asked_for_again() {
+        let cache = Arc::new(Cache::new());
+        let running = Arc::new(AtomicBool::new(true));
+
+        cache.insert_nxdomain(
+            "nx.example.com",
+            soa_record("example.com", 3600, 1),
+            Instant::now(),
+        );
+        assert_eq!(cached_entries(&cache), 1);
+
+        let sweeper = {
+            let cache = Arc::clone(&cache);
+            let running = Arc::clone(&running);
+
+            thread::spawn(move || cache.run_sweeper(Duration::from_millis(50), &running))
+        };
+
+        thread::sleep(Duration::from_millis(1300));
+        running.store(false, Ordering::SeqCst);
+        sweeper.join().unwrap();
+
+        assert_eq!(cached_entries(&cache), 0);
+    }
+
+    #[test]
+    fn full_cache_evicts_the_entry_that_runs_out_first() {
+        let cache = Cache::with_shards(1).with_max_entries(2);
+        let start = Instant::now();
+        let insert = |name: &str, ttl| {
+            cache.insert(
+                name,
+                QueryType::A,
+                vec![a_record(name, ADDRESS, ttl)],
+                start,
+            )
+        };
+
+        insert("a.example.com", 300);
+        insert("b.example.com", 60);
+        // replacing an entry does not need room
+        insert("a.example.com", 600);
+        assert_eq!(cached_entries(&cache), 2);
+
+        insert("c.example.com", 3600);
+        assert_eq!(cached_entries(&cache), 2);
+        assert!(cache.get("a.example.com", QueryType::A, start).is_some());
+        assert!(cache.get("b.example.com", QueryType::A, start).is_none());
+        assert!(cache.get("c.example.com", QueryType::A, start).is_some());
+
+        // the cap is shared out across the shards
+        let cache = Cache::with_shards(4).with_max_entries(8);
+        for i in 0..100 {
+            let name = format!("host{}.example.com", i);
+            cache.insert(
+                &name,
+                QueryType::A,
+                vec![a_record(&name, ADDRESS, 300)],
+                start,
+            );
+        }
+        assert!(cached_entries(&cache) <= 8);
+    }
+
+    #[test]
+    fn served_ttl_counts_down_from_insertion() {
+        let cache = Cache::new();
+        let now = Instant::now();
+
+        cache.insert(
+            "example.com",
+            QueryType::A,
+            vec![a_record("example.com", ADDRESS, 300)],
+            now,
+        );
+
+        assert_eq!(
+            cache.get("example.com", QueryType::A, now + Duration::from_secs(100)),
+            Some(vec![a_record("example.com", ADDRESS, 200)])
+        );
+        assert_eq!(
+            cache.get("example.com", QueryType::A, now + Duration::from_secs(299)),
+            Some(vec![a_record("example.com", ADDRESS, 1)])
+        );
+    }
+
+    #[test]
+    fn entry_is_served_until_expiry_then_replaced() {
+        let cache = Cache::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        cache.insert(
+            "example.com",
+            QueryType::A,
+            vec![a_record("example.com", ADDRESS, 60)],
+            start,
+        );
+
+        assert!(cache.get("Example.COM", QueryType::A, at(59)).is_some());
+        assert_eq!(cache.get("example.com", QueryType::AAAA, at(0)), None);
+
+        // expired, so the caller has to resolve again
+        assert_eq!(cache.get("example.com", QueryType::A, at(60)), None);
+        assert_eq!(
+            cache.get_stale("example.com", QueryType::A, at(60)),
+            Some(vec![a_record("example.com", ADDRESS, 30)])
+        );
+
+        cache.insert(
+            "example.com",
+            QueryType::A,
+            vec![a_record("example.com", ADDRESS, 120)],
+            at(60),
+        );
+        assert_eq!(
+            cache.get("example.com", QueryType::A, at(90)),
+            Some(vec![a_record("example.com", ADDRESS, 90)])
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use crate::dns::{QueryClass, QueryType, Record};
+use crate::utils::lock;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+const CHALLENGE_LABEL: &str = "_acme-challenge";
+// short, so that a resolver does not hold on to a value after it is cleared
+const CHALLENGE_TTL: u32 = 60;
+
+#[derive(Debug, Default)]
+pub struct ChallengeStore {
+    /// the TXT value set for each challenge name, None once cleared so that
+    /// the name keeps being answered here rather than upstream
+    values: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl ChallengeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answers `_acme-challenge.<domain>` TXT queries with `value`.
+    pub fn set_challenge(&self, domain: &str, value: &str) {
+        lock(&self.values).insert(challenge_name(domain), Some(value.to_string()));
+    }
+
+    pub fn clear_challenge(&self, domain: &str) {
+        if let Some(value) = lock(&self.values).get_mut(&challenge_name(domain)) {
+            *value = None;
+        }
+    }
+
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<Vec<Record>> {
+        let qname = qname.trim_end_matches('.').to_lowercase();
+        let value = lock(&self.values).get(&qname)?.clone();
+
+        let records = value
+            .filter(|_| matches!(qtype, QueryType::TXT | QueryType::ANY))
+            .map(|value| Record::TXT {
+                domain: qname,
+                strings: vec![value],
+                class: QueryClass::IN,
+                ttl: CHALLENGE_TTL,
+            });
+
+        Some(records.into_iter().collect())
+    }
+}
+
+fn challenge_name(domain: &str) -> String {
+    format!(
+        "{}.{}",
+        CHALLENGE_LABEL,
+        domain.trim_end_matches('.').to_lowercase()
+    )
+}
@@ -0,0 +1,166 @@
+use crate::blocklist::Blocklist;
+use crate::cache::{DEFAULT_MAX_ENTRIES, DEFAULT_MAX_STALE, DEFAULT_SHARDS};
+use crate::challenge::ChallengeStore;
+use crate::hooks::{Hooks, PostHook, PreHook};
+use crate::inflight::InFlight;
+use crate::pacer::Pacer;
+use crate::rtt::RttTable;
+use crate::stats::Stats;
+use crate::utils::Result;
+use crate::zone::Zone;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MAX_TTL: u32 = 86400;
+const DEFAULT_UPSTREAM_RATE: u32 = 50;
+const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(5);
+// a.root-servers.net
+const DEFAULT_ROOT_HINT: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
+
+#[derive(Debug, Clone)]
+pub struct ResolverConfig {
+    /// root nameservers that recursion starts from
+    pub root_hints: Vec<Ipv4Addr>,
+    /// upstream resolvers to forward queries to instead of recursing from the root
+    pub forwarders: Vec<SocketAddr>,
+    /// a DNS-over-HTTPS endpoint to send queries to instead of recursing from the root
+    pub doh_url: Option<String>,
+    /// how long to wait for an upstream response before resending
+    pub timeout: Duration,
+    /// how many times to resend a query that timed out
+    pub retries: usize,
+    /// local ports to send upstream queries from, or any ephemeral port if unset
+    pub source_ports: Option<RangeInclusive<u16>>,
+    /// queries a second sent to any one upstream server, beyond which sends
+    /// are delayed, or the server skipped if that would outlast the timeout
+    pub upstream_rate: u32,
+    /// whether to randomize the case of upstream query names (0x20 encoding)
+    pub randomize_case: bool,
+    /// lowest TTL given to upstream records, so zero TTLs do not bypass the cache
+    pub min_ttl: u32,
+    /// highest TTL given to upstream records
+    pub max_ttl: u32,
+    /// a /96 NAT64 prefix to synthesize AAAA records from A records with, if any
+    pub dns64_prefix: Option<Ipv6Addr>,
+    /// local zones answered authoritatively before going upstream, the most
+    /// specific one holding a name answering for it
+    pub zones: Vec<Zone>,
+    /// whether PTR queries under a declared local reverse zone are answered
+    /// from the local zones' A and AAAA records
+    pub synthesize_ptr: bool,
+    /// lowest TTL given to local zone records, apart from min_ttl, which only
+    /// bounds upstream records
+    pub zone_min_ttl: u32,
+    /// how many separately locked shards the cache is split into
+    pub cache_shards: usize,
+    /// how many entries the cache holds before evicting the first to run out
+    pub cache_max_entries: usize,
+    /// how long past expiry a cache entry is kept to be served stale
+    pub max_stale: Duration,
+    /// how often the cache is swept of entries that have run out
+    pub cache_sweep_interval: Duration,
+    /// how long past expiry a cache entry is still answered with while it is
+    /// looked up again, or zero to always look it up before answering
+    pub stale_while_revalidate: Duration,
+    /// how long a TCP connection may sit idle before it is closed, as
+    /// advertised to EDNS clients with the TCP keepalive option
+    pub tcp_keepalive: Duration,
+    /// names answered with NXDOMAIN without going upstream
+    pub blocklist: Blocklist,
+    /// whether AAAA queries for names whose AAAA lookups keep coming back
+    /// empty are answered with NODATA without going upstream
+    pub learn_ipv4_only: bool,
+    /// fraction of queries, from 0.0 to 1.0, whose question and response are logged
+    pub log_sample_rate: f64,
+    /// closures run on each question before it is answered and on each
+    /// response before it is sent
+    pub hooks: Hooks,
+    /// how often the counters are logged and reset, if ever
+    pub stats_rollover: Option<Duration>,
+    /// counters shared by every clone of this config
+    pub stats: Arc<Stats>,
+    /// upstream lookups in progress, shared by every clone of this config
+    pub inflight: Arc<InFlight>,
+    /// nameserver round-trip times, shared by every clone of this config
+    pub rtt: Arc<RttTable>,
+    /// sends booked to each upstream server, shared by every clone of this config
+    pub pacer: Arc<Pacer>,
+    /// ACME challenge TXT values set at runtime, shared by every clone of this config
+    pub challenges: Arc<ChallengeStore>,
+}
+
+impl Default for ResolverConfig {
+    fn default() -> Self {
+        Self {
+            root_hints: vec![DEFAULT_ROOT_HINT],
+            forwarders: Vec::new(),
+            doh_url: None,
+            timeout: Duration::from_secs(2),
+            retries: 3,
+            source_ports: None,
+            upstream_rate: DEFAULT_UPSTREAM_RATE,
+            randomize_case: true,
+            min_ttl: 0,
+            max_ttl: DEFAULT_MAX_TTL,
+            dns64_prefix: None,
+            zones: Vec::new(),
+            synthesize_ptr: false,
+            zone_min_ttl: 0,
+            cache_shards: DEFAULT_SHARDS,
+            cache_max_entries: DEFAULT_MAX_ENTRIES,
+            max_stale: DEFAULT_MAX_STALE,
+            cache_sweep_interval: DEFAULT_SWEEP_INTERVAL,
+            stale_while_revalidate: Duration::ZERO,
+            tcp_keepalive: DEFAULT_TCP_KEEPALIVE,
+            blocklist: Blocklist::default(),
+            learn_ipv4_only: false,
+            log_sample_rate: 1.0,
+            hooks: Hooks::default(),
+            stats_rollover: None,
+            stats: Arc::default(),
+            inflight: Arc::default(),
+            rtt: Arc::default(),
+            pacer: Arc::default(),
+            challenges: Arc::default(),
+        }
+    }
+}
+
+impl ResolverConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pre_hook(&mut self, hook: PreHook) {
+        self.hooks.add_pre_hook(hook);
+    }
+
+    pub fn add_post_hook(&mut self, hook: PostHook) {
+        self.hooks.add_post_hook(hook);
+    }
+
+    pub fn is_forward_only(&self) -> bool {
+        !self.forwarders.is_empty()
+    }
+
+    pub fn clamp_ttl(&self, ttl: u32) -> u32 {
+        ttl.max(self.min_ttl).min(self.max_ttl)
+    }
+
+    // decided by the query id, which clients pick at random, so that every
+    // line about one query agrees on whether it is logged
+    pub fn logs_query(&self, id: u16) -> bool {
+        f64::from(id) < self.log_sample_rate * 65536.0
+    }
+}
+
+pub fn parse_upstream(upstream: &str) -> Result<SocketAddr> {
+    if let Ok(address) = upstream.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+
+    Ok(SocketAddr::new(upstream.parse::<IpAddr>()?, 53))
+}
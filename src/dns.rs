@@ -1,7 +1,14 @@
+use crate::error::DnsError;
 use crate::packet::BytePacketBuffer;
-use crate::utils::Result;
+use crate::utils::{is_subdomain_of, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
 use log::debug;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use rand::seq::{IteratorRandom, SliceRandom};
+use rand::Rng;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseCode {
@@ -17,17 +24,58 @@ pub enum ResponseCode {
     NOTIMP,
     /// refused
     REFUSED,
+    /// name exists when it should not
+    YXDOMAIN,
+    /// RR set exists when it should not
+    YXRRSET,
+    /// RR set that should exist does not
+    NXRRSET,
+    /// server not authoritative for zone
+    NOTAUTH,
+    /// name not contained in zone
+    NOTZONE,
+    /// bad OPT version, only expressible with the OPT extended rcode
+    BADVERS,
+    /// unknown
+    UNKNOWN(u16),
 }
 
-impl From<u8> for ResponseCode {
-    fn from(num: u8) -> Self {
+impl From<u16> for ResponseCode {
+    fn from(num: u16) -> Self {
         match num {
+            0 => ResponseCode::NOERROR,
             1 => ResponseCode::FORMERR,
             2 => ResponseCode::SERVFAIL,
             3 => ResponseCode::NXDOMAIN,
             4 => ResponseCode::NOTIMP,
             5 => ResponseCode::REFUSED,
-            _ => ResponseCode::NOERROR,
+            6 => ResponseCode::YXDOMAIN,
+            7 => ResponseCode::YXRRSET,
+            8 => ResponseCode::NXRRSET,
+            9 => ResponseCode::NOTAUTH,
+            10 => ResponseCode::NOTZONE,
+            16 => ResponseCode::BADVERS,
+            _ => ResponseCode::UNKNOWN(num),
+        }
+    }
+}
+
+impl From<ResponseCode> for u16 {
+    fn from(rcode: ResponseCode) -> Self {
+        match rcode {
+            ResponseCode::NOERROR => 0,
+            ResponseCode::FORMERR => 1,
+            ResponseCode::SERVFAIL => 2,
+            ResponseCode::NXDOMAIN => 3,
+            ResponseCode::NOTIMP => 4,
+            ResponseCode::REFUSED => 5,
+            ResponseCode::YXDOMAIN => 6,
+            ResponseCode::YXRRSET => 7,
+            ResponseCode::NXRRSET => 8,
+            ResponseCode::NOTAUTH => 9,
+            ResponseCode::NOTZONE => 10,
+            ResponseCode::BADVERS => 16,
+            ResponseCode::UNKNOWN(num) => num,
         }
     }
 }
@@ -99,7 +147,7 @@ impl Header {
         self.aa = (a & (1 << 2)) > 0;
         self.opcode = (a >> 3) & 0x0F;
         self.response = (a & (1 << 7)) > 0;
-        self.rcode = ResponseCode::from(b & 0x0F);
+        self.rcode = ResponseCode::from((b & 0x0F) as u16);
         self.cd = (b & (1 << 4)) > 0;
         self.ad = (b & (1 << 5)) > 0;
         self.z = (b & (1 << 6)) > 0;
@@ -120,11 +168,11 @@ impl Header {
                 | ((self.tc as u8) << 1)
                 | ((self.aa as u8) << 2)
                 | (self.opcode << 3)
-                | ((self.response as u8) << 7) as u8,
+                | ((self.response as u8) << 7),
         )?;
 
         buffer.write_u8(
-            (self.rcode as u8)
+            (u16::from(self.rcode) & 0x0F) as u8
                 | ((self.cd as u8) << 4)
                 | ((self.ad as u8) << 5)
                 | ((self.z as u8) << 6)
@@ -148,10 +196,42 @@ pub enum QueryType {
     NS,
     /// 5 the canonical name for an alias
     CNAME,
+    /// 6 marks the start of a zone of authority
+    SOA,
+    /// 11 a well known service description
+    WKS,
+    /// 12 a domain name pointer
+    PTR,
     /// 15 mail exchange
     MX,
+    /// 16 text strings
+    TXT,
     /// 28 a host address (IPv6 address)
     AAAA,
+    /// 33 server selection
+    SRV,
+    /// 39 a redirection of a whole subtree of names
+    DNAME,
+    /// 41 EDNS0 option pseudo-record
+    OPT,
+    /// 49 DHCP information
+    DHCID,
+    /// 50 hashed authenticated denial of existence
+    NSEC3,
+    /// 51 parameters for hashed authenticated denial of existence
+    NSEC3PARAM,
+    /// 104 an ILNP node identifier
+    NID,
+    /// 105 a 32-bit ILNP locator
+    L32,
+    /// 106 a 64-bit ILNP locator
+    L64,
+    /// 107 the name of a subnetwork for ILNP
+    LP,
+    /// 255 a request for all records
+    ANY,
+    /// 257 certification authority restriction
+    CAA,
     /// unknown
     UNKNOWN(u16),
 }
@@ -162,41 +242,197 @@ impl From<u16> for QueryType {
             1 => QueryType::A,
             2 => QueryType::NS,
             5 => QueryType::CNAME,
+            6 => QueryType::SOA,
+            11 => QueryType::WKS,
+            12 => QueryType::PTR,
             15 => QueryType::MX,
+            16 => QueryType::TXT,
             28 => QueryType::AAAA,
+            33 => QueryType::SRV,
+            39 => QueryType::DNAME,
+            41 => QueryType::OPT,
+            49 => QueryType::DHCID,
+            50 => QueryType::NSEC3,
+            51 => QueryType::NSEC3PARAM,
+            104 => QueryType::NID,
+            105 => QueryType::L32,
+            106 => QueryType::L64,
+            107 => QueryType::LP,
+            255 => QueryType::ANY,
+            257 => QueryType::CAA,
             _ => QueryType::UNKNOWN(num),
         }
     }
 }
 
-impl Into<u16> for QueryType {
-    fn into(self) -> u16 {
-        match self {
+impl From<QueryType> for u16 {
+    fn from(qtype: QueryType) -> Self {
+        match qtype {
             QueryType::A => 1,
             QueryType::NS => 2,
             QueryType::CNAME => 5,
+            QueryType::SOA => 6,
+            QueryType::WKS => 11,
+            QueryType::PTR => 12,
             QueryType::MX => 15,
-            QueryType::AAAA => 18,
+            QueryType::TXT => 16,
+            QueryType::AAAA => 28,
+            QueryType::SRV => 33,
+            QueryType::DNAME => 39,
+            QueryType::OPT => 41,
+            QueryType::DHCID => 49,
+            QueryType::NSEC3 => 50,
+            QueryType::NSEC3PARAM => 51,
+            QueryType::NID => 104,
+            QueryType::L32 => 105,
+            QueryType::L64 => 106,
+            QueryType::LP => 107,
+            QueryType::ANY => 255,
+            QueryType::CAA => 257,
             QueryType::UNKNOWN(num) => num,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QueryClass {
+    /// 1 the Internet
+    IN,
+    /// 3 the CHAOS class
+    CH,
+    /// 4 Hesiod
+    HS,
+    /// 255 any class
+    ANY,
+    /// unknown
+    UNKNOWN(u16),
+}
+
+impl From<u16> for QueryClass {
+    fn from(num: u16) -> Self {
+        match num {
+            1 => QueryClass::IN,
+            3 => QueryClass::CH,
+            4 => QueryClass::HS,
+            255 => QueryClass::ANY,
+            _ => QueryClass::UNKNOWN(num),
+        }
+    }
+}
+
+impl From<QueryClass> for u16 {
+    fn from(qclass: QueryClass) -> Self {
+        match qclass {
+            QueryClass::IN => 1,
+            QueryClass::CH => 3,
+            QueryClass::HS => 4,
+            QueryClass::ANY => 255,
+            QueryClass::UNKNOWN(num) => num,
+        }
+    }
+}
+
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryType::A => write!(f, "A"),
+            QueryType::NS => write!(f, "NS"),
+            QueryType::CNAME => write!(f, "CNAME"),
+            QueryType::SOA => write!(f, "SOA"),
+            QueryType::WKS => write!(f, "WKS"),
+            QueryType::PTR => write!(f, "PTR"),
+            QueryType::MX => write!(f, "MX"),
+            QueryType::TXT => write!(f, "TXT"),
+            QueryType::AAAA => write!(f, "AAAA"),
+            QueryType::SRV => write!(f, "SRV"),
+            QueryType::DNAME => write!(f, "DNAME"),
+            QueryType::OPT => write!(f, "OPT"),
+            QueryType::DHCID => write!(f, "DHCID"),
+            QueryType::NSEC3 => write!(f, "NSEC3"),
+            QueryType::NSEC3PARAM => write!(f, "NSEC3PARAM"),
+            QueryType::NID => write!(f, "NID"),
+            QueryType::L32 => write!(f, "L32"),
+            QueryType::L64 => write!(f, "L64"),
+            QueryType::LP => write!(f, "LP"),
+            QueryType::ANY => write!(f, "ANY"),
+            QueryType::CAA => write!(f, "CAA"),
+            QueryType::UNKNOWN(num) => write!(f, "TYPE{}", num),
+        }
+    }
+}
+
+impl FromStr for QueryType {
+    type Err = DnsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(QueryType::A),
+            "NS" => Ok(QueryType::NS),
+            "CNAME" => Ok(QueryType::CNAME),
+            "SOA" => Ok(QueryType::SOA),
+            "WKS" => Ok(QueryType::WKS),
+            "PTR" => Ok(QueryType::PTR),
+            "MX" => Ok(QueryType::MX),
+            "TXT" => Ok(QueryType::TXT),
+            "AAAA" => Ok(QueryType::AAAA),
+            "SRV" => Ok(QueryType::SRV),
+            "DNAME" => Ok(QueryType::DNAME),
+            "OPT" => Ok(QueryType::OPT),
+            "DHCID" => Ok(QueryType::DHCID),
+            "NSEC3" => Ok(QueryType::NSEC3),
+            "NSEC3PARAM" => Ok(QueryType::NSEC3PARAM),
+            "NID" => Ok(QueryType::NID),
+            "L32" => Ok(QueryType::L32),
+            "L64" => Ok(QueryType::L64),
+            "LP" => Ok(QueryType::LP),
+            "ANY" => Ok(QueryType::ANY),
+            "CAA" => Ok(QueryType::CAA),
+            other => other
+                .strip_prefix("TYPE")
+                .and_then(|num| num.parse::<u16>().ok())
+                .map(QueryType::from)
+                .ok_or_else(|| DnsError::UnknownType(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for QueryClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryClass::IN => write!(f, "IN"),
+            QueryClass::CH => write!(f, "CH"),
+            QueryClass::HS => write!(f, "HS"),
+            QueryClass::ANY => write!(f, "ANY"),
+            QueryClass::UNKNOWN(num) => write!(f, "CLASS{}", num),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Question {
     pub qname: String,
     pub qtype: QueryType,
+    pub qclass: QueryClass,
 }
 
 impl Question {
     pub fn new(qname: String, qtype: QueryType) -> Self {
-        Self { qname, qtype }
+        Self {
+            qname,
+            qtype,
+            qclass: QueryClass::IN,
+        }
+    }
+
+    pub fn with_class(mut self, qclass: QueryClass) -> Self {
+        self.qclass = qclass;
+        self
     }
 
     fn read(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         buffer.read_qname(&mut self.qname)?;
         self.qtype = QueryType::from(buffer.read_u16()?);
-        let _ = buffer.read_u16()?;
+        self.qclass = QueryClass::from(buffer.read_u16()?);
 
         Ok(())
     }
@@ -205,7 +441,7 @@ impl Question {
         buffer.write_qname(&self.qname)?;
         let qtype = self.qtype.into();
         buffer.write_u16(qtype)?;
-        buffer.write_u16(1)?;
+        buffer.write_u16(self.qclass.into())?;
 
         Ok(())
     }
@@ -217,18 +453,50 @@ pub enum Record {
     A {
         domain: String,
         address: Ipv4Addr,
+        class: QueryClass,
         ttl: u32,
     },
     /// an authoritative name server
     NS {
         domain: String,
         host: String,
+        class: QueryClass,
         ttl: u32,
     },
     /// the canonical name for an alias
     CNAME {
         domain: String,
         host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// marks the start of a zone of authority
+    SOA {
+        domain: String,
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// a well known service description
+    WKS {
+        domain: String,
+        address: Ipv4Addr,
+        protocol: u8,
+        bitmap: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// a domain name pointer
+    PTR {
+        domain: String,
+        host: String,
+        class: QueryClass,
         ttl: u32,
     },
     /// mail exchange
@@ -236,19 +504,125 @@ pub enum Record {
         domain: String,
         priority: u16,
         host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// text strings
+    TXT {
+        domain: String,
+        strings: Vec<String>,
+        class: QueryClass,
         ttl: u32,
     },
     /// a host address (IPv6 address)
     AAAA {
         domain: String,
         address: Ipv6Addr,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// server selection
+    SRV {
+        domain: String,
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// a redirection of a whole subtree of names
+    DNAME {
+        domain: String,
+        target: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// EDNS0 option pseudo-record
+    OPT {
+        domain: String,
+        udp_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        flags: u16,
+        data: Vec<u8>,
+    },
+    /// DHCP information
+    DHCID {
+        domain: String,
+        data: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// hashed authenticated denial of existence
+    NSEC3 {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        next_hashed_owner: Vec<u8>,
+        type_bitmap: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// parameters for hashed authenticated denial of existence
+    NSEC3PARAM {
+        domain: String,
+        hash_algorithm: u8,
+        flags: u8,
+        iterations: u16,
+        salt: Vec<u8>,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// an ILNP node identifier
+    NID {
+        domain: String,
+        preference: u16,
+        node_id: u64,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// a 32-bit ILNP locator
+    L32 {
+        domain: String,
+        preference: u16,
+        locator: Ipv4Addr,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// a 64-bit ILNP locator
+    L64 {
+        domain: String,
+        preference: u16,
+        locator: u64,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// the name of a subnetwork for ILNP
+    LP {
+        domain: String,
+        preference: u16,
+        host: String,
+        class: QueryClass,
+        ttl: u32,
+    },
+    /// certification authority restriction
+    CAA {
+        domain: String,
+        flags: u8,
+        tag: String,
+        value: String,
+        class: QueryClass,
         ttl: u32,
     },
     /// unknown
     UNKNOWN {
         domain: String,
         qtype: u16,
-        len: u16,
+        data: Vec<u8>,
+        class: QueryClass,
         ttl: u32,
     },
 }
@@ -260,23 +634,49 @@ impl Record {
         buffer.read_qname(&mut domain)?;
 
         let qtype = buffer.read_u16()?;
-        let _ = buffer.read_u16()?;
+        let raw_class = buffer.read_u16()?;
+        let class = QueryClass::from(raw_class);
         let ttl = buffer.read_u32()?;
         let len = buffer.read_u16()?;
+        let start = buffer.position;
 
-        match QueryType::from(qtype) {
+        let record: Result<Self> = match QueryType::from(qtype) {
             QueryType::A => {
                 let raw_address = buffer.read_u32()?;
                 let address = Ipv4Addr::new(
                     ((raw_address >> 24) & 0xFF) as u8,
                     ((raw_address >> 16) & 0xFF) as u8,
                     ((raw_address >> 8) & 0xFF) as u8,
-                    ((raw_address >> 0) & 0xFF) as u8,
+                    (raw_address & 0xFF) as u8,
                 );
 
                 Ok(Record::A {
                     domain,
                     address,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::TXT => {
+                let end = buffer.position + len as usize;
+                let mut strings = Vec::new();
+
+                while buffer.position < end {
+                    let string_len = buffer.read_u8()?;
+                    let string = buffer.read_bytes(string_len as usize)?;
+                    strings.push(String::from_utf8_lossy(&string).to_string());
+                }
+
+                if buffer.position != end {
+                    return Err(DnsError::MalformedRecord(
+                        "TXT character-strings overrun record length".to_string(),
+                    ));
+                }
+
+                Ok(Record::TXT {
+                    domain,
+                    strings,
+                    class,
                     ttl,
                 })
             }
@@ -287,18 +687,19 @@ impl Record {
                 let raw_address4 = buffer.read_u32()?;
                 let address = Ipv6Addr::new(
                     ((raw_address1 >> 16) & 0xFFFF) as u16,
-                    ((raw_address1 >> 0) & 0xFFFF) as u16,
+                    (raw_address1 & 0xFFFF) as u16,
                     ((raw_address2 >> 16) & 0xFFFF) as u16,
-                    ((raw_address2 >> 0) & 0xFFFF) as u16,
+                    (raw_address2 & 0xFFFF) as u16,
                     ((raw_address3 >> 16) & 0xFFFF) as u16,
-                    ((raw_address3 >> 0) & 0xFFFF) as u16,
+                    (raw_address3 & 0xFFFF) as u16,
                     ((raw_address4 >> 16) & 0xFFFF) as u16,
-                    ((raw_address4 >> 0) & 0xFFFF) as u16,
+                    (raw_address4 & 0xFFFF) as u16,
                 );
 
                 Ok(Record::AAAA {
                     domain,
                     address,
+                    class,
                     ttl,
                 })
             }
@@ -306,13 +707,77 @@ impl Record {
                 let mut host = String::new();
                 buffer.read_qname(&mut host)?;
 
-                Ok(Record::NS { domain, host, ttl })
+                Ok(Record::NS {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
             }
             QueryType::CNAME => {
                 let mut host = String::new();
                 buffer.read_qname(&mut host)?;
 
-                Ok(Record::CNAME { domain, host, ttl })
+                Ok(Record::CNAME {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SOA => {
+                let mut mname = String::new();
+                buffer.read_qname(&mut mname)?;
+                let mut rname = String::new();
+                buffer.read_qname(&mut rname)?;
+                let serial = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry = buffer.read_u32()?;
+                let expire = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+
+                Ok(Record::SOA {
+                    domain,
+                    mname,
+                    rname,
+                    serial,
+                    refresh,
+                    retry,
+                    expire,
+                    minimum,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::WKS => {
+                let address = Ipv4Addr::from(buffer.read_u32()?);
+                let protocol = buffer.read_u8()?;
+                let bitmap_len = (len as usize).checked_sub(5).ok_or_else(|| {
+                    DnsError::MalformedRecord(
+                        "WKS rdata shorter than address and protocol".to_string(),
+                    )
+                })?;
+                let bitmap = buffer.read_bytes(bitmap_len)?;
+
+                Ok(Record::WKS {
+                    domain,
+                    address,
+                    protocol,
+                    bitmap,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::PTR => {
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(Record::PTR {
+                    domain,
+                    host,
+                    class,
+                    ttl,
+                })
             }
             QueryType::MX => {
                 let priority = buffer.read_u16()?;
@@ -323,20 +788,215 @@ impl Record {
                     domain,
                     priority,
                     host,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::SRV => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                Ok(Record::SRV {
+                    domain,
+                    priority,
+                    weight,
+                    port,
+                    target,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::DNAME => {
+                let mut target = String::new();
+                buffer.read_qname(&mut target)?;
+
+                Ok(Record::DNAME {
+                    domain,
+                    target,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::OPT => {
+                let data = buffer.read_bytes(len as usize)?;
+
+                Ok(Record::OPT {
+                    domain,
+                    udp_size: raw_class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: ((ttl >> 16) & 0xFF) as u8,
+                    flags: (ttl & 0xFFFF) as u16,
+                    data,
+                })
+            }
+            QueryType::DHCID => {
+                let data = buffer.read_bytes(len as usize)?;
+
+                Ok(Record::DHCID {
+                    domain,
+                    data,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3 => {
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read_u8()?;
+                let salt = buffer.read_bytes(salt_len as usize)?;
+                let hash_len = buffer.read_u8()?;
+                let next_hashed_owner = buffer.read_bytes(hash_len as usize)?;
+
+                let bitmap_len = (len as usize)
+                    .checked_sub(buffer.position - start)
+                    .ok_or_else(|| {
+                        DnsError::MalformedRecord("NSEC3 rdata exceeds record length".to_string())
+                    })?;
+                let type_bitmap = buffer.read_bytes(bitmap_len)?;
+
+                Ok(Record::NSEC3 {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    next_hashed_owner,
+                    type_bitmap,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::NSEC3PARAM => {
+                let hash_algorithm = buffer.read_u8()?;
+                let flags = buffer.read_u8()?;
+                let iterations = buffer.read_u16()?;
+                let salt_len = buffer.read_u8()?;
+                let salt = buffer.read_bytes(salt_len as usize)?;
+
+                Ok(Record::NSEC3PARAM {
+                    domain,
+                    hash_algorithm,
+                    flags,
+                    iterations,
+                    salt,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::NID => {
+                let preference = buffer.read_u16()?;
+                let node_id = buffer.read_u64()?;
+
+                Ok(Record::NID {
+                    domain,
+                    preference,
+                    node_id,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::L32 => {
+                let preference = buffer.read_u16()?;
+                let locator = Ipv4Addr::from(buffer.read_u32()?);
+
+                Ok(Record::L32 {
+                    domain,
+                    preference,
+                    locator,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::L64 => {
+                let preference = buffer.read_u16()?;
+                let locator = buffer.read_u64()?;
+
+                Ok(Record::L64 {
+                    domain,
+                    preference,
+                    locator,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::LP => {
+                let preference = buffer.read_u16()?;
+                let mut host = String::new();
+                buffer.read_qname(&mut host)?;
+
+                Ok(Record::LP {
+                    domain,
+                    preference,
+                    host,
+                    class,
+                    ttl,
+                })
+            }
+            QueryType::CAA => {
+                let flags = buffer.read_u8()?;
+                let tag_len = buffer.read_u8()?;
+                let tag = buffer.read_bytes(tag_len as usize)?;
+                let value_len = (len as usize)
+                    .checked_sub(2 + tag_len as usize)
+                    .ok_or_else(|| {
+                        DnsError::MalformedRecord("CAA tag exceeds record length".to_string())
+                    })?;
+                let value = buffer.read_bytes(value_len)?;
+
+                Ok(Record::CAA {
+                    domain,
+                    flags,
+                    tag: String::from_utf8_lossy(&tag).to_string(),
+                    value: String::from_utf8_lossy(&value).to_string(),
+                    class,
                     ttl,
                 })
             }
-            QueryType::UNKNOWN(_) => {
-                buffer.step(len as usize)?;
+            QueryType::ANY | QueryType::UNKNOWN(_) => {
+                let data = match compressed_names(qtype) {
+                    0 => buffer.read_bytes(len as usize)?,
+                    names => {
+                        // stored uncompressed, as the pointers would not survive
+                        // being written into another packet
+                        let mut data = BytePacketBuffer::new();
+
+                        for _ in 0..names {
+                            let mut name = String::new();
+                            buffer.read_qname_exact(&mut name)?;
+                            data.write_qname(&name)?;
+                        }
+
+                        data.get_range(0, data.position)?.to_vec()
+                    }
+                };
 
                 Ok(Record::UNKNOWN {
                     domain,
                     qtype,
-                    len,
+                    data,
+                    class,
                     ttl,
                 })
             }
+        };
+        let record = record?;
+
+        // every type must consume exactly its rdata, or the records after it
+        // would be read from the wrong offset
+        if buffer.position != start + len as usize {
+            return Err(DnsError::MalformedRecord(format!(
+                "{} rdata of {} bytes read as {}",
+                QueryType::from(qtype),
+                len,
+                buffer.position - start
+            )));
         }
+
+        Ok(record)
     }
 
     fn write(&self, buffer: &mut BytePacketBuffer) -> Result<usize> {
@@ -346,11 +1006,12 @@ impl Record {
             Record::A {
                 ref domain,
                 ref address,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::A.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(4)?;
 
@@ -363,11 +1024,12 @@ impl Record {
             Record::NS {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::NS.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -381,11 +1043,80 @@ impl Record {
             Record::CNAME {
                 ref domain,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::CNAME.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::WKS {
+                ref domain,
+                ref address,
+                protocol,
+                ref bitmap,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::WKS.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(rdata_len("WKS bitmap", 5, bitmap)?)?;
+
+                buffer.write_bytes(&address.octets())?;
+                buffer.write_u8(protocol)?;
+                buffer.write_bytes(bitmap)?;
+            }
+            Record::PTR {
+                ref domain,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.into())?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -400,11 +1131,12 @@ impl Record {
                 ref domain,
                 priority,
                 ref host,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::MX.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
 
                 let position = buffer.position;
@@ -416,14 +1148,45 @@ impl Record {
                 let size = buffer.position - (position + 2);
                 buffer.set_u16(position, size as u16)?;
             }
+            Record::TXT {
+                ref domain,
+                ref strings,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+
+                for string in strings {
+                    if string.len() > 0xFF {
+                        return Err(DnsError::MalformedRecord(format!(
+                            "TXT character-string of {} bytes exceeds 255 bytes",
+                            string.len()
+                        )));
+                    }
+
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string.as_bytes())?;
+                }
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
             Record::AAAA {
                 ref domain,
                 ref address,
+                class,
                 ttl,
             } => {
                 buffer.write_qname(domain)?;
                 buffer.write_u16(QueryType::AAAA.into())?;
-                buffer.write_u16(1)?;
+                buffer.write_u16(class.into())?;
                 buffer.write_u32(ttl)?;
                 buffer.write_u16(16)?;
 
@@ -431,29 +1194,626 @@ impl Record {
                     buffer.write_u16(*octet)?;
                 }
             }
-            Record::UNKNOWN { .. } => {
-                debug!("skipping record: {:?}", self);
+            Record::DHCID {
+                ref domain,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DHCID.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                buffer.write_bytes(data)?;
             }
-        }
+            Record::NSEC3 {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                ref next_hashed_owner,
+                ref type_bitmap,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
 
-        Ok(buffer.position - start)
-    }
-}
+                let position = buffer.position;
 
-#[derive(Debug, Clone)]
-pub struct Packet {
-    /// header
-    pub header: Header,
-    /// the question for the name server
-    pub questions: Vec<Question>,
-    /// resource records answering the question
-    pub answers: Vec<Record>,
-    /// resource records pointing toward an authority
+                buffer.write_u16(0)?;
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(octet_len("salt", salt)?)?;
+                buffer.write_bytes(salt)?;
+                buffer.write_u8(octet_len("next hashed owner", next_hashed_owner)?)?;
+                buffer.write_bytes(next_hashed_owner)?;
+                buffer.write_bytes(type_bitmap)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::NSEC3PARAM {
+                ref domain,
+                hash_algorithm,
+                flags,
+                iterations,
+                ref salt,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NSEC3PARAM.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_u8(hash_algorithm)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u16(iterations)?;
+                buffer.write_u8(octet_len("salt", salt)?)?;
+                buffer.write_bytes(salt)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::NID {
+                ref domain,
+                preference,
+                node_id,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NID.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(10)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_u64(node_id)?;
+            }
+            Record::L32 {
+                ref domain,
+                preference,
+                ref locator,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::L32.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(6)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_bytes(&locator.octets())?;
+            }
+            Record::L64 {
+                ref domain,
+                preference,
+                locator,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::L64.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(10)?;
+
+                buffer.write_u16(preference)?;
+                buffer.write_u64(locator)?;
+            }
+            Record::LP {
+                ref domain,
+                preference,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::LP.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_u16(preference)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref target,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_qname(target)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::DNAME {
+                ref domain,
+                ref target,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::DNAME.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_qname(target)?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::OPT {
+                ref domain,
+                udp_size,
+                extended_rcode,
+                version,
+                flags,
+                ref data,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::OPT.into())?;
+                buffer.write_u16(udp_size)?;
+                buffer.write_u8(extended_rcode)?;
+                buffer.write_u8(version)?;
+                buffer.write_u16(flags)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                buffer.write_bytes(data)?;
+            }
+            Record::CAA {
+                ref domain,
+                flags,
+                ref tag,
+                ref value,
+                class,
+                ttl,
+            } => {
+                if tag.is_empty() || tag.len() > 0xFF {
+                    return Err(DnsError::MalformedRecord(format!(
+                        "CAA tag {:?} has invalid length",
+                        tag
+                    )));
+                }
+
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CAA.into())?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+
+                let position = buffer.position;
+
+                buffer.write_u16(0)?;
+                buffer.write_u8(flags)?;
+                buffer.write_u8(tag.len() as u8)?;
+                buffer.write_bytes(tag.as_bytes())?;
+                buffer.write_bytes(value.as_bytes())?;
+
+                let size = buffer.position - (position + 2);
+                buffer.set_u16(position, size as u16)?;
+            }
+            Record::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.into())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+
+                buffer.write_bytes(data)?;
+            }
+        }
+
+        Ok(buffer.position - start)
+    }
+
+    pub fn domain(&self) -> &str {
+        match self {
+            Record::A { domain, .. }
+            | Record::NS { domain, .. }
+            | Record::CNAME { domain, .. }
+            | Record::SOA { domain, .. }
+            | Record::WKS { domain, .. }
+            | Record::PTR { domain, .. }
+            | Record::MX { domain, .. }
+            | Record::TXT { domain, .. }
+            | Record::AAAA { domain, .. }
+            | Record::SRV { domain, .. }
+            | Record::DNAME { domain, .. }
+            | Record::OPT { domain, .. }
+            | Record::DHCID { domain, .. }
+            | Record::NSEC3 { domain, .. }
+            | Record::NSEC3PARAM { domain, .. }
+            | Record::NID { domain, .. }
+            | Record::L32 { domain, .. }
+            | Record::L64 { domain, .. }
+            | Record::LP { domain, .. }
+            | Record::CAA { domain, .. }
+            | Record::UNKNOWN { domain, .. } => domain,
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match self {
+            Record::A { domain, .. }
+            | Record::NS { domain, .. }
+            | Record::CNAME { domain, .. }
+            | Record::SOA { domain, .. }
+            | Record::WKS { domain, .. }
+            | Record::PTR { domain, .. }
+            | Record::MX { domain, .. }
+            | Record::TXT { domain, .. }
+            | Record::AAAA { domain, .. }
+            | Record::SRV { domain, .. }
+            | Record::DNAME { domain, .. }
+            | Record::OPT { domain, .. }
+            | Record::DHCID { domain, .. }
+            | Record::NSEC3 { domain, .. }
+            | Record::NSEC3PARAM { domain, .. }
+            | Record::NID { domain, .. }
+            | Record::L32 { domain, .. }
+            | Record::L64 { domain, .. }
+            | Record::LP { domain, .. }
+            | Record::CAA { domain, .. }
+            | Record::UNKNOWN { domain, .. } => *domain = new_domain.to_string(),
+        }
+    }
+
+    pub fn ttl(&self) -> u32 {
+        match self {
+            Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::WKS { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::TXT { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::SRV { ttl, .. }
+            | Record::DNAME { ttl, .. }
+            | Record::DHCID { ttl, .. }
+            | Record::NSEC3 { ttl, .. }
+            | Record::NSEC3PARAM { ttl, .. }
+            | Record::NID { ttl, .. }
+            | Record::L32 { ttl, .. }
+            | Record::L64 { ttl, .. }
+            | Record::LP { ttl, .. }
+            | Record::CAA { ttl, .. }
+            | Record::UNKNOWN { ttl, .. } => *ttl,
+            Record::OPT { .. } => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match self {
+            Record::A { ttl, .. }
+            | Record::NS { ttl, .. }
+            | Record::CNAME { ttl, .. }
+            | Record::SOA { ttl, .. }
+            | Record::WKS { ttl, .. }
+            | Record::PTR { ttl, .. }
+            | Record::MX { ttl, .. }
+            | Record::TXT { ttl, .. }
+            | Record::AAAA { ttl, .. }
+            | Record::SRV { ttl, .. }
+            | Record::DNAME { ttl, .. }
+            | Record::DHCID { ttl, .. }
+            | Record::NSEC3 { ttl, .. }
+            | Record::NSEC3PARAM { ttl, .. }
+            | Record::NID { ttl, .. }
+            | Record::L32 { ttl, .. }
+            | Record::L64 { ttl, .. }
+            | Record::LP { ttl, .. }
+            | Record::CAA { ttl, .. }
+            | Record::UNKNOWN { ttl, .. } => *ttl = new_ttl,
+            Record::OPT { .. } => {}
+        }
+    }
+
+    pub fn qtype(&self) -> QueryType {
+        match self {
+            Record::A { .. } => QueryType::A,
+            Record::NS { .. } => QueryType::NS,
+            Record::CNAME { .. } => QueryType::CNAME,
+            Record::SOA { .. } => QueryType::SOA,
+            Record::WKS { .. } => QueryType::WKS,
+            Record::PTR { .. } => QueryType::PTR,
+            Record::MX { .. } => QueryType::MX,
+            Record::TXT { .. } => QueryType::TXT,
+            Record::AAAA { .. } => QueryType::AAAA,
+            Record::SRV { .. } => QueryType::SRV,
+            Record::DNAME { .. } => QueryType::DNAME,
+            Record::OPT { .. } => QueryType::OPT,
+            Record::DHCID { .. } => QueryType::DHCID,
+            Record::NSEC3 { .. } => QueryType::NSEC3,
+            Record::NSEC3PARAM { .. } => QueryType::NSEC3PARAM,
+            Record::NID { .. } => QueryType::NID,
+            Record::L32 { .. } => QueryType::L32,
+            Record::L64 { .. } => QueryType::L64,
+            Record::LP { .. } => QueryType::LP,
+            Record::CAA { .. } => QueryType::CAA,
+            Record::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(*qtype),
+        }
+    }
+
+    pub fn class(&self) -> QueryClass {
+        match self {
+            Record::A { class, .. }
+            | Record::NS { class, .. }
+            | Record::CNAME { class, .. }
+            | Record::SOA { class, .. }
+            | Record::WKS { class, .. }
+            | Record::PTR { class, .. }
+            | Record::MX { class, .. }
+            | Record::TXT { class, .. }
+            | Record::AAAA { class, .. }
+            | Record::SRV { class, .. }
+            | Record::DNAME { class, .. }
+            | Record::DHCID { class, .. }
+            | Record::NSEC3 { class, .. }
+            | Record::NSEC3PARAM { class, .. }
+            | Record::NID { class, .. }
+            | Record::L32 { class, .. }
+            | Record::L64 { class, .. }
+            | Record::LP { class, .. }
+            | Record::CAA { class, .. }
+            | Record::UNKNOWN { class, .. } => *class,
+            Record::OPT { udp_size, .. } => QueryClass::UNKNOWN(*udp_size),
+        }
+    }
+}
+
+// RFC 1035 types without a record of their own whose rdata is made up of
+// names that may be compressed (RFC 3597 section 4)
+// length prefixed fields only have a byte for their length, so anything
+// longer would be written as a truncated length followed by all the bytes
+fn octet_len(field: &str, bytes: &[u8]) -> Result<u8> {
+    u8::try_from(bytes.len()).map_err(|_| {
+        DnsError::MalformedRecord(format!(
+            "{} of {} bytes exceeds 255 bytes",
+            field,
+            bytes.len()
+        ))
+    })
+}
+
+fn rdata_len(field: &str, fixed: usize, bytes: &[u8]) -> Result<u16> {
+    u16::try_from(fixed + bytes.len()).map_err(|_| {
+        DnsError::MalformedRecord(format!(
+            "{} of {} bytes exceeds the rdata limit",
+            field,
+            bytes.len()
+        ))
+    })
+}
+
+fn compressed_names(qtype: u16) -> usize {
+    match qtype {
+        // MD, MF, MB, MG and MR
+        3 | 4 | 7 | 8 | 9 => 1,
+        // MINFO
+        14 => 2,
+        _ => 0,
+    }
+}
+
+fn fqdn(name: &str) -> String {
+    format!("{}.", name.trim_end_matches('.'))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_string();
+    }
+
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn locator64(value: u64) -> String {
+    format!(
+        "{:04x}:{:04x}:{:04x}:{:04x}",
+        value >> 48,
+        (value >> 32) & 0xFFFF,
+        (value >> 16) & 0xFFFF,
+        value & 0xFFFF
+    )
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t",
+            fqdn(self.domain()),
+            self.ttl(),
+            self.class(),
+            self.qtype()
+        )?;
+
+        match self {
+            Record::A { address, .. } => write!(f, "{}", address),
+            Record::NS { host, .. } | Record::CNAME { host, .. } | Record::PTR { host, .. } => {
+                write!(f, "{}", fqdn(host))
+            }
+            Record::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {} {}",
+                fqdn(mname),
+                fqdn(rname),
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum
+            ),
+            Record::WKS {
+                address,
+                protocol,
+                bitmap,
+                ..
+            } => write!(f, "{} {} {}", address, protocol, hex(bitmap)),
+            Record::MX { priority, host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            Record::TXT { strings, .. } => {
+                let strings = strings
+                    .iter()
+                    .map(|string| format!("{:?}", string))
+                    .collect::<Vec<_>>();
+
+                write!(f, "{}", strings.join(" "))
+            }
+            Record::AAAA { address, .. } => write!(f, "{}", address),
+            Record::SRV {
+                priority,
+                weight,
+                port,
+                target,
+                ..
+            } => write!(f, "{} {} {} {}", priority, weight, port, fqdn(target)),
+            Record::DNAME { target, .. } => write!(f, "{}", fqdn(target)),
+            Record::OPT {
+                extended_rcode,
+                version,
+                flags,
+                data,
+                ..
+            } => write!(
+                f,
+                "{} {} {:04x} {}",
+                extended_rcode,
+                version,
+                flags,
+                hex(data)
+            ),
+            Record::DHCID { data, .. } => write!(f, "{}", STANDARD.encode(data)),
+            Record::NSEC3 {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                next_hashed_owner,
+                type_bitmap,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                hex(salt),
+                hex(next_hashed_owner),
+                hex(type_bitmap)
+            ),
+            Record::NSEC3PARAM {
+                hash_algorithm,
+                flags,
+                iterations,
+                salt,
+                ..
+            } => write!(
+                f,
+                "{} {} {} {}",
+                hash_algorithm,
+                flags,
+                iterations,
+                hex(salt)
+            ),
+            Record::NID {
+                preference,
+                node_id,
+                ..
+            } => write!(f, "{} {}", preference, locator64(*node_id)),
+            Record::L32 {
+                preference,
+                locator,
+                ..
+            } => write!(f, "{} {}", preference, locator),
+            Record::L64 {
+                preference,
+                locator,
+                ..
+            } => write!(f, "{} {}", preference, locator64(*locator)),
+            Record::LP {
+                preference, host, ..
+            } => write!(f, "{} {}", preference, fqdn(host)),
+            Record::CAA {
+                flags, tag, value, ..
+            } => write!(f, "{} {} {:?}", flags, tag, value),
+            Record::UNKNOWN { data, .. } if data.is_empty() => write!(f, "\\# 0"),
+            Record::UNKNOWN { data, .. } => write!(f, "\\# {} {}", data.len(), hex(data)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Packet {
+    /// header
+    pub header: Header,
+    /// the question for the name server
+    pub questions: Vec<Question>,
+    /// resource records answering the question
+    pub answers: Vec<Record>,
+    /// resource records pointing toward an authority
     pub authorities: Vec<Record>,
     /// resource records holding additional information
     pub additions: Vec<Record>,
 }
 
+impl Default for Packet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Packet {
     pub fn new() -> Self {
         Self {
@@ -465,6 +1825,51 @@ impl Packet {
         }
     }
 
+    pub fn query(qname: &str, qtype: QueryType) -> Self {
+        Self::query_with_class(qname, qtype, QueryClass::IN)
+    }
+
+    pub fn query_with_class(qname: &str, qtype: QueryType, qclass: QueryClass) -> Self {
+        let mut packet = Packet::new();
+        packet.header.id = rand::random();
+        packet.header.qdcount = 1;
+        packet.header.rd = true;
+
+        packet.with_question(Question::new(qname.to_string(), qtype).with_class(qclass))
+    }
+
+    pub fn response_to(request: &Packet) -> Self {
+        let mut packet = Packet::new();
+        packet.header.id = request.header.id;
+        packet.header.opcode = request.header.opcode;
+        packet.header.rd = request.header.rd;
+        packet.header.response = true;
+        packet.header.qdcount = request.questions.len() as u16;
+        packet.questions = request.questions.clone();
+
+        packet
+    }
+
+    pub fn with_question(mut self, question: Question) -> Self {
+        self.questions.push(question);
+        self
+    }
+
+    pub fn with_answer(mut self, answer: Record) -> Self {
+        self.answers.push(answer);
+        self
+    }
+
+    pub fn with_authority(mut self, authority: Record) -> Self {
+        self.authorities.push(authority);
+        self
+    }
+
+    pub fn with_addition(mut self, addition: Record) -> Self {
+        self.additions.push(addition);
+        self
+    }
+
     pub fn from_buffer(buffer: &mut BytePacketBuffer) -> Result<Self> {
         let mut result = Packet::new();
 
@@ -488,15 +1893,89 @@ impl Packet {
             result.additions.push(Record::read(buffer)?);
         }
 
+        // the OPT record carries the upper 8 bits of a 12-bit rcode
+        if let Some(Record::OPT { extended_rcode, .. }) = result.opt() {
+            let rcode = (*extended_rcode as u16) << 4 | u16::from(result.header.rcode);
+            result.header.rcode = ResponseCode::from(rcode);
+        }
+
         Ok(result)
     }
 
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Self::from_buffer(&mut BytePacketBuffer::from_slice(data))
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let digits = hex
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| {
+                c.to_digit(16)
+                    .map(|digit| digit as u8)
+                    .ok_or_else(|| DnsError::InvalidEncoding(format!("invalid hex digit {:?}", c)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        if digits.len() % 2 != 0 {
+            return Err(DnsError::InvalidEncoding(
+                "odd number of hex digits".to_string(),
+            ));
+        }
+
+        let data = digits
+            .chunks(2)
+            .map(|pair| pair[0] << 4 | pair[1])
+            .collect::<Vec<_>>();
+
+        Self::from_bytes(&data)
+    }
+
+    pub fn from_base64(text: &str) -> Result<Self> {
+        let text = text
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect::<String>();
+
+        let data = STANDARD
+            .decode(&text)
+            .or_else(|_| URL_SAFE_NO_PAD.decode(&text))
+            .map_err(|e| DnsError::InvalidEncoding(e.to_string()))?;
+
+        Self::from_bytes(&data)
+    }
+
+    pub fn to_hex(&mut self) -> Result<String> {
+        let mut buffer = BytePacketBuffer::new();
+        self.write(&mut buffer)?;
+
+        let hex = buffer
+            .get_range(0, buffer.position)?
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Ok(hex)
+    }
+
     pub fn write(&mut self, buffer: &mut BytePacketBuffer) -> Result<()> {
         self.header.qdcount = self.questions.len() as u16;
         self.header.ancount = self.answers.len() as u16;
         self.header.nscount = self.authorities.len() as u16;
         self.header.arcount = self.additions.len() as u16;
 
+        let rcode = u16::from(self.header.rcode);
+
+        match self
+            .additions
+            .iter_mut()
+            .find(|addition| matches!(addition, Record::OPT { .. }))
+        {
+            Some(Record::OPT { extended_rcode, .. }) => *extended_rcode = (rcode >> 4) as u8,
+            _ if rcode > 0x0F => return Err(DnsError::ExtendedRcodeWithoutOpt(rcode)),
+            _ => {}
+        }
+
         self.header.write(buffer)?;
 
         for question in &self.questions {
@@ -518,13 +1997,135 @@ impl Packet {
         Ok(())
     }
 
+    pub fn write_into(&mut self, out: &mut [u8]) -> Result<usize> {
+        let mut buffer = BytePacketBuffer::wrap(out);
+        self.write(&mut buffer)?;
+
+        Ok(buffer.position)
+    }
+
+    pub fn truncate(&mut self) {
+        self.header.tc = true;
+        self.answers.clear();
+        self.authorities.clear();
+        self.additions
+            .retain(|addition| matches!(addition, Record::OPT { .. }));
+    }
+
+    /// Drops the last RRset of the additional section, or of the authority
+    /// section once only the OPT record is left in the additional section.
+    /// Returns whether there was an RRset to drop.
+    pub fn drop_last_rrset(&mut self) -> bool {
+        drop_last_rrset(&mut self.additions) || drop_last_rrset(&mut self.authorities)
+    }
+
+    pub fn edns_udp_size(&self) -> Option<u16> {
+        match self.opt()? {
+            Record::OPT { udp_size, .. } => Some(*udp_size),
+            _ => None,
+        }
+    }
+
+    pub fn opt(&self) -> Option<&Record> {
+        self.additions
+            .iter()
+            .find(|addition| matches!(addition, Record::OPT { .. }))
+    }
+
+    pub fn question_names(&self) -> impl Iterator<Item = &str> {
+        self.questions
+            .iter()
+            .map(|question| question.qname.as_str())
+    }
+
+    pub fn has_question(&self, qname: &str, qtype: QueryType) -> bool {
+        self.questions
+            .iter()
+            .any(|question| question.qname.eq_ignore_ascii_case(qname) && question.qtype == qtype)
+    }
+
     pub fn get_random_a(&self) -> Option<Ipv4Addr> {
+        self.answers
+            .iter()
+            .filter_map(|answer| match answer {
+                Record::A { address, .. } => Some(*address),
+                _ => None,
+            })
+            .choose(&mut rand::thread_rng())
+    }
+
+    pub fn get_random_aaaa(&self) -> Option<Ipv6Addr> {
+        self.answers
+            .iter()
+            .filter_map(|answer| match answer {
+                Record::AAAA { address, .. } => Some(*address),
+                _ => None,
+            })
+            .choose(&mut rand::thread_rng())
+    }
+
+    pub fn get_negative_ttl(&self) -> Option<u32> {
+        match self.get_soa()? {
+            Record::SOA { minimum, ttl, .. } => Some((*minimum).min(*ttl)),
+            _ => None,
+        }
+    }
+
+    pub fn get_soa(&self) -> Option<&Record> {
+        self.authorities
+            .iter()
+            .find(|authority| matches!(authority, Record::SOA { .. }))
+    }
+
+    pub fn get_cname(&self, qname: &str) -> Option<&str> {
         self.answers.iter().find_map(|answer| match answer {
-            Record::A { address, .. } => Some(*address),
+            Record::CNAME { domain, host, .. } if domain.eq_ignore_ascii_case(qname) => {
+                Some(host.as_str())
+            }
             _ => None,
         })
     }
 
+    pub fn synthesize_cname(&mut self, qname: &str) -> Option<String> {
+        let qname = qname.trim_end_matches('.');
+
+        let (domain, target, class, ttl) = self.answers.iter().find_map(|answer| match answer {
+            Record::DNAME {
+                domain,
+                target,
+                class,
+                ttl,
+            } if !domain.eq_ignore_ascii_case(qname) && is_subdomain_of(qname, domain) => {
+                Some((domain, target, *class, *ttl))
+            }
+            _ => None,
+        })?;
+
+        // the owner itself is not redirected, only the names below it
+        let prefix = &qname[..qname.len() - domain.trim_end_matches('.').len()];
+        let host = format!("{}{}", prefix, target.trim_end_matches('.'));
+
+        debug!(
+            "synthesizing cname {} -> {} from dname {}",
+            qname, host, domain
+        );
+
+        self.answers.push(Record::CNAME {
+            domain: qname.to_string(),
+            host: host.clone(),
+            class,
+            ttl,
+        });
+
+        Some(host)
+    }
+
+    pub fn has_answer_for(&self, qname: &str) -> bool {
+        self.answers
+            .iter()
+            .any(|answer| answer.domain().eq_ignore_ascii_case(qname))
+    }
+
     pub fn get_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
         self.authorities
             .iter()
@@ -532,26 +2133,1159 @@ impl Packet {
                 Record::NS { domain, host, .. } => Some((domain.as_str(), host.as_str())),
                 _ => None,
             })
-            .filter(|(domain, _)| qname.ends_with(*domain))
+            .filter(|(domain, _)| is_subdomain_of(qname, domain))
     }
 
-    pub fn get_resolved_ns(&self, qname: &str) -> Option<Ipv4Addr> {
-        self.get_ns(qname)
-            .flat_map(|(_, host)| {
-                self.additions
-                    .iter()
-                    .filter_map(move |addition| match addition {
-                        Record::A {
-                            domain, address, ..
-                        } if domain == host => Some(address),
-                        _ => None,
-                    })
-            })
-            .map(|address| *address)
-            .next()
+    pub fn get_resolved_ns<'a>(&'a self, qname: &'a str) -> impl Iterator<Item = IpAddr> + 'a {
+        self.get_ns(qname).flat_map(move |(_, host)| {
+            self.additions
+                .iter()
+                .filter_map(move |addition| match addition {
+                    Record::A {
+                        domain, address, ..
+                    } if domain == host => Some(IpAddr::V4(*address)),
+                    Record::AAAA {
+                        domain, address, ..
+                    } if domain == host => Some(IpAddr::V6(*address)),
+                    _ => None,
+                })
+        })
     }
 
     pub fn get_unresolved_ns<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
         self.get_ns(qname).map(|(_, host)| host).next()
     }
+
+    pub fn get_ns_zone<'a>(&'a self, qname: &'a str) -> Option<&'a str> {
+        self.get_ns(qname).map(|(domain, _)| domain).next()
+    }
+
+    pub fn is_lame(&self, qname: &str) -> bool {
+        match self.header.rcode {
+            ResponseCode::REFUSED => true,
+            ResponseCode::NOERROR => {
+                !self.header.aa && self.answers.is_empty() && self.get_ns(qname).next().is_none()
+            }
+            _ => false,
+        }
+    }
+
+    pub fn retain_in_bailiwick(&mut self, zone: &str) {
+        let in_bailiwick = |record: &Record| {
+            let in_zone = is_subdomain_of(record.domain(), zone);
+
+            if !in_zone {
+                debug!("discarding out-of-bailiwick record: {:?}", record);
+            }
+
+            in_zone
+        };
+
+        self.answers.retain(in_bailiwick);
+        self.authorities.retain(in_bailiwick);
+        self.additions.retain(in_bailiwick);
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let opcode = match self.header.opcode {
+            0 => "QUERY".to_string(),
+            1 => "IQUERY".to_string(),
+            2 => "STATUS".to_string(),
+            4 => "NOTIFY".to_string(),
+            5 => "UPDATE".to_string(),
+            opcode => opcode.to_string(),
+        };
+
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {:?}, id: {}",
+            opcode, self.header.rcode, self.header.id
+        )?;
+
+        let flags = [
+            ("qr", self.header.response),
+            ("aa", self.header.aa),
+            ("tc", self.header.tc),
+            ("rd", self.header.rd),
+            ("ra", self.header.ra),
+            ("ad", self.header.ad),
+            ("cd", self.header.cd),
+        ]
+        .iter()
+        .filter(|(_, set)| *set)
+        .map(|(flag, _)| *flag)
+        .collect::<Vec<_>>();
+
+        write!(
+            f,
+            ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            flags.join(" "),
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additions.len()
+        )?;
+
+        if let Some(Record::OPT {
+            udp_size,
+            version,
+            flags,
+            ..
+        }) = self
+            .additions
+            .iter()
+            .find(|record| matches!(record, Record::OPT { .. }))
+        {
+            write!(
+                f,
+                "\n\n;; OPT PSEUDOSECTION:\n; EDNS: version: {}, flags:{}; udp: {}",
+                version,
+                if flags & 0x8000 != 0 { " do" } else { "" },
+                udp_size
+            )?;
+        }
+
+        writeln!(f, "\n\n;; QUESTION SECTION:")?;
+
+        for question in &self.questions {
+            writeln!(
+                f,
+                ";{}\t\t{}\t{}",
+                fqdn(&question.qname),
+                question.qclass,
+                question.qtype
+            )?;
+        }
+
+        let sections = [
+            ("ANSWER", &self.answers),
+            ("AUTHORITY", &self.authorities),
+            ("ADDITIONAL", &self.additions),
+        ];
+
+        for (name, records) in sections {
+            let mut records = records
+                .iter()
+                .filter(|record| !matches!(record, Record::OPT { .. }))
+                .peekable();
+
+            if records.peek().is_none() {
+                continue;
+            }
+
+            writeln!(f, "\n;; {} SECTION:", name)?;
+
+            for record in records {
+                writeln!(f, "{}", record)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn srv_priority_and_weight(record: &Record) -> (u16, u16) {
+    match record {
+        Record::SRV {
+            priority, weight, ..
+        } => (*priority, *weight),
+        _ => (u16::MAX, 0),
+    }
+}
+
+pub fn sort_srv(records: &mut [Record]) {
+    records.sort_by_key(|record| srv_priority_and_weight(record).0);
+
+    let mut rng = rand::thread_rng();
+    let mut start = 0;
+
+    while start < records.len() {
+        let priority = srv_priority_and_weight(&records[start]).0;
+        let end = start
+            + records[start..]
+                .iter()
+                .take_while(|record| srv_priority_and_weight(record).0 == priority)
+                .count();
+
+        for i in start..end {
+            records[i..end].sort_by_key(|record| srv_priority_and_weight(record).1 != 0);
+
+            let total = records[i..end]
+                .iter()
+                .map(|record| srv_priority_and_weight(record).1 as u32)
+                .sum::<u32>();
+            let pick = rng.gen_range(0..=total);
+
+            let mut running = 0;
+            let chosen = (i..end)
+                .find(|&j| {
+                    running += srv_priority_and_weight(&records[j]).1 as u32;
+                    running >= pick
+                })
+                .unwrap_or(i);
+
+            records.swap(i, chosen);
+        }
+
+        start = end;
+    }
+}
+
+// every record sharing the last record's name and type goes with it, so
+// that no RRset is ever left partial
+fn drop_last_rrset(records: &mut Vec<Record>) -> bool {
+    let (domain, qtype) = match records
+        .iter()
+        .rev()
+        .find(|record| !matches!(record, Record::OPT { .. }))
+    {
+        Some(record) => (record.domain().to_string(), record.qtype()),
+        None => return false,
+    };
+
+    records.retain(|record| {
+        matches!(record, Record::OPT { .. })
+            || record.qtype() != qtype
+            || !record.domain().eq_ignore_ascii_case(&domain)
+    });
+
+    true
+}
+
+pub fn sort_mx(records: &mut [Record]) {
+    let positions = records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| matches!(record, Record::MX { .. }))
+        .map(|(position, _)| position)
+        .collect::<Vec<_>>();
+
+    let mut mx = positions
+        .iter()
+        .map(|&position| records[position].clone())
+        .collect::<Vec<_>>();
+
+    mx.shuffle(&mut rand::thread_rng());
+    mx.sort_by_key(|record| match record {
+        Record::MX { priority, .. } => *priority,
+        _ => u16::MAX,
+    });
+
+    for (position, record) in positions.into_iter().zip(mx) {
+        records[position] = record;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a_record, aaaa_record, mx_record, ns_record, srv_record, txt_record};
+    use std::collections::HashSet;
+
+    fn round_trip(record: Record) -> Record {
+        let mut packet = Packet::new().with_answer(record);
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+
+        let mut packet = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+        assert_eq!(packet.answers.len(), 1);
+
+        packet.answers.remove(0)
+    }
+
+    #[test]
+    fn builders_set_header_flags_and_counts() {
+        let query = Packet::query("example.com", QueryType::MX);
+
+        assert!(query.header.rd);
+        assert!(!query.header.response);
+        assert_eq!(query.header.opcode, 0);
+        assert_eq!(query.header.qdcount, 1);
+        assert!(query.has_question("example.com", QueryType::MX));
+
+        let mut response = Packet::response_to(&query)
+            .with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300))
+            .with_authority(ns_record("example.com", "ns1.example.com"));
+
+        assert_eq!(response.header.id, query.header.id);
+        assert!(response.header.response);
+        assert!(response.header.rd);
+        assert_eq!(response.questions, query.questions);
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer).unwrap();
+        let decoded = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+        assert_eq!(decoded.header.qdcount, 1);
+        assert_eq!(decoded.header.ancount, 1);
+        assert_eq!(decoded.header.nscount, 1);
+        assert_eq!(decoded.header.arcount, 0);
+    }
+
+    const QUERY_TYPES: [QueryType; 22] = [
+        QueryType::A,
+        QueryType::NS,
+        QueryType::CNAME,
+        QueryType::SOA,
+        QueryType::WKS,
+        QueryType::PTR,
+        QueryType::MX,
+        QueryType::TXT,
+        QueryType::AAAA,
+        QueryType::SRV,
+        QueryType::DNAME,
+        QueryType::OPT,
+        QueryType::DHCID,
+        QueryType::NSEC3,
+        QueryType::NSEC3PARAM,
+        QueryType::NID,
+        QueryType::L32,
+        QueryType::L64,
+        QueryType::LP,
+        QueryType::ANY,
+        QueryType::CAA,
+        QueryType::UNKNOWN(99),
+    ];
+
+    #[test]
+    fn query_type_display_parses_back() {
+        for qtype in QUERY_TYPES {
+            assert_eq!(qtype.to_string().parse::<QueryType>().unwrap(), qtype);
+        }
+
+        assert_eq!(QueryType::UNKNOWN(99).to_string(), "TYPE99");
+        assert_eq!("mx".parse::<QueryType>().unwrap(), QueryType::MX);
+        assert_eq!(
+            "type99".parse::<QueryType>().unwrap(),
+            QueryType::UNKNOWN(99)
+        );
+        // the generic form of a known type is the known type
+        assert_eq!("TYPE28".parse::<QueryType>().unwrap(), QueryType::AAAA);
+        assert!(matches!(
+            "BOGUS".parse::<QueryType>(),
+            Err(DnsError::UnknownType(name)) if name == "BOGUS"
+        ));
+        assert!("TYPE65536".parse::<QueryType>().is_err());
+    }
+
+    // example.com. 300 IN A 93.184.216.34, answering id 0x1234
+    const A_RESPONSE_HEX: &str = "1234 8180 0001 0001 0000 0000 \
+        076578616d706c6503636f6d00 0001 0001 \
+        c00c 0001 0001 0000012c 0004 5db8d822";
+    const A_RESPONSE_BASE64: &str = "EjSBgAABAAEAAAAAB2V4YW1wbGUDY29tAAABAAHADAABAAEAAAEsAARduNgi";
+
+    #[test]
+    fn known_a_response_is_decoded_from_hex_and_base64() {
+        let packet = Packet::from_hex(A_RESPONSE_HEX).unwrap();
+
+        assert_eq!(packet.header.id, 0x1234);
+        assert!(packet.header.response);
+        assert!(packet.header.rd);
+        assert!(packet.header.ra);
+        assert_eq!(packet.header.rcode, ResponseCode::NOERROR);
+        assert!(packet.has_question("example.com", QueryType::A));
+        assert_eq!(
+            packet.answers,
+            vec![a_record(
+                "example.com",
+                Ipv4Addr::new(93, 184, 216, 34),
+                300
+            )]
+        );
+
+        let decoded = Packet::from_base64(A_RESPONSE_BASE64).unwrap();
+        assert_eq!(decoded.header.id, packet.header.id);
+        assert_eq!(decoded.answers, packet.answers);
+        assert!(matches!(
+            Packet::from_hex("123"),
+            Err(DnsError::InvalidEncoding(_))
+        ));
+        assert!(matches!(
+            Packet::from_hex("zz"),
+            Err(DnsError::InvalidEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn packet_prints_like_dig() {
+        let mut request = Packet::query("example.com", QueryType::A);
+        request.header.id = 1234;
+
+        let mut packet = Packet::response_to(&request)
+            .with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300))
+            .with_authority(ns_record("example.com", "ns1.example.com"))
+            .with_addition(opt());
+        packet.header.ra = true;
+
+        assert_eq!(
+            packet.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 1234\n\
+             ;; flags: qr rd ra; QUERY: 1, ANSWER: 1, AUTHORITY: 1, ADDITIONAL: 1\n\
+             \n\
+             ;; OPT PSEUDOSECTION:\n\
+             ; EDNS: version: 0, flags:; udp: 4096\n\
+             \n\
+             ;; QUESTION SECTION:\n\
+             ;example.com.\t\tIN\tA\n\
+             \n\
+             ;; ANSWER SECTION:\n\
+             example.com.\t300\tIN\tA\t192.0.2.1\n\
+             \n\
+             ;; AUTHORITY SECTION:\n\
+             example.com.\t3600\tIN\tNS\tns1.example.com.\n"
+        );
+    }
+
+    #[test]
+    fn random_a_picks_every_address_over_many_calls() {
+        let addresses = [
+            Ipv4Addr::new(192, 0, 2, 1),
+            Ipv4Addr::new(192, 0, 2, 2),
+            Ipv4Addr::new(192, 0, 2, 3),
+        ];
+        let mut packet = Packet::new();
+        for address in addresses {
+            packet = packet.with_answer(a_record("example.com", address, 300));
+        }
+
+        // missing one of three in 200 uniform picks has odds of about 1e-35
+        let seen = (0..200)
+            .filter_map(|_| packet.get_random_a())
+            .collect::<HashSet<_>>();
+
+        assert_eq!(seen, addresses.into_iter().collect());
+        assert_eq!(Packet::new().get_random_a(), None);
+    }
+
+    #[test]
+    fn truncated_packet_is_end_of_buffer() {
+        let mut packet = Packet::query("example.com", QueryType::A);
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let data = buffer.get_range(0, buffer.position).unwrap();
+
+        for len in [0, 5, data.len() - 1] {
+            let error = Packet::from_bytes(&data[..len]).unwrap_err();
+
+            assert!(matches!(error, DnsError::EndOfBuffer), "{}", error);
+            assert_eq!(error.to_string(), "end of buffer");
+        }
+    }
+
+    #[test]
+    fn mx_records_are_sorted_by_priority_in_place() {
+        let a = a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300);
+        let mut records = vec![
+            mx_record("example.com", 20, "backup.example.com"),
+            a.clone(),
+            mx_record("example.com", 10, "mx1.example.com"),
+            mx_record("example.com", 10, "mx2.example.com"),
+        ];
+
+        sort_mx(&mut records);
+
+        assert_eq!(records[1], a);
+        assert_eq!(
+            records[3],
+            mx_record("example.com", 20, "backup.example.com")
+        );
+
+        let mut preferred = vec![records[0].clone(), records[2].clone()];
+        preferred.sort_by_key(|record| record.to_string());
+        assert_eq!(
+            preferred,
+            vec![
+                mx_record("example.com", 10, "mx1.example.com"),
+                mx_record("example.com", 10, "mx2.example.com")
+            ]
+        );
+    }
+
+    #[test]
+    fn ptr_round_trips() {
+        let record = Record::PTR {
+            domain: "1.2.0.192.in-addr.arpa".to_string(),
+            host: "host.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            "1.2.0.192.in-addr.arpa.\t300\tIN\tPTR\thost.example.com."
+        );
+    }
+
+    #[test]
+    fn unknown_type_round_trips_verbatim() {
+        let record = Record::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 99,
+            data: b"\x0bv=spf1 -all".to_vec(),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t300\tIN\tTYPE99\t\\# 12 0B763D73706631202D616C6C"
+        );
+    }
+
+    #[test]
+    fn write_into_fills_exactly_sized_slice() {
+        let mut packet = Packet::query("example.com", QueryType::A);
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let expected = buffer.get_range(0, buffer.position).unwrap();
+
+        let mut out = vec![0; expected.len()];
+        assert_eq!(packet.write_into(&mut out).unwrap(), expected.len());
+        assert_eq!(out, expected);
+
+        let mut out = vec![0; expected.len() - 1];
+        assert!(matches!(
+            packet.write_into(&mut out),
+            Err(DnsError::PacketTooLarge { limit, .. }) if limit == expected.len() - 1
+        ));
+    }
+
+    #[test]
+    fn write_into_leaves_rest_of_oversized_slice() {
+        let mut packet = Packet::query("example.com", QueryType::A);
+
+        let mut out = [0xAA; 512];
+        let len = packet.write_into(&mut out).unwrap();
+
+        assert_eq!(
+            Packet::from_bytes(&out[..len]).unwrap().questions,
+            packet.questions
+        );
+        assert!(out[len..].iter().all(|byte| *byte == 0xAA));
+    }
+
+    #[test]
+    fn opt_round_trips_with_do_bit() {
+        let record = Record::OPT {
+            domain: String::new(),
+            udp_size: 4096,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0x8000,
+            data: Vec::new(),
+        };
+
+        let mut packet = Packet::query("example.com", QueryType::A).with_addition(record.clone());
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let packet = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+        assert_eq!(packet.additions, vec![record]);
+        assert_eq!(packet.edns_udp_size(), Some(4096));
+    }
+
+    fn opt() -> Record {
+        Record::OPT {
+            domain: String::new(),
+            udp_size: 4096,
+            extended_rcode: 0,
+            version: 0,
+            flags: 0,
+            data: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn extended_rcode_is_split_and_merged() {
+        let mut packet = Packet::new().with_addition(opt());
+        packet.header.response = true;
+        packet.header.rcode = ResponseCode::BADVERS;
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let data = buffer.get_range(0, buffer.position).unwrap();
+
+        // 16 is a zero header nibble and an extended rcode of 1
+        assert_eq!(data[3] & 0x0F, 0);
+        assert!(matches!(
+            packet.additions[0],
+            Record::OPT {
+                extended_rcode: 1,
+                ..
+            }
+        ));
+
+        let packet = Packet::from_bytes(data).unwrap();
+        assert_eq!(packet.header.rcode, ResponseCode::BADVERS);
+    }
+
+    #[test]
+    fn extended_rcode_needs_opt() {
+        let mut packet = Packet::new();
+        packet.header.rcode = ResponseCode::BADVERS;
+
+        assert!(matches!(
+            packet.write(&mut BytePacketBuffer::new()),
+            Err(DnsError::ExtendedRcodeWithoutOpt(16))
+        ));
+    }
+
+    #[test]
+    fn ilnp_records_round_trip() {
+        let records = [
+            Record::NID {
+                domain: "example.com".to_string(),
+                preference: 10,
+                node_id: 0x0014_4fff_ff20_ee64,
+                class: QueryClass::IN,
+                ttl: 300,
+            },
+            Record::L32 {
+                domain: "example.com".to_string(),
+                preference: 10,
+                locator: Ipv4Addr::new(10, 1, 2, 0),
+                class: QueryClass::IN,
+                ttl: 300,
+            },
+            Record::L64 {
+                domain: "example.com".to_string(),
+                preference: 10,
+                locator: 0x2001_0db8_1140_1000,
+                class: QueryClass::IN,
+                ttl: 300,
+            },
+            Record::LP {
+                domain: "example.com".to_string(),
+                preference: 10,
+                host: "l64-subnet.example.com".to_string(),
+                class: QueryClass::IN,
+                ttl: 300,
+            },
+        ];
+
+        for record in records {
+            assert_eq!(round_trip(record.clone()), record);
+        }
+    }
+
+    fn packet_with_rdata(qtype: u16, rdata: &[u8]) -> Vec<u8> {
+        let mut buffer = BytePacketBuffer::new();
+        Header {
+            response: true,
+            ancount: 1,
+            ..Header::new()
+        }
+        .write(&mut buffer)
+        .unwrap();
+
+        buffer.write_qname("example.com").unwrap();
+        buffer.write_u16(qtype).unwrap();
+        buffer.write_u16(1).unwrap();
+        buffer.write_u32(300).unwrap();
+        buffer.write_u16(rdata.len() as u16).unwrap();
+        buffer.write_bytes(rdata).unwrap();
+
+        buffer.get_range(0, buffer.position).unwrap().to_vec()
+    }
+
+    #[test]
+    fn rdlength_must_match_fixed_size_rdata() {
+        // NID, L32 and L64 with one byte too few and one byte too many
+        for (qtype, len) in [(104, 10), (105, 6), (106, 10)] {
+            for rdata_len in [len - 1, len + 1] {
+                // padded so that a short record reads into the bytes after it
+                let mut data = packet_with_rdata(qtype, &vec![0; rdata_len]);
+                data.extend([0; 8]);
+
+                assert!(
+                    matches!(Packet::from_bytes(&data), Err(DnsError::MalformedRecord(_))),
+                    "type {} with {} bytes of rdata",
+                    qtype,
+                    rdata_len
+                );
+            }
+
+            assert!(Packet::from_bytes(&packet_with_rdata(qtype, &vec![0; len])).is_ok());
+        }
+    }
+
+    #[test]
+    fn rdlength_must_match_nsec3param_salt() {
+        // SHA-1, no flags, 10 iterations and a 4 byte salt
+        let rdata = b"\x01\x00\x00\x0a\x04\xaa\xbb\xcc\xdd";
+
+        let packet = Packet::from_bytes(&packet_with_rdata(51, rdata)).unwrap();
+        assert_eq!(
+            packet.answers,
+            vec![Record::NSEC3PARAM {
+                domain: "example.com".to_string(),
+                hash_algorithm: 1,
+                flags: 0,
+                iterations: 10,
+                salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+                class: QueryClass::IN,
+                ttl: 300,
+            }]
+        );
+
+        let mut padded = rdata.to_vec();
+        padded.push(0);
+
+        assert!(matches!(
+            Packet::from_bytes(&packet_with_rdata(51, &padded)),
+            Err(DnsError::MalformedRecord(_))
+        ));
+    }
+
+    // 0p9mhaveqvm6t7vbl5lop2u3t2rp3tom.example. NSEC3 1 1 12 aabbccdd (
+    //     2t7b4g4vsa5smi47k61mv5bv1a22bojr MX DNSKEY NS SOA NSEC3PARAM RRSIG )
+    // from the example zone in RFC 5155 appendix A
+    const NSEC3_RDATA: &[u8] = b"\x01\x01\x00\x0c\x04\xaa\xbb\xcc\xdd\x14\
+        \x17\x4e\xb2\x40\x9f\xe2\x8b\xcb\x48\x87\xa1\x83\x6f\x95\x7f\x0a\x84\x25\xe2\x7b\
+        \x00\x07\x22\x01\x00\x00\x00\x02\x90";
+
+    #[test]
+    fn nsec3_round_trips_from_captured_rdata() {
+        let packet = Packet::from_bytes(&packet_with_rdata(50, NSEC3_RDATA)).unwrap();
+        let record = Record::NSEC3 {
+            domain: "example.com".to_string(),
+            hash_algorithm: 1,
+            flags: 1,
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            next_hashed_owner: NSEC3_RDATA[10..30].to_vec(),
+            type_bitmap: vec![0x00, 0x07, 0x22, 0x01, 0x00, 0x00, 0x00, 0x02, 0x90],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+
+        let mut buffer = BytePacketBuffer::new();
+        record.write(&mut buffer).unwrap();
+        let written = buffer.get_range(0, buffer.position).unwrap();
+        assert!(written.ends_with(NSEC3_RDATA));
+    }
+
+    #[test]
+    fn nsec3param_round_trips_from_captured_rdata() {
+        // example. NSEC3PARAM 1 0 12 aabbccdd
+        let rdata = b"\x01\x00\x00\x0c\x04\xaa\xbb\xcc\xdd";
+        let packet = Packet::from_bytes(&packet_with_rdata(51, rdata)).unwrap();
+        let record = Record::NSEC3PARAM {
+            domain: "example.com".to_string(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 12,
+            salt: vec![0xaa, 0xbb, 0xcc, 0xdd],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t300\tIN\tNSEC3PARAM\t1 0 12 AABBCCDD"
+        );
+    }
+
+    #[test]
+    fn oversized_length_prefixed_fields_are_rejected() {
+        let nsec3 = |salt: usize, next_hashed_owner: usize| Record::NSEC3 {
+            domain: "example.com".to_string(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 12,
+            salt: vec![0xaa; salt],
+            next_hashed_owner: vec![0xbb; next_hashed_owner],
+            type_bitmap: Vec::new(),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        let nsec3param = Record::NSEC3PARAM {
+            domain: "example.com".to_string(),
+            hash_algorithm: 1,
+            flags: 0,
+            iterations: 12,
+            salt: vec![0xaa; 256],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+        let wks = Record::WKS {
+            domain: "example.com".to_string(),
+            address: Ipv4Addr::new(192, 0, 2, 1),
+            protocol: 6,
+            bitmap: vec![0xff; 0xFFFF],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(round_trip(nsec3(255, 255)), nsec3(255, 255));
+
+        for record in [nsec3(256, 20), nsec3(4, 256), nsec3param, wks] {
+            let mut buffer = BytePacketBuffer::new();
+            let result = Packet::new().with_answer(record).write(&mut buffer);
+
+            assert!(
+                matches!(result, Err(DnsError::MalformedRecord(_))),
+                "{:?}",
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn minfo_names_are_decompressed() {
+        let mut buffer = BytePacketBuffer::new();
+        Header {
+            response: true,
+            qdcount: 1,
+            ancount: 1,
+            ..Header::new()
+        }
+        .write(&mut buffer)
+        .unwrap();
+        Question::new("example.com".to_string(), QueryType::UNKNOWN(14))
+            .write(&mut buffer)
+            .unwrap();
+
+        // owner, rmailbx and emailbx all point back at the question name
+        buffer.write_u16(0xC00C).unwrap();
+        buffer.write_u16(14).unwrap();
+        buffer.write_u16(1).unwrap();
+        buffer.write_u32(300).unwrap();
+        buffer.write_u16(17).unwrap();
+        buffer.write_bytes(b"\x05admin\xC0\x0C").unwrap();
+        buffer.write_bytes(b"\x06errors\xC0\x0C").unwrap();
+
+        let mut packet = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+        let expected = Record::UNKNOWN {
+            domain: "example.com".to_string(),
+            qtype: 14,
+            data: b"\x05admin\x07example\x03com\x00\x06errors\x07example\x03com\x00".to_vec(),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![expected.clone()]);
+
+        // once written elsewhere the names must still resolve to the same data
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let packet = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+        assert_eq!(packet.answers, vec![expected]);
+    }
+
+    #[test]
+    fn caa_round_trips() {
+        let rdata = b"\x00\x05issueletsencrypt.org";
+        let packet = Packet::from_bytes(&packet_with_rdata(257, rdata)).unwrap();
+        let record = Record::CAA {
+            domain: "example.com".to_string(),
+            flags: 0,
+            tag: "issue".to_string(),
+            value: "letsencrypt.org".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t300\tIN\tCAA\t0 issue \"letsencrypt.org\""
+        );
+    }
+
+    #[test]
+    fn srv_fields_are_in_wire_order() {
+        let rdata = b"\x00\x0a\x00\x05\x14\x95\x04xmpp\x07example\x03com\x00";
+        let packet = Packet::from_bytes(&packet_with_rdata(33, rdata)).unwrap();
+
+        assert!(matches!(
+            &packet.answers[..],
+            [Record::SRV { priority: 10, weight: 5, port: 5269, target, .. }]
+                if target == "xmpp.example.com"
+        ));
+
+        let record = srv_record("_xmpp._tcp.example.com", 10, 5, 5269, "xmpp.example.com");
+        assert_eq!(round_trip(record.clone()), record);
+    }
+
+    #[test]
+    fn srv_records_are_grouped_by_priority() {
+        let mut records = vec![
+            srv_record("_xmpp._tcp.example.com", 20, 0, 5269, "backup.example.com"),
+            srv_record("_xmpp._tcp.example.com", 10, 60, 5269, "a.example.com"),
+            srv_record("_xmpp._tcp.example.com", 10, 40, 5269, "b.example.com"),
+            srv_record("_xmpp._tcp.example.com", 10, 0, 5269, "c.example.com"),
+        ];
+
+        sort_srv(&mut records);
+
+        assert_eq!(
+            records[3],
+            srv_record("_xmpp._tcp.example.com", 20, 0, 5269, "backup.example.com")
+        );
+
+        let mut preferred = records[..3].to_vec();
+        preferred.sort_by_key(|record| record.to_string());
+        assert_eq!(
+            preferred,
+            vec![
+                srv_record("_xmpp._tcp.example.com", 10, 0, 5269, "c.example.com"),
+                srv_record("_xmpp._tcp.example.com", 10, 40, 5269, "b.example.com"),
+                srv_record("_xmpp._tcp.example.com", 10, 60, 5269, "a.example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolved_ns_includes_aaaa_glue() {
+        let v4 = Ipv4Addr::new(192, 0, 2, 53);
+        let v6 = "2001:db8::53".parse::<Ipv6Addr>().unwrap();
+        let ns = |host: &str| ns_record("example.com", host);
+        let aaaa = |host: &str, address| aaaa_record(host, address, 3600);
+
+        let packet = Packet::new()
+            .with_authority(ns("ns1.example.com"))
+            .with_authority(ns("ns2.example.com"))
+            .with_addition(a_record("ns1.example.com", v4, 3600))
+            .with_addition(aaaa("ns2.example.com", v6))
+            // glue for a name that is not one of the nameservers is ignored
+            .with_addition(aaaa("other.example.com", Ipv6Addr::LOCALHOST));
+
+        assert_eq!(
+            packet
+                .get_resolved_ns("www.example.com")
+                .collect::<Vec<_>>(),
+            vec![IpAddr::V4(v4), IpAddr::V6(v6)]
+        );
+
+        let v6_only = Packet::new()
+            .with_authority(ns("ns2.example.com"))
+            .with_addition(aaaa("ns2.example.com", v6));
+
+        assert_eq!(
+            v6_only
+                .get_resolved_ns("www.example.com")
+                .collect::<Vec<_>>(),
+            vec![IpAddr::V6(v6)]
+        );
+        assert_eq!(v6_only.get_random_aaaa(), None);
+        assert_eq!(
+            Packet::new()
+                .with_answer(aaaa("example.com", v6))
+                .get_random_aaaa(),
+            Some(v6)
+        );
+    }
+
+    #[test]
+    fn buffer_grows_past_512_bytes() {
+        let mut packet = Packet::new();
+        for i in 0..20 {
+            packet = packet.with_answer(Record::TXT {
+                domain: "example.com".to_string(),
+                strings: vec![format!("{:0>100}", i)],
+                class: QueryClass::IN,
+                ttl: 300,
+            });
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        assert!(buffer.position > 2048, "{}", buffer.position);
+
+        let decoded = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+        assert_eq!(decoded.answers, packet.answers);
+    }
+
+    #[test]
+    fn fluent_packet_serializes_every_section() {
+        let answer = a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 1), 0);
+        let authority = ns_record("example.com", "ns1.example.com");
+        let addition = a_record("ns1.example.com", Ipv4Addr::new(192, 0, 2, 53), 3600);
+
+        let mut packet = Packet::new()
+            .with_question(Question::new("www.example.com".to_string(), QueryType::A))
+            .with_answer(answer.clone())
+            .with_authority(authority.clone())
+            .with_addition(addition.clone());
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let decoded = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+        assert_eq!(
+            (
+                decoded.header.qdcount,
+                decoded.header.ancount,
+                decoded.header.nscount,
+                decoded.header.arcount
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(decoded.questions, packet.questions);
+        // a zero TTL is written as is
+        assert_eq!(decoded.answers, vec![answer]);
+        assert_eq!(decoded.authorities, vec![authority]);
+        assert_eq!(decoded.additions, vec![addition]);
+    }
+
+    #[test]
+    fn two_question_packet_is_introspected() {
+        let mut packet = Packet::query("example.com", QueryType::A)
+            .with_question(Question::new("Example.NET".to_string(), QueryType::MX));
+
+        let mut buffer = BytePacketBuffer::new();
+        packet.write(&mut buffer).unwrap();
+        let decoded = Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+        assert_eq!(decoded.header.qdcount, 2);
+        assert_eq!(
+            decoded.question_names().collect::<Vec<_>>(),
+            vec!["example.com", "example.net"]
+        );
+        assert!(decoded.has_question("EXAMPLE.com", QueryType::A));
+        assert!(decoded.has_question("example.net", QueryType::MX));
+        assert!(!decoded.has_question("example.net", QueryType::A));
+        assert!(!decoded.has_question("example.org", QueryType::A));
+    }
+
+    #[test]
+    fn header_rcodes_round_trip_without_collapsing() {
+        for num in 0..16 {
+            let mut packet = Packet::new();
+            packet.header.response = true;
+            packet.header.rcode = ResponseCode::from(num);
+
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer).unwrap();
+            let decoded =
+                Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+            assert_eq!(u16::from(decoded.header.rcode), num);
+        }
+
+        assert_eq!(ResponseCode::from(9), ResponseCode::NOTAUTH);
+        assert_eq!(ResponseCode::from(12), ResponseCode::UNKNOWN(12));
+    }
+
+    #[test]
+    fn query_types_round_trip_through_numbers() {
+        for qtype in QUERY_TYPES {
+            assert_eq!(QueryType::from(u16::from(qtype)), qtype);
+
+            let mut packet = Packet::query("example.com", qtype);
+            let mut buffer = BytePacketBuffer::new();
+            packet.write(&mut buffer).unwrap();
+            let decoded =
+                Packet::from_bytes(buffer.get_range(0, buffer.position).unwrap()).unwrap();
+
+            assert!(decoded.has_question("example.com", qtype), "{}", qtype);
+        }
+
+        assert_eq!(u16::from(QueryType::AAAA), 28);
+        assert_eq!(QueryType::from(28), QueryType::AAAA);
+    }
+
+    #[test]
+    fn txt_keeps_each_character_string() {
+        let rdata = b"\x0bv=spf1 -all\x00\x05hello";
+        let packet = Packet::from_bytes(&packet_with_rdata(16, rdata)).unwrap();
+        let record = txt_record(
+            "example.com",
+            vec![
+                "v=spf1 -all".to_string(),
+                String::new(),
+                "hello".to_string(),
+            ],
+        );
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+
+        let longest = txt_record("example.com", vec!["a".repeat(255), "b".repeat(255)]);
+        assert_eq!(round_trip(longest.clone()), longest);
+    }
+
+    #[test]
+    fn txt_string_over_255_bytes_is_rejected() {
+        let mut packet =
+            Packet::new().with_answer(txt_record("example.com", vec!["a".repeat(256)]));
+
+        assert!(matches!(
+            packet.write(&mut BytePacketBuffer::new()),
+            Err(DnsError::MalformedRecord(_))
+        ));
+
+        // a length octet that runs past RDLENGTH
+        let rdata = b"\x05abc";
+        assert!(Packet::from_bytes(&packet_with_rdata(16, rdata)).is_err());
+    }
+
+    #[test]
+    fn dhcid_round_trips() {
+        // the client identifier example from RFC 4701 section 3.6
+        let encoded = "AAIBY2/AuCccgoJbsaxcQc9TUapptP69lOjxfNuVAA2kjEA=";
+        let data = STANDARD.decode(encoded).unwrap();
+        let packet = Packet::from_bytes(&packet_with_rdata(49, &data)).unwrap();
+        let record = Record::DHCID {
+            domain: "example.com".to_string(),
+            data,
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            format!("example.com.\t300\tIN\tDHCID\t{}", encoded)
+        );
+    }
+
+    #[test]
+    fn wks_round_trips() {
+        // 192.0.2.1 over TCP (6) offering ports 21, 22 and 25
+        let rdata = b"\xc0\x00\x02\x01\x06\x00\x00\x06\x40";
+        let packet = Packet::from_bytes(&packet_with_rdata(11, rdata)).unwrap();
+        let record = Record::WKS {
+            domain: "example.com".to_string(),
+            address: Ipv4Addr::new(192, 0, 2, 1),
+            protocol: 6,
+            bitmap: vec![0x00, 0x00, 0x06, 0x40],
+            class: QueryClass::IN,
+            ttl: 300,
+        };
+
+        assert_eq!(packet.answers, vec![record.clone()]);
+        assert_eq!(round_trip(record.clone()), record);
+        assert_eq!(
+            record.to_string(),
+            "example.com.\t300\tIN\tWKS\t192.0.2.1 6 00000640"
+        );
+    }
+
+    #[test]
+    fn get_ns_ignores_string_suffixes_and_case() {
+        let packet = Packet::new()
+            .with_authority(ns_record("ample.com", "ns.ample.com"))
+            .with_authority(ns_record("Example.COM", "ns.example.com"));
+
+        assert_eq!(
+            packet.get_ns("www.EXAMPLE.com").collect::<Vec<_>>(),
+            vec![("Example.COM", "ns.example.com")]
+        );
+        assert_eq!(packet.get_ns("www.ample.com").count(), 1);
+        assert_eq!(packet.get_ns("sample.com").count(), 0);
+    }
 }
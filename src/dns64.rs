@@ -0,0 +1,24 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub const DNS64_PREFIX: Ipv6Addr = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+
+pub fn synthesize_aaaa(prefix: Ipv6Addr, address: Ipv4Addr) -> Ipv6Addr {
+    // only /96 prefixes are supported, so the address fills the last 32 bits
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&address.octets());
+
+    Ipv6Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_fills_last_32_bits_of_prefix() {
+        assert_eq!(
+            synthesize_aaaa(DNS64_PREFIX, Ipv4Addr::new(192, 0, 2, 1)),
+            "64:ff9b::c000:201".parse::<Ipv6Addr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use crate::dns::{Packet, QueryType};
+use crate::error::DnsError;
+use crate::packet::BytePacketBuffer;
+use crate::utils::Result;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::debug;
+use std::io::Read;
+use std::time::Duration;
+
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+const DNS_MESSAGE: &str = "application/dns-message";
+const MAX_MESSAGE_LEN: u64 = 0xFFFF;
+
+pub fn lookup_doh(qname: &str, qtype: QueryType, url: &str) -> Result<Packet> {
+    // RFC 8484 asks for a zero id so that identical queries stay cacheable
+    let mut packet = Packet::query(qname, qtype);
+    packet.header.id = 0;
+
+    let mut request = BytePacketBuffer::new();
+    packet.write(&mut request)?;
+
+    let query = URL_SAFE_NO_PAD.encode(request.get_range(0, request.position)?);
+
+    debug!("querying {:?} {} over https from {}", qtype, qname, url);
+
+    let response = ureq::AgentBuilder::new()
+        .timeout(DOH_TIMEOUT)
+        .build()
+        .get(url)
+        .query("dns", &query)
+        .set("Accept", DNS_MESSAGE)
+        .call()
+        .map_err(|e| DnsError::Doh(e.to_string()))?;
+
+    if response.content_type() != DNS_MESSAGE {
+        return Err(DnsError::Doh(format!(
+            "unexpected content type {:?}",
+            response.content_type()
+        )));
+    }
+
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_MESSAGE_LEN)
+        .read_to_end(&mut body)?;
+
+    let response = Packet::from_buffer(&mut BytePacketBuffer::from_slice(&body))?;
+
+    if !response.has_question(qname, qtype) {
+        return Err(DnsError::Doh(format!(
+            "response does not answer {:?} {}",
+            qtype, qname
+        )));
+    }
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::a_record;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::{self, JoinHandle};
+
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+    // answers a single GET with the query it carries plus one A record, and
+    // hands back the query it was sent
+    fn serve_once(content_type: &'static str) -> (String, JoinHandle<Packet>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let url = format!("http://{}/dns-query", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+
+                if header.trim().is_empty() {
+                    break;
+                }
+            }
+
+            let query = request_line
+                .split_whitespace()
+                .nth(1)
+                .and_then(|path| path.split_once("?dns="))
+                .map(|(_, query)| URL_SAFE_NO_PAD.decode(query).unwrap())
+                .unwrap();
+            let query = Packet::from_bytes(&query).unwrap();
+
+            let mut response =
+                Packet::response_to(&query).with_answer(a_record("example.com", ADDRESS, 300));
+            let mut body = BytePacketBuffer::new();
+            response.write(&mut body).unwrap();
+            let body = body.get_range(0, body.position).unwrap();
+
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(body).unwrap();
+
+            query
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn query_is_sent_as_base64url_get() {
+        let (url, server) = serve_once(DNS_MESSAGE);
+
+        let response = lookup_doh("example.com", QueryType::A, &url).unwrap();
+        let query = server.join().unwrap();
+
+        assert_eq!(query.header.id, 0);
+        assert!(query.has_question("example.com", QueryType::A));
+        assert_eq!(
+            response.answers,
+            vec![a_record("example.com", ADDRESS, 300)]
+        );
+    }
+
+    #[test]
+    fn unexpected_content_type_is_rejected() {
+        let (url, server) = serve_once("text/html");
+
+        let result = lookup_doh("example.com", QueryType::A, &url);
+        server.join().unwrap();
+
+        assert!(matches!(result, Err(DnsError::Doh(_))));
+    }
+}
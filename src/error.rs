@@ -0,0 +1,89 @@
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DnsError {
+    /// read or write past the end of the buffer
+    #[error("end of buffer")]
+    EndOfBuffer,
+    /// too many compression pointers followed in a single name
+    #[error("limit of {0} jumps exceeded")]
+    TooManyJumps(usize),
+    /// a compression pointer that does not point backward
+    #[error("compression pointer to {offset} at {position} does not point backward")]
+    InvalidPointer { offset: usize, position: usize },
+    /// a label longer than 63 octets
+    #[error("single label exceeds 63 characters of length")]
+    LabelTooLong,
+    /// a name longer than 255 octets once encoded
+    #[error("name exceeds 255 octets of length")]
+    NameTooLong,
+    /// a record type mnemonic that is not recognized
+    #[error("unknown record type {0:?}")]
+    UnknownType(String),
+    /// a name that cannot be encoded
+    #[error("invalid name: {0}")]
+    InvalidName(String),
+    /// record data inconsistent with its length or type
+    #[error("malformed record: {0}")]
+    MalformedRecord(String),
+    /// a zone file line that cannot be parsed
+    #[error("zone line {line}: {reason}")]
+    ZoneSyntax { line: usize, reason: String },
+    /// an rcode above 15 in a packet without an OPT record to carry its upper bits
+    #[error("rcode {0} needs an OPT record")]
+    ExtendedRcodeWithoutOpt(u16),
+    /// an encoded packet larger than the space available for it
+    #[error("packet of at least {len} bytes exceeds {limit} byte output")]
+    PacketTooLarge { len: usize, limit: usize },
+    /// resolution could not be completed
+    #[error("{0}")]
+    Resolution(String),
+    /// an upstream server that failed to answer usefully
+    #[error("upstream {server}: {kind}")]
+    Upstream {
+        server: SocketAddr,
+        kind: UpstreamError,
+    },
+    /// text that is not a valid hex or base64 encoding of a packet
+    #[error("invalid encoding: {0}")]
+    InvalidEncoding(String),
+    /// a DNS-over-HTTPS request that failed
+    #[error("doh: {0}")]
+    Doh(String),
+    /// socket or file error
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// unparsable address
+    #[error(transparent)]
+    AddrParse(#[from] AddrParseError),
+}
+
+/// why an upstream query failed, counted per upstream in `Stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Error)]
+pub enum UpstreamError {
+    /// no usable response before the last retry timed out
+    #[error("timed out")]
+    Timeout,
+    /// the server's host reported that nothing listens on its port
+    #[error("connection refused")]
+    ConnectionRefused,
+    /// the server sent something that does not parse as a response
+    #[error("malformed response")]
+    MalformedResponse,
+    /// the server answered with SERVFAIL or REFUSED
+    #[error("failure rcode")]
+    Rcode,
+}
+
+impl UpstreamError {
+    pub fn label(self) -> &'static str {
+        match self {
+            UpstreamError::Timeout => "timeout",
+            UpstreamError::ConnectionRefused => "connection_refused",
+            UpstreamError::MalformedResponse => "malformed_response",
+            UpstreamError::Rcode => "rcode_failure",
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use crate::dns::{Packet, Question, ResponseCode};
+use std::fmt;
+use std::sync::Arc;
+
+type PreHookFn = dyn Fn(&mut Question) -> HookAction + Send + Sync;
+type PostHookFn = dyn Fn(&mut Packet) + Send + Sync;
+
+pub type PreHook = Box<PreHookFn>;
+pub type PostHook = Box<PostHookFn>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HookAction {
+    /// go on with the question as the hook left it
+    Allow,
+    /// look up this name instead, answering under the name asked for
+    Rewrite(String),
+    /// answer with this rcode without looking the question up
+    Respond(ResponseCode),
+}
+
+#[derive(Clone, Default)]
+pub struct Hooks {
+    /// run in order on each question before it is answered
+    pre: Vec<Arc<PreHookFn>>,
+    /// run in order on each response before it is sent
+    post: Vec<Arc<PostHookFn>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pre_hook(&mut self, hook: PreHook) {
+        self.pre.push(Arc::from(hook));
+    }
+
+    pub fn add_post_hook(&mut self, hook: PostHook) {
+        self.post.push(Arc::from(hook));
+    }
+
+    // the first hook to respond stops the rest from running
+    pub fn before(&self, question: &mut Question) -> Option<ResponseCode> {
+        for hook in &self.pre {
+            match hook(question) {
+                HookAction::Allow => {}
+                HookAction::Rewrite(qname) => question.qname = qname,
+                HookAction::Respond(rcode) => return Some(rcode),
+            }
+        }
+
+        None
+    }
+
+    pub fn after(&self, packet: &mut Packet) {
+        for hook in &self.post {
+            hook(packet);
+        }
+    }
+}
+
+impl fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("pre", &self.pre.len())
+            .field("post", &self.post.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::QueryType;
+
+    #[test]
+    fn responding_hook_stops_later_hooks() {
+        let mut hooks = Hooks::new();
+        hooks.add_pre_hook(Box::new(|question| {
+            if question.qname.ends_with(".internal") {
+                HookAction::Respond(ResponseCode::REFUSED)
+            } else {
+                HookAction::Allow
+            }
+        }));
+        hooks.add_pre_hook(Box::new(|_| {
+            HookAction::Rewrite("later.example".to_string())
+        }));
+
+        let mut question = Question::new("db.internal".to_string(), QueryType::A);
+        assert_eq!(hooks.before(&mut question), Some(ResponseCode::REFUSED));
+        assert_eq!(question.qname, "db.internal");
+
+        let mut question = Question::new("www.example".to_string(), QueryType::A);
+        assert_eq!(hooks.before(&mut question), None);
+        assert_eq!(question.qname, "later.example");
+    }
+}
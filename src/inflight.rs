@@ -0,0 +1,139 @@
+use crate::dns::{Packet, QueryClass, QueryType};
+use crate::error::DnsError;
+use crate::utils::{lock, Result};
+use log::debug;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+
+type Key = (String, QueryType, QueryClass);
+
+#[derive(Debug, Default)]
+struct Pending {
+    /// the outcome of the lookup, set once it finishes
+    result: Mutex<Option<std::result::Result<Packet, String>>>,
+    /// signalled when the result is set
+    done: Condvar,
+}
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    /// lookups currently being performed, keyed by question
+    lookups: Mutex<HashMap<Key, Arc<Pending>>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn join<F>(&self, key: Key, lookup: F) -> Result<Packet>
+    where
+        F: FnOnce() -> Result<Packet>,
+    {
+        let pending = {
+            let mut lookups = lock(&self.lookups);
+
+            match lookups.get(&key) {
+                Some(pending) => Some(Arc::clone(pending)),
+                None => {
+                    lookups.insert(key.clone(), Arc::default());
+                    None
+                }
+            }
+        };
+
+        match pending {
+            Some(pending) => {
+                debug!("waiting on lookup in flight: {:?}", key);
+                pending.wait()
+            }
+            None => {
+                let result = lookup();
+
+                if let Some(pending) = lock(&self.lookups).remove(&key) {
+                    pending.finish(&result);
+                }
+
+                result
+            }
+        }
+    }
+}
+
+impl Pending {
+    fn finish(&self, result: &Result<Packet>) {
+        *lock(&self.result) = Some(match result {
+            Ok(packet) => Ok(packet.clone()),
+            Err(e) => Err(e.to_string()),
+        });
+
+        self.done.notify_all();
+    }
+
+    fn wait(&self) -> Result<Packet> {
+        let mut result = lock(&self.result);
+
+        loop {
+            match result.as_ref() {
+                Some(Ok(packet)) => return Ok(packet.clone()),
+                Some(Err(e)) => return Err(DnsError::Resolution(e.clone())),
+                None => {
+                    result = self
+                        .done
+                        .wait(result)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Barrier;
+    use std::thread;
+    use std::time::Duration;
+
+    const CLIENTS: usize = 8;
+
+    #[test]
+    fn simultaneous_lookups_share_one_result() {
+        let inflight = InFlight::new();
+        let lookups = AtomicUsize::new(0);
+        let barrier = Barrier::new(CLIENTS);
+        let key = ("example.com".to_string(), QueryType::A, QueryClass::IN);
+
+        let results = thread::scope(|scope| {
+            let clients = (0..CLIENTS)
+                .map(|_| {
+                    scope.spawn(|| {
+                        barrier.wait();
+
+                        inflight.join(key.clone(), || {
+                            lookups.fetch_add(1, Ordering::SeqCst);
+                            // hold the lookup open until every client has joined it
+                            thread::sleep(Duration::from_millis(200));
+
+                            let mut packet = Packet::new();
+                            packet.header.id = 1234;
+                            Ok(packet)
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            clients
+                .into_iter()
+                .map(|client| client.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+        assert!(results
+            .iter()
+            .all(|result| matches!(result, Ok(packet) if packet.header.id == 1234)));
+        assert!(lock(&inflight.lookups).is_empty());
+    }
+}
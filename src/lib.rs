@@ -1,119 +1,1206 @@
+mod blocklist;
+mod cache;
+mod challenge;
+mod config;
 mod dns;
+mod dns64;
+mod doh;
+mod error;
+mod hooks;
+mod inflight;
+#[cfg(feature = "async")]
+mod nonblocking;
+mod pacer;
 mod packet;
+mod ratelimit;
+mod recursion;
+mod resolver;
+mod rtt;
+mod search;
+mod stats;
+#[cfg(any(test, feature = "testing"))]
+mod testing;
+#[cfg(test)]
+mod tests;
 mod utils;
+mod zone;
 
-use crate::dns::{Packet, QueryType, Question, ResponseCode};
 use crate::packet::BytePacketBuffer;
-use crate::utils::Result as DnsResult;
-use log::debug;
-use std::net::{Ipv4Addr, UdpSocket};
+use crate::recursion::{Recursion, Step};
+use crate::utils::{randomize_case, Result as DnsResult};
+use log::{debug, error};
+use rand::Rng;
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub use crate::blocklist::Blocklist;
+pub use crate::cache::{Cache, CachedRecord};
+pub use crate::challenge::ChallengeStore;
+pub use crate::config::{parse_upstream, ResolverConfig};
+pub use crate::dns::{
+    sort_mx, sort_srv, Packet, QueryClass, QueryType, Question, Record, ResponseCode,
+};
+pub use crate::dns64::{synthesize_aaaa, DNS64_PREFIX};
+pub use crate::doh::lookup_doh;
+pub use crate::error::{DnsError, UpstreamError};
+pub use crate::hooks::{HookAction, Hooks, PostHook, PreHook};
+pub use crate::inflight::InFlight;
+#[cfg(feature = "async")]
+pub use crate::nonblocking::{lookup_async, run_async};
+pub use crate::ratelimit::RateLimiter;
+pub use crate::resolver::{Resolver, UdpResolver};
+pub use crate::rtt::RttTable;
+pub use crate::search::SearchList;
+pub use crate::stats::{Stats, StatsSnapshot};
+#[cfg(feature = "testing")]
+pub use crate::testing::{
+    a_record, aaaa_record, cname_record, mx_record, ns_record, soa_record, srv_record, txt_record,
+    txt_records, MockResolver, MockUpstream,
+};
+pub use crate::zone::Zone;
 
 pub type Result<T> = DnsResult<T>;
 
-fn lookup(qname: &str, qtype: QueryType, server: (Ipv4Addr, u16)) -> Result<Packet> {
-    let socket = UdpSocket::bind(("0.0.0.0", 43210))?;
+const MAX_BIND_ATTEMPTS: usize = 8;
+const MAX_UDP_SIZE: u16 = 4096;
+const MAX_TCP_MESSAGE_LEN: usize = 0xFFFF;
+const HEADER_LEN: usize = 12;
+const EDNS_TCP_KEEPALIVE: u16 = 11;
 
-    let mut packet = Packet::new();
-    packet.header.id = 6666;
-    packet.header.qdcount = 1;
-    packet.header.rd = true;
-    packet
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveOutcome {
+    /// the name exists and has records of the requested type
+    Records(Vec<Record>),
+    /// the name exists but has no records of the requested type
+    NoData,
+    /// the name does not exist
+    NxDomain,
+    /// the resolution failed
+    ServFail,
+}
+
+fn bind_socket(server: IpAddr, config: &ResolverConfig) -> Result<UdpSocket> {
+    let address = match server {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let ports = match config.source_ports {
+        Some(ref ports) => ports,
+        None => return Ok(UdpSocket::bind((address, 0))?),
+    };
+
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        let port = rand::thread_rng().gen_range(ports.clone());
+
+        match UdpSocket::bind((address, port)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(DnsError::Resolution(format!(
+        "no free source port in {:?}",
+        ports
+    )))
+}
+
+fn recv_response(
+    socket: &UdpSocket,
+    server: (IpAddr, u16),
+    request: &Packet,
+    timeout: Duration,
+) -> Result<Option<Packet>> {
+    let deadline = Instant::now() + timeout;
+    let mut malformed = false;
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+
+        if remaining.is_zero() {
+            return unanswered(server, malformed);
+        }
+
+        socket.set_read_timeout(Some(remaining))?;
+
+        let mut data = [0; MAX_UDP_SIZE as usize];
+
+        let (len, src) = match socket.recv_from(&mut data) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return unanswered(server, malformed)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        match accept_response(&data[..len], src, server, request) {
+            Some(response) => return Ok(Some(response)),
+            None => malformed |= is_malformed_from(&data[..len], src, server),
+        }
+    }
+}
+
+// a server that only sent garbage before the deadline is reported as such
+// rather than as having timed out
+fn unanswered(server: (IpAddr, u16), malformed: bool) -> Result<Option<Packet>> {
+    if malformed {
+        return Err(DnsError::Upstream {
+            server: server.into(),
+            kind: UpstreamError::MalformedResponse,
+        });
+    }
+
+    Ok(None)
+}
+
+fn is_malformed_from(data: &[u8], src: SocketAddr, server: (IpAddr, u16)) -> bool {
+    src == SocketAddr::from(server) && Packet::from_bytes(data).is_err()
+}
+
+fn accept_response(
+    data: &[u8],
+    src: SocketAddr,
+    server: (IpAddr, u16),
+    request: &Packet,
+) -> Option<Packet> {
+    if src != SocketAddr::from(server) {
+        debug!("ignoring response from unexpected source {}", src);
+        return None;
+    }
+
+    let mut response = BytePacketBuffer::from_slice(data);
+
+    let response = match Packet::from_buffer(&mut response) {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("ignoring malformed response from {}: {}", src, e);
+            return None;
+        }
+    };
+
+    if response.header.id != request.header.id {
+        debug!(
+            "ignoring response with id {} for request {}",
+            response.header.id, request.header.id
+        );
+        return None;
+    }
+
+    // read_qname lowercases, so the echoed name is read again as it was sent
+    let echoed = echoed_qname(data);
+    let sent = request
         .questions
-        .push(Question::new(qname.to_string(), qtype));
+        .first()
+        .map(|question| question.qname.trim_end_matches('.'));
+
+    if echoed.as_deref() != sent {
+        debug!(
+            "ignoring response echoing {:?} for query name {:?}",
+            echoed, sent
+        );
+        return None;
+    }
+
+    if response.questions.len() != request.questions.len()
+        || !request
+            .questions
+            .iter()
+            .all(|question| response.has_question(&question.qname, question.qtype))
+    {
+        debug!(
+            "ignoring response with mismatched question: {:?}",
+            response.questions
+        );
+        return None;
+    }
+
+    Some(response)
+}
+
+fn upstream_qname(qname: &str, config: &ResolverConfig) -> String {
+    if config.randomize_case {
+        randomize_case(qname)
+    } else {
+        qname.to_string()
+    }
+}
+
+fn echoed_qname(data: &[u8]) -> Option<String> {
+    let mut buffer = BytePacketBuffer::from_slice(data);
+    buffer.step(HEADER_LEN).ok()?;
+
+    let mut qname = String::new();
+    buffer.read_qname_exact(&mut qname).ok()?;
+
+    Some(qname)
+}
+
+fn lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    server: (IpAddr, u16),
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let packet = Packet::query_with_class(&upstream_qname(qname, config), qtype, qclass);
+
+    exchange(packet, qname, server, config)
+}
+
+fn exchange(
+    packet: Packet,
+    qname: &str,
+    server: (IpAddr, u16),
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let wait = pace(server, config)?;
+
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+
+    let result = exchange_udp(packet, qname, server, config);
+    record_upstream_result(server, &result, config);
+
+    result
+}
+
+fn pace(server: (IpAddr, u16), config: &ResolverConfig) -> Result<Duration> {
+    config
+        .pacer
+        .reserve(
+            server.into(),
+            config.upstream_rate,
+            Instant::now(),
+            config.timeout,
+        )
+        .ok_or_else(|| {
+            DnsError::Resolution(format!("{:?} is paced past the query's deadline", server))
+        })
+}
+
+fn record_upstream_result(server: (IpAddr, u16), result: &Result<Packet>, config: &ResolverConfig) {
+    if let Some(error) = classify_upstream_result(result) {
+        debug!("upstream {:?} failed: {}", server, error);
+        config.stats.record_upstream_error(server.into(), error);
+    }
+}
+
+fn classify_upstream_result(result: &Result<Packet>) -> Option<UpstreamError> {
+    match result {
+        Ok(response) => matches!(
+            response.header.rcode,
+            ResponseCode::SERVFAIL | ResponseCode::REFUSED
+        )
+        .then_some(UpstreamError::Rcode),
+        Err(DnsError::Upstream { kind, .. }) => Some(*kind),
+        Err(DnsError::Io(e)) => match e.kind() {
+            ErrorKind::ConnectionRefused => Some(UpstreamError::ConnectionRefused),
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => Some(UpstreamError::Timeout),
+            _ => None,
+        },
+        Err(_) => None,
+    }
+}
+
+fn exchange_udp(
+    mut packet: Packet,
+    qname: &str,
+    server: (IpAddr, u16),
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    // advertised so that upstreams only truncate answers that really are large
+    packet.additions.push(edns_opt());
+
+    // connected, so that an unreachable port is reported as refused
+    let socket = bind_socket(server.0, config)?;
+    socket.connect(server)?;
 
     let mut request = BytePacketBuffer::new();
     packet.write(&mut request)?;
-    socket.send_to(&request.buffer[0..request.position], server)?;
+    let request = request.get_range(0, request.position)?;
+
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            debug!(
+                "lookup of {} timed out, retrying ({}/{})",
+                qname, attempt, config.retries
+            );
+        }
 
-    let mut response = BytePacketBuffer::new();
-    socket.recv_from(&mut response.buffer)?;
+        socket.send(request)?;
 
-    Packet::from_buffer(&mut response)
+        if let Some(response) = recv_response(&socket, server, &packet, config.timeout)? {
+            if response.header.tc {
+                debug!("response for {} truncated, retrying over tcp", qname);
+
+                return exchange_tcp(request, &packet, server, config.timeout);
+            }
+
+            return Ok(response);
+        }
+
+        Stats::increment(&config.stats.upstream_timeouts);
+    }
+
+    Err(DnsError::Upstream {
+        server: server.into(),
+        kind: UpstreamError::Timeout,
+    })
+}
+
+fn exchange_tcp(
+    request: &[u8],
+    packet: &Packet,
+    server: (IpAddr, u16),
+    timeout: Duration,
+) -> Result<Packet> {
+    let address = SocketAddr::from(server);
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    write_message(&mut stream, request)?;
+
+    let response = read_message(&mut stream)?.ok_or_else(|| {
+        DnsError::Resolution(format!(
+            "{} closed the connection without answering",
+            address
+        ))
+    })?;
+
+    accept_response(&response, address, server, packet).ok_or(DnsError::Upstream {
+        server: address,
+        kind: UpstreamError::MalformedResponse,
+    })
+}
+
+// reads one length-prefixed message, or None if the peer closed the
+// connection before starting another
+fn read_message(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 2];
+
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut data = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut data)?;
+
+    Ok(Some(data))
 }
 
-fn recursive_lookup(qname: &str, qtype: QueryType) -> Result<Packet> {
-    let mut ns = "198.41.0.4".parse::<Ipv4Addr>()?;
+fn write_message(stream: &mut impl Write, data: &[u8]) -> Result<()> {
+    let len = u16::try_from(data.len()).map_err(|_| DnsError::PacketTooLarge {
+        len: data.len(),
+        limit: MAX_TCP_MESSAGE_LEN,
+    })?;
+
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&len.to_be_bytes());
+    message.extend_from_slice(data);
+
+    stream.write_all(&message)?;
+
+    Ok(())
+}
+
+pub fn recursive_lookup(
+    qname: &str,
+    qtype: QueryType,
+    resolver: &dyn Resolver,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let mut recursion = Recursion::new(qname, qtype, config);
+    let mut step = recursion.start()?;
 
     loop {
-        debug!("attempting lookup of {:?} {} with ns {}", qtype, qname, ns);
+        let query = match step {
+            Step::Query(query) => query,
+            Step::Done(response) => return Ok(response),
+        };
 
-        let server = (ns.clone(), 53);
-        let response = lookup(qname, qtype, server)?;
+        let started = Instant::now();
+        let result = resolver.query(&query.qname, query.qtype, (query.server, 53));
 
-        if !response.answers.is_empty() && response.header.rcode == ResponseCode::NOERROR {
-            return Ok(response);
+        step = recursion.resume(result, started.elapsed())?;
+    }
+}
+
+fn dns64_prefix(result: &Packet, question: &Question, config: &ResolverConfig) -> Option<Ipv6Addr> {
+    if question.qtype != QueryType::AAAA
+        || question.qclass != QueryClass::IN
+        || result.header.rcode != ResponseCode::NOERROR
+        || result
+            .answers
+            .iter()
+            .any(|answer| matches!(answer, Record::AAAA { .. }))
+    {
+        return None;
+    }
+
+    config.dns64_prefix
+}
+
+fn synthesize_dns64(result: &mut Packet, response: Result<Packet>, prefix: Ipv6Addr) {
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("dns64 lookup for {:?} failed: {}", result.questions, e);
+            return;
         }
+    };
 
-        if response.header.rcode == ResponseCode::NXDOMAIN {
-            return Ok(response);
+    if response.header.rcode != ResponseCode::NOERROR
+        || !response
+            .answers
+            .iter()
+            .any(|answer| matches!(answer, Record::A { .. }))
+    {
+        return;
+    }
+
+    debug!("synthesizing dns64 answers for {:?}", result.questions);
+
+    result.answers = response
+        .answers
+        .into_iter()
+        .map(|answer| match answer {
+            Record::A {
+                domain,
+                address,
+                class,
+                ttl,
+            } => Record::AAAA {
+                domain,
+                address: synthesize_aaaa(prefix, address),
+                class,
+                ttl,
+            },
+            answer => answer,
+        })
+        .collect();
+    result.authorities.clear();
+}
+
+fn forward_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let mut result = Err(DnsError::Resolution("no forwarders configured".to_string()));
+
+    for forwarder in &config.forwarders {
+        debug!("forwarding {:?} {} to {}", qtype, qname, forwarder);
+
+        result = lookup(
+            qname,
+            qtype,
+            qclass,
+            (forwarder.ip(), forwarder.port()),
+            config,
+        );
+
+        if forwarder_answered(*forwarder, &result) {
+            break;
         }
+    }
+
+    result
+}
 
-        if let Some(new_ns) = response.get_resolved_ns(qname) {
-            ns = new_ns;
-            continue;
+// a forwarder that fails or returns SERVFAIL is passed over for the next one
+fn forwarder_answered(forwarder: SocketAddr, result: &Result<Packet>) -> bool {
+    match result {
+        Ok(response) if response.header.rcode != ResponseCode::SERVFAIL => true,
+        Ok(_) => {
+            debug!("forwarder {} returned SERVFAIL", forwarder);
+            false
         }
+        Err(e) => {
+            debug!("forwarder {} failed: {}", forwarder, e);
+            false
+        }
+    }
+}
 
-        let new_ns_name = match response.get_unresolved_ns(qname) {
-            Some(ns_name) => ns_name,
-            _ => return Ok(response),
-        };
+fn upstream_lookup(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    match upstream_route(qclass, config)? {
+        Upstream::Doh(url) => lookup_doh(qname, qtype, url),
+        Upstream::Forward => forward_lookup(qname, qtype, qclass, config),
+        Upstream::Recurse => recursive_lookup(qname, qtype, &UdpResolver::new(config), config),
+    }
+}
 
-        let recursive_response = recursive_lookup(&new_ns_name, QueryType::A)?;
+enum Upstream<'a> {
+    Doh(&'a str),
+    Forward,
+    Recurse,
+}
 
-        if let Some(new_ns) = recursive_response.get_random_a() {
-            ns = new_ns;
-        } else {
-            return Ok(response);
+fn upstream_route(qclass: QueryClass, config: &ResolverConfig) -> Result<Upstream<'_>> {
+    if let Some(url) = &config.doh_url {
+        Ok(Upstream::Doh(url))
+    } else if config.is_forward_only() {
+        Ok(Upstream::Forward)
+    } else if qclass == QueryClass::IN {
+        Ok(Upstream::Recurse)
+    } else {
+        Err(DnsError::Resolution(format!(
+            "class {:?} is only supported when forwarding",
+            qclass
+        )))
+    }
+}
+
+pub fn resolve_records(
+    qname: &str,
+    qtype: QueryType,
+    resolver: &dyn Resolver,
+    config: &ResolverConfig,
+) -> Result<ResolveOutcome> {
+    let response = recursive_lookup(qname, qtype, resolver, config)?;
+
+    let outcome = match response.header.rcode {
+        ResponseCode::NOERROR if response.answers.is_empty() => ResolveOutcome::NoData,
+        ResponseCode::NOERROR => ResolveOutcome::Records(response.answers),
+        ResponseCode::NXDOMAIN => ResolveOutcome::NxDomain,
+        _ => ResolveOutcome::ServFail,
+    };
+
+    Ok(outcome)
+}
+
+pub fn resolve(
+    name: &str,
+    qtype: QueryType,
+    resolver: &dyn Resolver,
+    config: &ResolverConfig,
+) -> Result<Vec<Record>> {
+    match resolve_records(name, qtype, resolver, config)? {
+        ResolveOutcome::Records(records) => Ok(records),
+        ResolveOutcome::NoData => Ok(Vec::new()),
+        ResolveOutcome::NxDomain => Err(DnsError::Resolution(format!("{} does not exist", name))),
+        ResolveOutcome::ServFail => Err(DnsError::Resolution(format!(
+            "server failure resolving {}",
+            name
+        ))),
+    }
+}
+
+pub fn resolve_records_with_search(
+    qname: &str,
+    qtype: QueryType,
+    search: &SearchList,
+    resolver: &dyn Resolver,
+    config: &ResolverConfig,
+) -> Result<ResolveOutcome> {
+    let mut outcome = Ok(ResolveOutcome::NxDomain);
+
+    // a candidate that fails to resolve does not stop the search, only the
+    // last candidate's failure is returned
+    for candidate in search.candidates(qname) {
+        debug!("searching {:?} as {:?}", qname, candidate);
+
+        outcome = resolve_records(&candidate, qtype, resolver, config);
+
+        match outcome {
+            Ok(ResolveOutcome::Records(_)) | Ok(ResolveOutcome::NoData) => break,
+            Ok(_) => {}
+            Err(ref e) => debug!("search candidate {:?} failed: {}", candidate, e),
         }
     }
+
+    outcome
 }
 
-pub fn handle_query(socket: &UdpSocket) -> Result<()> {
-    let mut request = BytePacketBuffer::new();
-    let (_, src) = socket.recv_from(&mut request.buffer)?;
-    let mut request = Packet::from_buffer(&mut request)?;
+#[cfg(unix)]
+pub fn bind_reuseport(address: SocketAddr) -> Result<UdpSocket> {
+    let socket = Socket::new(
+        Domain::for_address(address),
+        Type::DGRAM,
+        Some(Protocol::UDP),
+    )?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&address.into())?;
 
-    let mut packet = Packet::new();
-    packet.header.id = request.header.id;
-    packet.header.rd = true;
-    packet.header.ra = true;
-    packet.header.response = true;
+    Ok(socket.into())
+}
 
-    if let Some(question) = request.questions.pop() {
-        debug!("question: {:?}", question);
+#[cfg(not(unix))]
+pub fn bind_reuseport(_: SocketAddr) -> Result<UdpSocket> {
+    Err(DnsError::Io(std::io::Error::new(
+        ErrorKind::Unsupported,
+        "reuseport is only supported on unix",
+    )))
+}
 
-        if let Ok(result) = recursive_lookup(&question.qname, question.qtype) {
-            packet.questions.push(question.clone());
-            packet.header.rcode = result.header.rcode;
+pub fn recv_query(socket: &UdpSocket) -> Result<(Packet, SocketAddr)> {
+    let mut data = [0; MAX_UDP_SIZE as usize];
+    let (len, src) = socket.recv_from(&mut data)?;
+    let mut request = BytePacketBuffer::from_slice(&data[..len]);
 
-            for answer in result.answers {
-                debug!("answer: {:?}", answer);
-                packet.answers.push(answer);
+    Ok((Packet::from_buffer(&mut request)?, src))
+}
+
+pub fn is_poll_timeout(e: &DnsError) -> bool {
+    matches!(
+        e,
+        DnsError::Io(e)
+            if matches!(
+                e.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+            )
+    )
+}
+
+pub fn serve_udp(
+    socket: &UdpSocket,
+    queries: Sender<(Packet, SocketAddr)>,
+    running: &AtomicBool,
+) -> Result<()> {
+    socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+    while running.load(Ordering::SeqCst) {
+        match recv_query(socket) {
+            Ok(query) => queries.send(query).map_err(|_| {
+                DnsError::Io(std::io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "no workers left to answer queries",
+                ))
+            })?,
+            Err(e) if is_poll_timeout(&e) => {}
+            Err(e) => error!("an error occurred: {}", e),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn answer_query(
+    socket: &UdpSocket,
+    request: Packet,
+    src: SocketAddr,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<()> {
+    let limit = reply_limit(&request);
+    let response = build_reply(
+        request,
+        src.ip(),
+        Transport::Udp(limit),
+        cache,
+        limiter,
+        config,
+    )?;
+
+    socket.send_to(&response, src)?;
+    revalidate(cache, config);
+
+    Ok(())
+}
+
+// run once the reply is sent, so that the client answered from a stale entry
+// does not wait for its refresh
+fn revalidate(cache: &Cache, config: &ResolverConfig) {
+    for (qname, qtype) in cache.take_refreshes() {
+        debug!("revalidating {:?} {}", qtype, qname);
+
+        let question = Question::new(qname, qtype);
+        let result = upstream_lookup(&question.qname, qtype, QueryClass::IN, config);
+        refresh(&question, result, cache, config);
+    }
+}
+
+// a failed refresh leaves the stale entry in place for the next query
+fn refresh(question: &Question, result: Result<Packet>, cache: &Cache, config: &ResolverConfig) {
+    match result {
+        Ok(response) if response.header.rcode != ResponseCode::SERVFAIL => {
+            complete_answer(Packet::new(), question, Ok(response), cache, config);
+        }
+        Ok(_) => debug!("refresh of {:?} failed", question),
+        Err(e) => debug!("refresh of {:?} failed: {}", question, e),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    /// a datagram, limited to this many bytes
+    Udp(usize),
+    /// a TCP connection, closed after it has been idle this long
+    Tcp(Duration),
+}
+
+fn build_reply(
+    request: Packet,
+    src: IpAddr,
+    transport: Transport,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<Vec<u8>> {
+    Stats::increment(&config.stats.queries_total);
+
+    let packet = if limiter.allow(src, Instant::now()) {
+        build_response(request, transport, cache, config)
+    } else {
+        build_refusal(&request)
+    };
+
+    let limit = match transport {
+        Transport::Udp(limit) => limit,
+        Transport::Tcp(_) => MAX_TCP_MESSAGE_LEN,
+    };
+    let response = encode_reply(packet, limit)?;
+    config.stats.record_response_size(response.len());
+
+    Ok(response)
+}
+
+fn reply_limit(request: &Packet) -> usize {
+    request
+        .edns_udp_size()
+        .map_or(512, |size| size.clamp(512, MAX_UDP_SIZE)) as usize
+}
+
+fn encode_reply(mut packet: Packet, limit: usize) -> Result<Vec<u8>> {
+    let mut response = vec![0; limit];
+
+    // Whole RRsets are dropped from the additional and then the authority
+    // section until the response fits. Only a response whose answers do not
+    // fit on their own is sent as just the question with TC set, so that the
+    // client retries over TCP.
+    let len = loop {
+        match packet.write_into(&mut response) {
+            Ok(len) => break len,
+            Err(DnsError::PacketTooLarge { limit, .. }) if packet.drop_last_rrset() => {
+                debug!("dropped an rrset to fit {} bytes", limit);
             }
+            Err(DnsError::PacketTooLarge { limit, .. }) => {
+                debug!("truncating response to fit {} bytes", limit);
 
-            for authority in result.authorities {
-                debug!("authority: {:?}", authority);
-                packet.authorities.push(authority);
+                packet.truncate();
+                break packet.write_into(&mut response)?;
             }
+            Err(e) => return Err(e),
+        }
+    };
+    response.truncate(len);
+
+    Ok(response)
+}
 
-            for addition in result.additions {
-                debug!("addition: {:?}", addition);
-                packet.additions.push(addition);
+pub fn handle_query(
+    socket: &UdpSocket,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<()> {
+    let (request, src) = recv_query(socket)?;
+
+    answer_query(socket, request, src, cache, limiter, config)
+}
+
+pub fn handle_query_tcp(
+    stream: &mut TcpStream,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<()> {
+    let src = stream.peer_addr()?.ip();
+    let transport = Transport::Tcp(config.tcp_keepalive);
+    stream.set_read_timeout(Some(config.tcp_keepalive))?;
+
+    // queries are answered in order until the client closes the connection or
+    // leaves it idle past the stream's read timeout (RFC 7766)
+    loop {
+        let data = match read_message(stream) {
+            Ok(Some(data)) => data,
+            Ok(None) => return Ok(()),
+            Err(e) if is_poll_timeout(&e) => {
+                debug!("closing idle tcp connection from {}", src);
+                return Ok(());
             }
-        } else {
-            packet.header.rcode = ResponseCode::SERVFAIL;
+            Err(e) => return Err(e),
+        };
+
+        let mut request = BytePacketBuffer::from_slice(&data);
+        let request = Packet::from_buffer(&mut request)?;
+
+        let response = build_reply(request, src, transport, cache, limiter, config)?;
+
+        write_message(stream, &response)?;
+        revalidate(cache, config);
+    }
+}
+
+fn build_refusal(request: &Packet) -> Packet {
+    let mut packet = Packet::response_to(request);
+    packet.header.rcode = ResponseCode::REFUSED;
+
+    packet
+}
+
+fn build_response(
+    request: Packet,
+    transport: Transport,
+    cache: &Cache,
+    config: &ResolverConfig,
+) -> Packet {
+    let edns = request.edns_udp_size().is_some();
+
+    finish_response(
+        answer_question(request, cache, config),
+        edns,
+        transport,
+        config,
+    )
+}
+
+fn finish_response(
+    mut packet: Packet,
+    edns: bool,
+    transport: Transport,
+    config: &ResolverConfig,
+) -> Packet {
+    // a client without EDNS cannot be sent an extended rcode
+    if !edns && u16::from(packet.header.rcode) > 0x0F {
+        packet.header.rcode = ResponseCode::SERVFAIL;
+    }
+
+    if packet
+        .questions
+        .iter()
+        .any(|question| question.qtype == QueryType::MX)
+    {
+        sort_mx(&mut packet.answers);
+    }
+
+    config.hooks.after(&mut packet);
+
+    match packet.header.rcode {
+        ResponseCode::NXDOMAIN => Stats::increment(&config.stats.nxdomain),
+        ResponseCode::SERVFAIL => Stats::increment(&config.stats.servfail),
+        _ => {}
+    }
+
+    if edns {
+        let mut opt = edns_opt();
+
+        // the idle timeout is only ever advertised over TCP (RFC 7828)
+        if let (Transport::Tcp(idle), Record::OPT { data, .. }) = (transport, &mut opt) {
+            data.extend(keepalive_option(idle));
         }
-    } else {
+
+        packet.additions.push(opt);
+    }
+
+    if config.logs_query(packet.header.id) {
+        debug!("response:\n{}", packet);
+    }
+
+    packet
+}
+
+fn edns_opt() -> Record {
+    Record::OPT {
+        domain: String::new(),
+        udp_size: MAX_UDP_SIZE,
+        extended_rcode: 0,
+        version: 0,
+        flags: 0,
+        data: Vec::new(),
+    }
+}
+
+// the timeout is carried in units of 100 milliseconds
+fn keepalive_option(idle: Duration) -> Vec<u8> {
+    let timeout = u16::try_from(idle.as_millis() / 100).unwrap_or(u16::MAX);
+
+    let mut option = Vec::with_capacity(6);
+    option.extend(EDNS_TCP_KEEPALIVE.to_be_bytes());
+    option.extend(2u16.to_be_bytes());
+    option.extend(timeout.to_be_bytes());
+
+    option
+}
+
+enum Answer {
+    /// answered without going upstream
+    Ready(Packet),
+    /// the response so far and the question to look up upstream
+    Upstream(Packet, Question),
+}
+
+fn answer_question(request: Packet, cache: &Cache, config: &ResolverConfig) -> Packet {
+    let (packet, question) = match answer_locally(&request, cache, config) {
+        Answer::Ready(packet) => return packet,
+        Answer::Upstream(packet, question) => (packet, question),
+    };
+
+    let key = (question.qname.clone(), question.qtype, question.qclass);
+
+    // identical questions arriving while a lookup is in flight share its result
+    let mut result = config.inflight.join(key, || {
+        upstream_lookup(&question.qname, question.qtype, question.qclass, config)
+    });
+
+    if let Ok(ref mut result) = result {
+        if let Some(prefix) = dns64_prefix(result, &question, config) {
+            let response = upstream_lookup(&question.qname, QueryType::A, QueryClass::IN, config);
+            synthesize_dns64(result, response, prefix);
+        }
+    }
+
+    complete_answer(packet, &question, result, cache, config)
+}
+
+fn answer_locally(request: &Packet, cache: &Cache, config: &ResolverConfig) -> Answer {
+    let mut packet = Packet::response_to(request);
+    packet.header.ra = true;
+
+    if request.header.opcode != 0 {
+        debug!("unsupported opcode: {}", request.header.opcode);
+
+        packet.header.rcode = ResponseCode::NOTIMP;
+        return Answer::Ready(packet);
+    }
+
+    // Only single-question queries are answered. Anything else is rejected
+    // with FORMERR rather than silently dropping the extra questions.
+    if request.questions.len() != 1 {
+        debug!("unsupported question count: {}", request.questions.len());
+
         packet.header.rcode = ResponseCode::FORMERR;
+        return Answer::Ready(packet);
     }
 
-    let mut response = BytePacketBuffer::new();
-    packet.write(&mut response)?;
+    let mut question = request.questions[0].clone();
+    if config.logs_query(request.header.id) {
+        debug!("question: {:?}", question);
+    }
+
+    if let Some(rcode) = config.hooks.before(&mut question) {
+        debug!("answered by hook: {:?}", question);
 
-    let len = response.position;
-    let response = response.get_range(0, len)?;
+        packet.header.rcode = rcode;
+        return Answer::Ready(packet);
+    }
 
-    socket.send_to(response, src)?;
+    if question.qclass == QueryClass::IN {
+        if let Some(records) = config.challenges.answer(&question.qname, question.qtype) {
+            debug!("acme challenge hit: {:?}", question);
 
-    Ok(())
+            packet.header.aa = true;
+            packet.answers.extend(records);
+            restore_qname(&mut packet, &question);
+            return Answer::Ready(packet);
+        }
+
+        let local = zone::answer(&config.zones, &question.qname, question.qtype).or_else(|| {
+            (config.synthesize_ptr && question.qtype == QueryType::PTR)
+                .then(|| zone::answer_ptr(&config.zones, &question.qname))
+                .flatten()
+        });
+
+        if let Some(records) = local {
+            debug!("local zone hit: {:?}", question);
+
+            packet.header.aa = true;
+            packet.answers.extend(records.into_iter().map(|mut record| {
+                record.set_ttl(record.ttl().max(config.zone_min_ttl));
+                record
+            }));
+            restore_qname(&mut packet, &question);
+            return Answer::Ready(packet);
+        }
+    }
+
+    if config.blocklist.is_blocked(&question.qname) {
+        debug!("blocked: {:?}", question);
+
+        packet.header.rcode = ResponseCode::NXDOMAIN;
+        return Answer::Ready(packet);
+    }
+
+    // only the Internet class is cached, as cache keys do not include the class
+    let cacheable = question.qclass == QueryClass::IN;
+    // ANY is answered with every type cached for the name, and is never
+    // cached itself so that it cannot shadow the per-type entries
+    let cached = cacheable
+        .then(|| match question.qtype {
+            QueryType::ANY => cache.get_any(&question.qname, Instant::now()),
+            qtype => cache.get(&question.qname, qtype, Instant::now()),
+        })
+        .flatten();
+
+    let nxdomain = cacheable
+        .then(|| cache.get_nxdomain(&question.qname, Instant::now()))
+        .flatten();
+
+    let revalidating = (cached.is_none()
+        && cacheable
+        && question.qtype != QueryType::ANY
+        && !config.stale_while_revalidate.is_zero())
+    .then(|| {
+        cache.get_revalidating(
+            &question.qname,
+            question.qtype,
+            Instant::now(),
+            config.stale_while_revalidate,
+        )
+    })
+    .flatten();
+
+    let ipv4_only = cacheable
+        && learns_ipv4_only(&question, config)
+        && cache.is_ipv4_only(&question.qname, Instant::now());
+
+    if let Some(soa) = nxdomain {
+        debug!("negative cache hit: {:?}", question);
+        Stats::increment(&config.stats.cache_hits);
+
+        packet.header.rcode = ResponseCode::NXDOMAIN;
+        packet.authorities.push(soa);
+    } else if let Some(answers) = cached {
+        debug!("cache hit: {:?}", question);
+        Stats::increment(&config.stats.cache_hits);
+
+        packet.answers.extend(answers);
+    } else if let Some(answers) = revalidating {
+        debug!("serving stale while revalidating: {:?}", question);
+        Stats::increment(&config.stats.served_stale);
+
+        cache.queue_refresh(&question.qname, question.qtype);
+        packet.answers.extend(answers);
+    } else if ipv4_only {
+        debug!("known ipv4-only name, answering nodata: {:?}", question);
+        Stats::increment(&config.stats.cache_hits);
+    } else {
+        Stats::increment(&config.stats.cache_misses);
+
+        return Answer::Upstream(packet, question);
+    }
+
+    restore_qname(&mut packet, &question);
+    Answer::Ready(packet)
+}
+
+// answers for a question a hook rewrote are given under the name asked for,
+// as clients do not accept records owned by some other name
+fn restore_qname(packet: &mut Packet, question: &Question) {
+    let asked = match packet.questions.first() {
+        Some(asked) if !asked.qname.eq_ignore_ascii_case(&question.qname) => asked.qname.clone(),
+        _ => return,
+    };
+
+    for record in &mut packet.answers {
+        if record.domain().eq_ignore_ascii_case(&question.qname) {
+            record.set_domain(&asked);
+        }
+    }
+}
+
+// DNS64 needs the AAAA lookup to come back empty before it synthesizes, so
+// the fast NODATA is never given while it is enabled
+fn learns_ipv4_only(question: &Question, config: &ResolverConfig) -> bool {
+    config.learn_ipv4_only && config.dns64_prefix.is_none() && question.qtype == QueryType::AAAA
+}
+
+fn complete_answer(
+    mut packet: Packet,
+    question: &Question,
+    result: Result<Packet>,
+    cache: &Cache,
+    config: &ResolverConfig,
+) -> Packet {
+    let cacheable = question.qclass == QueryClass::IN;
+
+    match result {
+        Ok(mut result) if result.header.rcode != ResponseCode::SERVFAIL => {
+            // a truncated answer may be missing records, so it is passed on
+            // but never cached
+            let cacheable = cacheable && !result.header.tc;
+
+            for record in result
+                .answers
+                .iter_mut()
+                .chain(result.authorities.iter_mut())
+                .chain(result.additions.iter_mut())
+            {
+                record.set_ttl(config.clamp_ttl(record.ttl()));
+            }
+
+            match result.header.rcode {
+                ResponseCode::NOERROR if cacheable && question.qtype != QueryType::ANY => cache
+                    .insert(
+                        &question.qname,
+                        question.qtype,
+                        result.answers.clone(),
+                        Instant::now(),
+                    ),
+                ResponseCode::NXDOMAIN if cacheable => {
+                    if let Some(soa) = result.get_soa() {
+                        cache.insert_nxdomain(&question.qname, soa.clone(), Instant::now());
+                    }
+                }
+                _ => {}
+            }
+
+            if cacheable
+                && result.header.rcode == ResponseCode::NOERROR
+                && learns_ipv4_only(question, config)
+            {
+                cache.record_aaaa_result(
+                    &question.qname,
+                    result.answers.is_empty(),
+                    Instant::now(),
+                );
+            }
+
+            packet.header.rcode = result.header.rcode;
+
+            packet.answers.extend(result.answers);
+            packet.authorities.extend(result.authorities);
+            packet.additions.extend(
+                result
+                    .additions
+                    .into_iter()
+                    .filter(|addition| !matches!(addition, Record::OPT { .. })),
+            );
+        }
+        _ => match cacheable
+            .then(|| cache.get_stale(&question.qname, question.qtype, Instant::now()))
+            .flatten()
+        {
+            Some(answers) => {
+                debug!("upstream failed, serving stale: {:?}", question);
+                Stats::increment(&config.stats.served_stale);
+
+                packet.answers.extend(answers);
+            }
+            None => packet.header.rcode = ResponseCode::SERVFAIL,
+        },
+    }
+
+    restore_qname(&mut packet, question);
+    packet
 }
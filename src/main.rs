@@ -1,18 +1,513 @@
-use dns_server_example::{handle_query, Result};
+#[cfg(feature = "async")]
+use dns_server_example::run_async;
+use dns_server_example::{
+    answer_query, bind_reuseport, handle_query, handle_query_tcp, is_poll_timeout, parse_upstream,
+    serve_udp, Blocklist, Cache, Packet, RateLimiter, ResolverConfig, Stats, Zone, DNS64_PREFIX,
+    SHUTDOWN_POLL_INTERVAL,
+};
 use log::{debug, error};
-use std::net::UdpSocket;
+use std::env;
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const DEFAULT_WORKERS: usize = 4;
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+struct Args {
+    config: ResolverConfig,
+    workers: usize,
+    rate_limit: Option<u32>,
+    metrics: Option<SocketAddr>,
+    reuseport: bool,
+    nonblocking: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut config = ResolverConfig::new();
+    let mut workers = DEFAULT_WORKERS;
+    let mut blocklist = None;
+    let mut rate_limit = None;
+    let mut metrics = None;
+    let mut reuseport = false;
+    let mut nonblocking = false;
+    let mut root_hints = Vec::new();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--forward" => {
+                let upstream = args.next().ok_or("--forward requires an address")?;
+                config.forwarders.push(parse_upstream(&upstream)?);
+            }
+            "--root-hint" => {
+                let hint = args.next().ok_or("--root-hint requires an address")?;
+                root_hints.push(hint.parse()?);
+            }
+            "--doh" => {
+                config.doh_url = Some(args.next().ok_or("--doh requires a url")?);
+            }
+            "--zone" => {
+                let path = args.next().ok_or("--zone requires a path")?;
+                config.zones.push(Zone::load(path)?);
+            }
+            "--zone-min-ttl" => {
+                config.zone_min_ttl = args
+                    .next()
+                    .ok_or("--zone-min-ttl requires seconds")?
+                    .parse()?;
+            }
+            "--cache-shards" => {
+                let shards = args
+                    .next()
+                    .ok_or("--cache-shards requires a count")?
+                    .parse()?;
+
+                if shards == 0 {
+                    return Err("--cache-shards must be at least 1".into());
+                }
+
+                config.cache_shards = shards;
+            }
+            "--cache-max-entries" => {
+                let max = args
+                    .next()
+                    .ok_or("--cache-max-entries requires a count")?
+                    .parse()?;
+
+                if max == 0 {
+                    return Err("--cache-max-entries must be at least 1".into());
+                }
+
+                config.cache_max_entries = max;
+            }
+            "--cache-sweep-interval" => {
+                let secs = args
+                    .next()
+                    .ok_or("--cache-sweep-interval requires seconds")?
+                    .parse()?;
+
+                if secs == 0 {
+                    return Err("--cache-sweep-interval must be at least 1".into());
+                }
+
+                config.cache_sweep_interval = Duration::from_secs(secs);
+            }
+            "--max-stale" => {
+                let secs = args.next().ok_or("--max-stale requires seconds")?.parse()?;
+
+                config.max_stale = Duration::from_secs(secs);
+            }
+            "--stale-while-revalidate" => {
+                let secs = args
+                    .next()
+                    .ok_or("--stale-while-revalidate requires seconds")?
+                    .parse()?;
+
+                config.stale_while_revalidate = Duration::from_secs(secs);
+            }
+            "--tcp-keepalive" => {
+                let secs = args
+                    .next()
+                    .ok_or("--tcp-keepalive requires seconds")?
+                    .parse()?;
+
+                if secs == 0 {
+                    return Err("--tcp-keepalive must be at least 1".into());
+                }
+
+                config.tcp_keepalive = Duration::from_secs(secs);
+            }
+            "--synthesize-ptr" => config.synthesize_ptr = true,
+            "--blocklist" => {
+                blocklist = Some(args.next().ok_or("--blocklist requires a path")?);
+            }
+            "--block-subdomains" => config.blocklist.wildcard = true,
+            "--workers" => {
+                workers = args.next().ok_or("--workers requires a count")?.parse()?;
+
+                if workers == 0 {
+                    return Err("--workers must be at least 1".into());
+                }
+            }
+            "--rate-limit" => {
+                let rate = args.next().ok_or("--rate-limit requires a rate")?.parse()?;
+
+                if rate == 0 {
+                    return Err("--rate-limit must be at least 1".into());
+                }
+
+                rate_limit = Some(rate);
+            }
+            "--upstream-rate" => {
+                let rate = args
+                    .next()
+                    .ok_or("--upstream-rate requires a rate")?
+                    .parse()?;
+
+                if rate == 0 {
+                    return Err("--upstream-rate must be at least 1".into());
+                }
+
+                config.upstream_rate = rate;
+            }
+            "--min-ttl" => {
+                config.min_ttl = args.next().ok_or("--min-ttl requires seconds")?.parse()?;
+            }
+            "--max-ttl" => {
+                config.max_ttl = args.next().ok_or("--max-ttl requires seconds")?.parse()?;
+            }
+            "--dns64" => config.dns64_prefix = Some(DNS64_PREFIX),
+            "--dns64-prefix" => {
+                let prefix = args.next().ok_or("--dns64-prefix requires a prefix")?;
+                config.dns64_prefix = Some(parse_dns64_prefix(&prefix)?);
+            }
+            "--reuseport" => reuseport = true,
+            "--async" if cfg!(feature = "async") => nonblocking = true,
+            "--async" => return Err("--async requires the async feature".into()),
+            "--no-randomize-case" => config.randomize_case = false,
+            "--learn-ipv4-only" => config.learn_ipv4_only = true,
+            "--log-sample-rate" => {
+                let rate: f64 = args
+                    .next()
+                    .ok_or("--log-sample-rate requires a fraction")?
+                    .parse()?;
+
+                if !(0.0..=1.0).contains(&rate) {
+                    return Err("--log-sample-rate must be between 0 and 1".into());
+                }
+
+                config.log_sample_rate = rate;
+            }
+            "--stats-rollover" => {
+                let secs = args
+                    .next()
+                    .ok_or("--stats-rollover requires seconds")?
+                    .parse()?;
+
+                if secs == 0 {
+                    return Err("--stats-rollover must be at least 1".into());
+                }
+
+                config.stats_rollover = Some(Duration::from_secs(secs));
+            }
+            "--metrics" => {
+                metrics = Some(
+                    args.next()
+                        .ok_or("--metrics requires an address")?
+                        .parse()?,
+                );
+            }
+            _ => return Err(format!("unknown argument: {}", arg).into()),
+        }
+    }
+
+    if !root_hints.is_empty() {
+        config.root_hints = root_hints;
+    }
+
+    if config.min_ttl > config.max_ttl {
+        return Err("--min-ttl must not exceed --max-ttl".into());
+    }
+
+    if nonblocking && reuseport {
+        return Err("--async cannot be combined with --reuseport".into());
+    }
+
+    if let Some(path) = blocklist {
+        config.blocklist = Blocklist::load(path, config.blocklist.wildcard)?;
+    }
+
+    Ok(Args {
+        config,
+        workers,
+        rate_limit,
+        metrics,
+        reuseport,
+        nonblocking,
+    })
+}
+
+fn parse_dns64_prefix(prefix: &str) -> Result<Ipv6Addr> {
+    let (address, len) = prefix.split_once('/').unwrap_or((prefix, "96"));
+
+    if len != "96" {
+        return Err(format!("unsupported dns64 prefix length: /{}", len).into());
+    }
+
+    Ok(address.parse()?)
+}
+
+fn serve_metrics(listener: TcpListener, stats: Arc<Stats>) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("an error occurred: {}", e);
+                continue;
+            }
+        };
+
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request);
+
+        let body = stats.to_prometheus();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            error!("an error occurred: {}", e);
+        }
+    }
+}
+
+fn serve_tcp(
+    listener: TcpListener,
+    cache: Arc<Cache>,
+    limiter: Arc<RateLimiter>,
+    config: Arc<ResolverConfig>,
+) {
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("an error occurred: {}", e);
+                continue;
+            }
+        };
+
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            debug!(
+                "dropping tcp connection, {} already open",
+                MAX_TCP_CONNECTIONS
+            );
+            continue;
+        }
+
+        let cache = Arc::clone(&cache);
+        let limiter = Arc::clone(&limiter);
+        let config = Arc::clone(&config);
+        let connections = Arc::clone(&connections);
+
+        thread::spawn(move || {
+            if let Err(e) = answer_tcp(stream, &cache, &limiter, &config) {
+                error!("an error occurred: {}", e);
+            }
+
+            connections.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn answer_tcp(
+    mut stream: TcpStream,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<()> {
+    // a client that stops reading must not hold on to its thread; one that
+    // stops sending is closed after the configured keepalive
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+
+    handle_query_tcp(&mut stream, cache, limiter, config)?;
+
+    Ok(())
+}
+
+fn spawn_worker(
+    socket: UdpSocket,
+    queries: Arc<Mutex<Receiver<(Packet, SocketAddr)>>>,
+    cache: Arc<Cache>,
+    limiter: Arc<RateLimiter>,
+    config: Arc<ResolverConfig>,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        let query = match queries.lock() {
+            Ok(queries) => queries.recv(),
+            Err(_) => return,
+        };
+
+        let (request, src) = match query {
+            Ok(query) => query,
+            Err(_) => return,
+        };
+
+        if let Err(e) = answer_query(&socket, request, src, &cache, &limiter, &config) {
+            error!("an error occurred: {}", e);
+        }
+    })
+}
+
+fn spawn_reuseport_worker(
+    socket: UdpSocket,
+    cache: Arc<Cache>,
+    limiter: Arc<RateLimiter>,
+    config: Arc<ResolverConfig>,
+    running: Arc<AtomicBool>,
+) -> Result<JoinHandle<()>> {
+    socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))?;
+
+    Ok(thread::spawn(move || {
+        while running.load(Ordering::SeqCst) {
+            match handle_query(&socket, &cache, &limiter, &config) {
+                Ok(()) => {}
+                Err(e) if is_poll_timeout(&e) => {}
+                Err(e) => error!("an error occurred: {}", e),
+            }
+        }
+    }))
+}
+
+#[cfg(feature = "async")]
+fn serve_async(
+    server: SocketAddr,
+    cache: Arc<Cache>,
+    limiter: Arc<RateLimiter>,
+    config: Arc<ResolverConfig>,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(run_async(server, cache, limiter, config, running))?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "async"))]
+fn serve_async(
+    _: SocketAddr,
+    _: Arc<Cache>,
+    _: Arc<RateLimiter>,
+    _: Arc<ResolverConfig>,
+    _: Arc<AtomicBool>,
+) -> Result<()> {
+    Err("--async requires the async feature".into())
+}
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let server = ("0.0.0.0", 2053);
-    let socket = UdpSocket::bind(server)?;
+    let args = parse_args()?;
 
-    debug!("DNS cache server started at {:?}", server);
+    let running = Arc::new(AtomicBool::new(true));
+    let handler_running = Arc::clone(&running);
 
-    loop {
-        if let Err(e) = handle_query(&socket) {
-            error!("an error occurred: {}", e);
+    ctrlc::set_handler(move || handler_running.store(false, Ordering::SeqCst))?;
+    let config = Arc::new(args.config);
+
+    let server = SocketAddr::from(([0, 0, 0, 0], 2053));
+    let listener = TcpListener::bind(server)?;
+    let cache = Arc::new(
+        Cache::with_shards(config.cache_shards)
+            .with_max_entries(config.cache_max_entries)
+            .with_max_stale(config.max_stale),
+    );
+    let limiter = Arc::new(
+        args.rate_limit
+            .map_or_else(RateLimiter::unlimited, RateLimiter::new),
+    );
+
+    debug!(
+        "DNS cache server started at {} with {} workers",
+        server, args.workers
+    );
+
+    if let Some(address) = args.metrics {
+        let listener = TcpListener::bind(address)?;
+        let stats = Arc::clone(&config.stats);
+
+        debug!("metrics served at {}", address);
+
+        thread::spawn(move || serve_metrics(listener, stats));
+    }
+
+    {
+        let cache = Arc::clone(&cache);
+        let limiter = Arc::clone(&limiter);
+        let config = Arc::clone(&config);
+
+        thread::spawn(move || serve_tcp(listener, cache, limiter, config));
+    }
+
+    if let Some(interval) = config.stats_rollover {
+        let stats = Arc::clone(&config.stats);
+        let running = Arc::clone(&running);
+
+        thread::spawn(move || stats.run_rollover(interval, &running));
+    }
+
+    {
+        let cache = Arc::clone(&cache);
+        let interval = config.cache_sweep_interval;
+        let running = Arc::clone(&running);
+
+        thread::spawn(move || cache.run_sweeper(interval, &running));
+    }
+
+    let mut workers = Vec::with_capacity(args.workers);
+
+    if args.nonblocking {
+        debug!("answering udp queries on an async runtime");
+
+        serve_async(
+            server,
+            Arc::clone(&cache),
+            Arc::clone(&limiter),
+            Arc::clone(&config),
+            Arc::clone(&running),
+        )?;
+    } else if args.reuseport {
+        // one socket per worker lets the kernel spread datagrams across them
+        for _ in 0..args.workers {
+            workers.push(spawn_reuseport_worker(
+                bind_reuseport(server)?,
+                Arc::clone(&cache),
+                Arc::clone(&limiter),
+                Arc::clone(&config),
+                Arc::clone(&running),
+            )?);
+        }
+    } else {
+        let socket = UdpSocket::bind(server)?;
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..args.workers {
+            workers.push(spawn_worker(
+                socket.try_clone()?,
+                Arc::clone(&receiver),
+                Arc::clone(&cache),
+                Arc::clone(&limiter),
+                Arc::clone(&config),
+            ));
         }
+
+        serve_udp(&socket, sender, &running)?;
+    }
+
+    debug!("shutting down, waiting for in-flight queries");
+
+    for worker in workers {
+        let _ = worker.join();
     }
+
+    println!("DNS cache server stopped");
+
+    Ok(())
 }
@@ -0,0 +1,419 @@
+use crate::error::DnsError;
+use crate::packet::BytePacketBuffer;
+use crate::recursion::{Recursion, Step};
+use crate::stats::Stats;
+use crate::utils::Result;
+use crate::{
+    accept_response, answer_locally, bind_socket, build_refusal, complete_answer, dns64_prefix,
+    edns_opt, encode_reply, exchange_tcp, finish_response, forwarder_answered, is_malformed_from,
+    lookup_doh, pace, record_upstream_result, refresh, reply_limit, synthesize_dns64, unanswered,
+    upstream_qname, upstream_route, Answer, Cache, Packet, QueryClass, QueryType, Question,
+    RateLimiter, ResolverConfig, Transport, Upstream, UpstreamError, MAX_UDP_SIZE,
+    SHUTDOWN_POLL_INTERVAL,
+};
+use log::{debug, error};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time;
+
+pub async fn lookup_async(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    server: (IpAddr, u16),
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let wait = pace(server, config)?;
+
+    if !wait.is_zero() {
+        time::sleep(wait).await;
+    }
+
+    let result = exchange_async(qname, qtype, qclass, server, config).await;
+    record_upstream_result(server, &result, config);
+
+    result
+}
+
+async fn exchange_async(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    server: (IpAddr, u16),
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let socket = bind_socket(server.0, config)?;
+    socket.connect(server)?;
+    socket.set_nonblocking(true)?;
+    let socket = UdpSocket::from_std(socket)?;
+
+    let mut packet = Packet::query_with_class(&upstream_qname(qname, config), qtype, qclass);
+    packet.additions.push(edns_opt());
+
+    let mut request = BytePacketBuffer::new();
+    packet.write(&mut request)?;
+    let request = request.get_range(0, request.position)?;
+    let mut malformed = false;
+
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            debug!(
+                "lookup of {} timed out, retrying ({}/{})",
+                qname, attempt, config.retries
+            );
+        }
+
+        socket.send(request).await?;
+
+        let received = recv_response(&socket, server, &packet, &mut malformed);
+
+        if let Ok(response) = time::timeout(config.timeout, received).await {
+            let response = response?;
+
+            if response.header.tc {
+                debug!("response for {} truncated, retrying over tcp", qname);
+
+                return exchange_tcp_async(request.to_vec(), packet, server, config.timeout).await;
+            }
+
+            return Ok(response);
+        }
+
+        unanswered(server, malformed)?;
+        Stats::increment(&config.stats.upstream_timeouts);
+    }
+
+    Err(DnsError::Upstream {
+        server: server.into(),
+        kind: UpstreamError::Timeout,
+    })
+}
+
+async fn recv_response(
+    socket: &UdpSocket,
+    server: (IpAddr, u16),
+    request: &Packet,
+    malformed: &mut bool,
+) -> Result<Packet> {
+    let mut data = [0; MAX_UDP_SIZE as usize];
+
+    loop {
+        let (len, src) = socket.recv_from(&mut data).await?;
+
+        match accept_response(&data[..len], src, server, request) {
+            Some(response) => return Ok(response),
+            None => *malformed |= is_malformed_from(&data[..len], src, server),
+        }
+    }
+}
+
+// the fallback is rare enough that the blocking exchange is simply kept off
+// the runtime, as DoH is
+async fn exchange_tcp_async(
+    request: Vec<u8>,
+    packet: Packet,
+    server: (IpAddr, u16),
+    timeout: Duration,
+) -> Result<Packet> {
+    tokio::task::spawn_blocking(move || exchange_tcp(&request, &packet, server, timeout))
+        .await
+        .map_err(|e| DnsError::Resolution(format!("tcp task failed: {}", e)))?
+}
+
+async fn recursive_lookup_async(
+    qname: &str,
+    qtype: QueryType,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let mut recursion = Recursion::new(qname, qtype, config);
+    let mut step = recursion.start()?;
+
+    loop {
+        let query = match step {
+            Step::Query(query) => query,
+            Step::Done(response) => return Ok(response),
+        };
+
+        let server = (query.server, 53);
+        let started = Instant::now();
+        let result = lookup_async(&query.qname, query.qtype, QueryClass::IN, server, config).await;
+
+        step = recursion.resume(result, started.elapsed())?;
+    }
+}
+
+async fn forward_lookup_async(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    let mut result = Err(DnsError::Resolution("no forwarders configured".to_string()));
+
+    for forwarder in &config.forwarders {
+        debug!("forwarding {:?} {} to {}", qtype, qname, forwarder);
+
+        let server = (forwarder.ip(), forwarder.port());
+        result = lookup_async(qname, qtype, qclass, server, config).await;
+
+        if forwarder_answered(*forwarder, &result) {
+            break;
+        }
+    }
+
+    result
+}
+
+async fn upstream_lookup_async(
+    qname: &str,
+    qtype: QueryType,
+    qclass: QueryClass,
+    config: &ResolverConfig,
+) -> Result<Packet> {
+    match upstream_route(qclass, config)? {
+        Upstream::Doh(url) => {
+            // the http client only blocks, so DoH alone is kept off the runtime
+            let qname = qname.to_string();
+            let url = url.to_string();
+
+            tokio::task::spawn_blocking(move || lookup_doh(&qname, qtype, &url))
+                .await
+                .map_err(|e| DnsError::Resolution(format!("doh task failed: {}", e)))?
+        }
+        Upstream::Forward => forward_lookup_async(qname, qtype, qclass, config).await,
+        Upstream::Recurse => recursive_lookup_async(qname, qtype, config).await,
+    }
+}
+
+async fn answer_question_async(request: Packet, cache: &Cache, config: &ResolverConfig) -> Packet {
+    let (packet, question) = match answer_locally(&request, cache, config) {
+        Answer::Ready(packet) => return packet,
+        Answer::Upstream(packet, question) => (packet, question),
+    };
+
+    // identical questions are not joined here, as waiting on another
+    // lookup's result would block the runtime
+    let mut result =
+        upstream_lookup_async(&question.qname, question.qtype, question.qclass, config).await;
+
+    if let Ok(ref mut result) = result {
+        if let Some(prefix) = dns64_prefix(result, &question, config) {
+            let response =
+                upstream_lookup_async(&question.qname, QueryType::A, QueryClass::IN, config).await;
+            synthesize_dns64(result, response, prefix);
+        }
+    }
+
+    complete_answer(packet, &question, result, cache, config)
+}
+
+pub async fn run_async(
+    address: SocketAddr,
+    cache: Arc<Cache>,
+    limiter: Arc<RateLimiter>,
+    config: Arc<ResolverConfig>,
+    running: Arc<AtomicBool>,
+) -> Result<()> {
+    let socket = Arc::new(UdpSocket::bind(address).await?);
+
+    while running.load(Ordering::SeqCst) {
+        let mut data = [0; MAX_UDP_SIZE as usize];
+
+        let (len, src) =
+            match time::timeout(SHUTDOWN_POLL_INTERVAL, socket.recv_from(&mut data)).await {
+                Ok(Ok(received)) => received,
+                Ok(Err(e)) => {
+                    error!("failed to receive query: {}", e);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+        let data = data[..len].to_vec();
+        let socket = Arc::clone(&socket);
+        let cache = Arc::clone(&cache);
+        let limiter = Arc::clone(&limiter);
+        let config = Arc::clone(&config);
+
+        tokio::spawn(async move {
+            if let Err(e) = answer_datagram(&socket, &data, src, &cache, &limiter, &config).await {
+                error!("failed to answer query from {}: {}", src, e);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+async fn answer_datagram(
+    socket: &UdpSocket,
+    data: &[u8],
+    src: SocketAddr,
+    cache: &Cache,
+    limiter: &RateLimiter,
+    config: &ResolverConfig,
+) -> Result<()> {
+    let mut request = BytePacketBuffer::from_slice(data);
+    let request = Packet::from_buffer(&mut request)?;
+
+    Stats::increment(&config.stats.queries_total);
+
+    let limit = reply_limit(&request);
+
+    let packet = if limiter.allow(src.ip(), Instant::now()) {
+        let edns = request.edns_udp_size().is_some();
+
+        finish_response(
+            answer_question_async(request, cache, config).await,
+            edns,
+            Transport::Udp(limit),
+            config,
+        )
+    } else {
+        build_refusal(&request)
+    };
+
+    let response = encode_reply(packet, limit)?;
+    config.stats.record_response_size(response.len());
+
+    socket.send_to(&response, src).await?;
+
+    for (qname, qtype) in cache.take_refreshes() {
+        let question = Question::new(qname, qtype);
+        let result = upstream_lookup_async(&question.qname, qtype, QueryClass::IN, config).await;
+        refresh(&question, result, cache, config);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a_record, MockUpstream};
+    use crate::ResponseCode;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn run_async_answers_through_forwarder() {
+        let mock = MockUpstream::start().unwrap();
+        let record = a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300);
+        mock.respond(
+            "example.com",
+            QueryType::A,
+            Packet::new().with_answer(record.clone()),
+        );
+
+        let config = ResolverConfig {
+            forwarders: vec![mock.address()],
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            ..ResolverConfig::default()
+        };
+        let address = std::net::UdpSocket::bind(("127.0.0.1", 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let response = runtime.block_on(async {
+            tokio::spawn(run_async(
+                address,
+                Arc::new(Cache::new()),
+                Arc::new(RateLimiter::unlimited()),
+                Arc::new(config),
+                Arc::new(AtomicBool::new(true)),
+            ));
+
+            let client = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+            let mut request = Packet::query("example.com", QueryType::A);
+            let mut data = [0; 512];
+            let len = request.write_into(&mut data).unwrap();
+
+            // resent until the server task has bound its socket
+            for _ in 0..25 {
+                client.send_to(&data[..len], address).await.unwrap();
+
+                let mut response = [0; 512];
+                let received =
+                    time::timeout(Duration::from_millis(200), client.recv(&mut response));
+
+                if let Ok(Ok(len)) = received.await {
+                    return Packet::from_bytes(&response[..len]).unwrap();
+                }
+            }
+
+            panic!("no response from {}", address);
+        });
+
+        assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+        assert_eq!(response.answers, vec![record]);
+        assert_eq!(mock.queries(), 1);
+    }
+
+    #[test]
+    fn run_async_returns_once_stopped() {
+        let running = Arc::new(AtomicBool::new(true));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        let result = runtime.block_on(async {
+            let server = tokio::spawn(run_async(
+                SocketAddr::from(([127, 0, 0, 1], 0)),
+                Arc::new(Cache::new()),
+                Arc::new(RateLimiter::unlimited()),
+                Arc::new(ResolverConfig::default()),
+                Arc::clone(&running),
+            ));
+
+            running.store(false, Ordering::SeqCst);
+
+            time::timeout(SHUTDOWN_POLL_INTERVAL * 4, server).await
+        });
+
+        assert!(matches!(result, Ok(Ok(Ok(())))));
+    }
+
+    #[test]
+    fn lookup_async_retries_truncated_response_over_tcp() {
+        let mock = MockUpstream::start().unwrap();
+        let mut canned =
+            Packet::new().with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300));
+        canned.header.tc = true;
+        mock.respond("example.com", QueryType::A, canned.clone());
+
+        let config = ResolverConfig {
+            timeout: Duration::from_secs(1),
+            retries: 0,
+            ..ResolverConfig::default()
+        };
+        let server = (mock.address().ip(), mock.address().port());
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let response = runtime
+            .block_on(lookup_async(
+                "example.com",
+                QueryType::A,
+                QueryClass::IN,
+                server,
+                &config,
+            ))
+            .unwrap();
+
+        assert!(!response.header.tc);
+        assert_eq!(response.answers, canned.answers);
+        assert_eq!(mock.queries(), 2);
+    }
+}
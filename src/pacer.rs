@@ -0,0 +1,118 @@
+use crate::utils::lock;
+use log::debug;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    /// queries that may still be sent right now, negative once sends have
+    /// been booked ahead of the refill
+    tokens: f64,
+    /// when the tokens were last refilled
+    updated: Instant,
+}
+
+#[derive(Debug, Default)]
+pub struct Pacer {
+    buckets: Mutex<HashMap<SocketAddr, Bucket>>,
+    cleaned: Mutex<Option<Instant>>,
+}
+
+impl Pacer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Books a send to `server` at up to `rate` queries a second, returning
+    /// how long to wait before sending, or None without booking anything if
+    /// that would take longer than `deadline`.
+    pub fn reserve(
+        &self,
+        server: SocketAddr,
+        rate: u32,
+        now: Instant,
+        deadline: Duration,
+    ) -> Option<Duration> {
+        let rate = f64::from(rate.max(1));
+
+        self.cleanup(now);
+
+        let mut buckets = lock(&self.buckets);
+        let bucket = buckets.entry(server).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens).max(0.0) / rate);
+
+        if wait > deadline {
+            debug!("pacing {} would wait {:?}, skipping it", server, wait);
+            return None;
+        }
+
+        bucket.tokens -= 1.0;
+
+        Some(wait)
+    }
+
+    fn cleanup(&self, now: Instant) {
+        let mut cleaned = lock(&self.cleaned);
+
+        match *cleaned {
+            Some(last) if now.saturating_duration_since(last) < CLEANUP_INTERVAL => return,
+            _ => *cleaned = Some(now),
+        }
+
+        // a bucket left alone for a full interval has refilled and can be recreated on demand
+        lock(&self.buckets)
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < CLEANUP_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEADLINE: Duration = Duration::from_millis(250);
+
+    #[test]
+    fn burst_is_spread_out_at_the_configured_rate() {
+        let pacer = Pacer::new();
+        let server = SocketAddr::from(([192, 0, 2, 53], 53));
+        let other = SocketAddr::from(([192, 0, 2, 54], 53));
+        let now = Instant::now();
+
+        for _ in 0..10 {
+            assert_eq!(
+                pacer.reserve(server, 10, now, DEADLINE),
+                Some(Duration::ZERO)
+            );
+        }
+
+        // each send past the burst waits another tenth of a second
+        let waits = (0..2)
+            .map(|_| pacer.reserve(server, 10, now, DEADLINE).unwrap())
+            .collect::<Vec<_>>();
+        assert!(waits[0] > Duration::from_millis(99) && waits[0] <= Duration::from_millis(100));
+        assert!(waits[1] > Duration::from_millis(199) && waits[1] <= Duration::from_millis(200));
+
+        // the next would wait past the deadline, so the server is skipped
+        assert_eq!(pacer.reserve(server, 10, now, DEADLINE), None);
+        assert_eq!(
+            pacer.reserve(other, 10, now, DEADLINE),
+            Some(Duration::ZERO)
+        );
+
+        // the booked sends are paid back before the bucket refills
+        let later = now + Duration::from_millis(300);
+        assert!(pacer.reserve(server, 10, later, DEADLINE).unwrap() <= Duration::from_millis(1));
+    }
+}
@@ -1,19 +1,56 @@
+use crate::error::DnsError;
 use crate::utils::Result;
 
-const LIMIT_OF_BUFFER: usize = 512;
+const DEFAULT_CAPACITY: usize = 512;
+const MAX_LABEL_LEN: usize = 0x3F;
+const MAX_NAME_LEN: usize = 0xFF;
 
-pub struct BytePacketBuffer {
-    pub buffer: [u8; 512],
+enum Storage<'a> {
+    /// grows as it is written to
+    Owned(Vec<u8>),
+    /// written in place, never past the end of the slice
+    Borrowed { data: &'a mut [u8], len: usize },
+}
+
+pub struct BytePacketBuffer<'a> {
+    buffer: Storage<'a>,
     pub position: usize,
 }
 
-impl BytePacketBuffer {
+impl BytePacketBuffer<'static> {
     pub fn new() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buffer: Storage::Owned(Vec::with_capacity(capacity)),
+            position: 0,
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Self {
         Self {
-            buffer: [0; 512],
+            buffer: Storage::Owned(data.to_vec()),
             position: 0,
         }
     }
+}
+
+impl<'a> BytePacketBuffer<'a> {
+    pub fn wrap(data: &'a mut [u8]) -> Self {
+        Self {
+            buffer: Storage::Borrowed { data, len: 0 },
+            position: 0,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self.buffer {
+            Storage::Owned(ref buffer) => buffer,
+            Storage::Borrowed { ref data, len } => &data[..len],
+        }
+    }
 
     pub fn step(&mut self, steps: usize) -> Result<()> {
         self.position += steps;
@@ -28,44 +65,68 @@ impl BytePacketBuffer {
     }
 
     fn read(&mut self) -> Result<u8> {
-        if self.position >= LIMIT_OF_BUFFER {
-            return Err("end buffer".into());
+        if self.position >= self.data().len() {
+            return Err(DnsError::EndOfBuffer);
         }
 
-        let result = self.buffer[self.position];
+        let result = self.data()[self.position];
         self.position += 1;
 
         Ok(result)
     }
 
     fn get(&self, position: usize) -> Result<u8> {
-        if position >= LIMIT_OF_BUFFER {
-            return Err("end of buffer".into());
+        if position >= self.data().len() {
+            return Err(DnsError::EndOfBuffer);
         }
 
-        Ok(self.buffer[position])
+        Ok(self.data()[position])
     }
 
     pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8]> {
-        if start + len >= LIMIT_OF_BUFFER {
-            return Err("end of buffer".into());
+        if start + len > self.data().len() {
+            return Err(DnsError::EndOfBuffer);
         }
 
-        Ok(&self.buffer[start..len + start])
+        Ok(&self.data()[start..len + start])
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        self.read()
     }
 
     pub fn read_u16(&mut self) -> Result<u16> {
-        Ok((self.read()? as u16) << 8 | (self.read()? as u16) << 0)
+        Ok((self.read()? as u16) << 8 | (self.read()? as u16))
     }
 
     pub fn read_u32(&mut self) -> Result<u32> {
         Ok((self.read()? as u32) << 24
             | (self.read()? as u32) << 16
             | (self.read()? as u32) << 8
-            | (self.read()? as u32) << 0)
+            | (self.read()? as u32))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        Ok((self.read_u32()? as u64) << 32 | (self.read_u32()? as u64))
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let bytes = self.get_range(self.position, len)?.to_vec();
+        self.step(len)?;
+
+        Ok(bytes)
     }
 
     pub fn read_qname(&mut self, out: &mut String) -> Result<()> {
+        let mut name = String::new();
+        self.read_qname_exact(&mut name)?;
+
+        out.push_str(&name.to_lowercase());
+
+        Ok(())
+    }
+
+    pub fn read_qname_exact(&mut self, out: &mut String) -> Result<()> {
         let mut position = self.position;
 
         let mut jumped = false;
@@ -76,7 +137,7 @@ impl BytePacketBuffer {
 
         loop {
             if jumped_cnt > max_jumped_cnt {
-                return Err(format!("limit of {} jumps exceeded", max_jumped_cnt).into());
+                return Err(DnsError::TooManyJumps(max_jumped_cnt));
             }
 
             let len = self.get(position)?;
@@ -87,8 +148,13 @@ impl BytePacketBuffer {
                 }
 
                 let b2 = self.get(position + 1)? as u16;
-                let offset = ((len as u16) ^ 0xC0) << 8 | b2;
-                position = offset as usize;
+                let offset = (((len as u16) ^ 0xC0) << 8 | b2) as usize;
+
+                if offset >= position {
+                    return Err(DnsError::InvalidPointer { offset, position });
+                }
+
+                position = offset;
 
                 jumped = true;
                 jumped_cnt += 1;
@@ -102,7 +168,7 @@ impl BytePacketBuffer {
                 out.push_str(delimiter);
 
                 let buffer = self.get_range(position, len as usize)?;
-                out.push_str(&String::from_utf8_lossy(buffer).to_lowercase());
+                out.push_str(&String::from_utf8_lossy(buffer));
 
                 delimiter = ".";
                 position += len as usize;
@@ -117,11 +183,31 @@ impl BytePacketBuffer {
     }
 
     fn write(&mut self, byte: u8) -> Result<()> {
-        if self.position >= LIMIT_OF_BUFFER {
-            return Err("End of buffer".into());
+        match self.buffer {
+            Storage::Owned(ref mut buffer) => {
+                if self.position < buffer.len() {
+                    buffer[self.position] = byte;
+                } else {
+                    buffer.resize(self.position, 0);
+                    buffer.push(byte);
+                }
+            }
+            Storage::Borrowed {
+                ref mut data,
+                ref mut len,
+            } => {
+                let limit = data.len();
+
+                *data
+                    .get_mut(self.position)
+                    .ok_or(DnsError::PacketTooLarge {
+                        len: self.position + 1,
+                        limit,
+                    })? = byte;
+                *len = (*len).max(self.position + 1);
+            }
         }
 
-        self.buffer[self.position] = byte;
         self.position += 1;
 
         Ok(())
@@ -135,7 +221,7 @@ impl BytePacketBuffer {
 
     pub fn write_u16(&mut self, byte: u16) -> Result<()> {
         self.write(((byte >> 8) & 0xFF) as u8)?;
-        self.write(((byte >> 0) & 0xFF) as u8)?;
+        self.write((byte & 0xFF) as u8)?;
 
         Ok(())
     }
@@ -144,17 +230,50 @@ impl BytePacketBuffer {
         self.write(((byte >> 24) & 0xFF) as u8)?;
         self.write(((byte >> 16) & 0xFF) as u8)?;
         self.write(((byte >> 8) & 0xFF) as u8)?;
-        self.write(((byte >> 0) & 0xFF) as u8)?;
+        self.write((byte & 0xFF) as u8)?;
+
+        Ok(())
+    }
+
+    pub fn write_u64(&mut self, byte: u64) -> Result<()> {
+        self.write_u32(((byte >> 32) & 0xFFFF_FFFF) as u32)?;
+        self.write_u32((byte & 0xFFFF_FFFF) as u32)?;
+
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<()> {
+        for byte in bytes {
+            self.write(*byte)?;
+        }
 
         Ok(())
     }
 
     pub fn write_qname(&mut self, query_name: &str) -> Result<()> {
-        for label in query_name.split(".") {
+        let query_name = query_name.strip_suffix('.').unwrap_or(query_name);
+
+        if query_name.is_empty() {
+            return self.write_u8(0);
+        }
+
+        // every label gains a length octet and the name ends with a zero octet
+        if query_name.len() + 2 > MAX_NAME_LEN {
+            return Err(DnsError::NameTooLong);
+        }
+
+        for label in query_name.split('.') {
             let len = label.len();
 
-            if len > 0x34 {
-                return Err("single label exceeds 63 characters of length".into());
+            if len == 0 {
+                return Err(DnsError::InvalidName(format!(
+                    "empty label in {:?}",
+                    query_name
+                )));
+            }
+
+            if len > MAX_LABEL_LEN {
+                return Err(DnsError::LabelTooLong);
             }
 
             self.write_u8(len as u8)?;
@@ -170,7 +289,14 @@ impl BytePacketBuffer {
     }
 
     fn set(&mut self, position: usize, byte: u8) -> Result<()> {
-        self.buffer[position] = byte;
+        if position >= self.data().len() {
+            return Err(DnsError::EndOfBuffer);
+        }
+
+        match self.buffer {
+            Storage::Owned(ref mut buffer) => buffer[position] = byte,
+            Storage::Borrowed { ref mut data, .. } => data[position] = byte,
+        }
 
         Ok(())
     }
@@ -182,3 +308,121 @@ impl BytePacketBuffer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(lens: &[usize]) -> String {
+        lens.iter()
+            .map(|&len| "a".repeat(len))
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    #[test]
+    fn name_limit_counts_every_encoded_octet() {
+        // 3 * (1 + 63) + (1 + 61) + 1 = 255 octets
+        let longest = labels(&[63, 63, 63, 61]);
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&longest).unwrap();
+        assert_eq!(buffer.position, MAX_NAME_LEN);
+
+        let too_long = labels(&[63, 63, 63, 62]);
+        assert!(matches!(
+            BytePacketBuffer::new().write_qname(&too_long),
+            Err(DnsError::NameTooLong)
+        ));
+        assert!(matches!(
+            BytePacketBuffer::new().write_qname(&labels(&[63; 4])),
+            Err(DnsError::NameTooLong)
+        ));
+    }
+
+    #[test]
+    fn self_referential_pointer_is_rejected() {
+        let mut data = vec![0; 12];
+        data.extend_from_slice(&[0xC0, 0x0C]);
+
+        let mut buffer = BytePacketBuffer::from_slice(&data);
+        buffer.step(12).unwrap();
+
+        assert!(matches!(
+            buffer.read_qname(&mut String::new()),
+            Err(DnsError::InvalidPointer {
+                offset: 12,
+                position: 12
+            })
+        ));
+    }
+
+    #[test]
+    fn empty_labels_are_rejected() {
+        for name in ["foo..bar", ".foo", "foo.."] {
+            assert!(
+                matches!(
+                    BytePacketBuffer::new().write_qname(name),
+                    Err(DnsError::InvalidName(_))
+                ),
+                "{:?}",
+                name
+            );
+        }
+
+        // a single trailing dot is the root label, not an empty one
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname("foo.").unwrap();
+        buffer.write_qname(".").unwrap();
+        assert_eq!(
+            buffer.get_range(0, buffer.position).unwrap(),
+            b"\x03foo\x00\x00"
+        );
+    }
+
+    #[test]
+    fn label_length_limit_is_63() {
+        let mut buffer = BytePacketBuffer::new();
+        buffer.write_qname(&labels(&[63, 3])).unwrap();
+        assert_eq!(buffer.get_range(0, 1).unwrap(), [63]);
+
+        assert!(matches!(
+            BytePacketBuffer::new().write_qname(&labels(&[64, 3])),
+            Err(DnsError::LabelTooLong)
+        ));
+    }
+
+    #[test]
+    fn get_range_reaches_the_last_byte() {
+        let buffer = BytePacketBuffer::from_slice(&[0; 512]);
+
+        assert_eq!(buffer.get_range(0, 512).unwrap().len(), 512);
+        assert_eq!(buffer.get_range(511, 1).unwrap(), [0]);
+        assert!(matches!(
+            buffer.get_range(0, 513),
+            Err(DnsError::EndOfBuffer)
+        ));
+        assert!(matches!(
+            buffer.get_range(512, 1),
+            Err(DnsError::EndOfBuffer)
+        ));
+    }
+
+    #[test]
+    fn reads_stop_at_written_length_not_capacity() {
+        let mut buffer = BytePacketBuffer::with_capacity(4096);
+        buffer.write_u32(0xDEAD_BEEF).unwrap();
+        buffer.position = 0;
+
+        assert_eq!(buffer.read_u32().unwrap(), 0xDEAD_BEEF);
+        assert!(matches!(buffer.read_u8(), Err(DnsError::EndOfBuffer)));
+        assert!(matches!(buffer.get_range(0, 5), Err(DnsError::EndOfBuffer)));
+
+        let mut out = [0; 64];
+        let mut wrapped = BytePacketBuffer::wrap(&mut out);
+        wrapped.write_u16(0xBEEF).unwrap();
+        assert!(matches!(
+            wrapped.get_range(0, 3),
+            Err(DnsError::EndOfBuffer)
+        ));
+    }
+}
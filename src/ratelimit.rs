@@ -0,0 +1,104 @@
+use crate::utils::lock;
+use log::debug;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Bucket {
+    /// queries the client may still send right now
+    tokens: f64,
+    /// when the tokens were last refilled
+    updated: Instant,
+}
+
+#[derive(Default)]
+pub struct RateLimiter {
+    rate: Option<u32>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    cleaned: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: Some(rate),
+            ..Self::default()
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&self, client: IpAddr, now: Instant) -> bool {
+        let rate = match self.rate {
+            Some(rate) => rate as f64,
+            None => return true,
+        };
+
+        self.cleanup(now);
+
+        let mut buckets = lock(&self.buckets);
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            debug!("rate limit exceeded by {}", client);
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+
+        true
+    }
+
+    fn cleanup(&self, now: Instant) {
+        let mut cleaned = lock(&self.cleaned);
+
+        match *cleaned {
+            Some(last) if now.saturating_duration_since(last) < CLEANUP_INTERVAL => return,
+            _ => *cleaned = Some(now),
+        }
+
+        // a bucket left alone for a full interval has refilled and can be recreated on demand
+        lock(&self.buckets)
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < CLEANUP_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn tokens_refill_at_the_configured_rate() {
+        let limiter = RateLimiter::new(2);
+        let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let now = Instant::now();
+
+        assert!(limiter.allow(client, now));
+        assert!(limiter.allow(client, now));
+        assert!(!limiter.allow(client, now));
+
+        // half a second at two queries a second earns one more
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.allow(client, later));
+        assert!(!limiter.allow(client, later));
+
+        // the bucket never holds more than a second's worth
+        let much_later = later + Duration::from_secs(10);
+        assert!(limiter.allow(client, much_later));
+        assert!(limiter.allow(client, much_later));
+        assert!(!limiter.allow(client, much_later));
+    }
+}
@@ -0,0 +1,467 @@
+use crate::config::ResolverConfig;
+use crate::dns::{Packet, QueryType, ResponseCode};
+use crate::error::DnsError;
+use crate::utils::Result;
+use log::debug;
+use rand::seq::SliceRandom;
+use std::collections::{HashSet, VecDeque};
+use std::mem;
+use std::net::IpAddr;
+use std::time::Duration;
+
+pub const MAX_NS_CONTACTED: usize = 32;
+pub const MAX_SUB_LOOKUPS: usize = 8;
+const MAX_CNAME_CHAIN: usize = 8;
+pub const MAX_RECURSION_DEPTH: u8 = 16;
+
+struct RecursionBudget {
+    contacted: HashSet<IpAddr>,
+    sub_lookups: usize,
+}
+
+impl RecursionBudget {
+    fn new() -> Self {
+        Self {
+            contacted: HashSet::new(),
+            sub_lookups: 0,
+        }
+    }
+
+    fn contact(&mut self, ns: IpAddr) -> Result<()> {
+        self.contacted.insert(ns);
+
+        if self.contacted.len() > MAX_NS_CONTACTED {
+            return Err(DnsError::Resolution(format!(
+                "contacted more than {} ns",
+                MAX_NS_CONTACTED
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn sub_lookup(&mut self) -> Result<()> {
+        self.sub_lookups += 1;
+
+        if self.sub_lookups > MAX_SUB_LOOKUPS {
+            return Err(DnsError::Resolution(format!(
+                "performed more than {} sub-lookups",
+                MAX_SUB_LOOKUPS
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// a query to a single nameserver that the recursion is waiting on
+pub struct Query {
+    pub qname: String,
+    pub qtype: QueryType,
+    pub server: IpAddr,
+}
+
+pub enum Step {
+    /// send the query and hand its result to `Recursion::resume`
+    Query(Query),
+    /// the resolution is complete
+    Done(Packet),
+}
+
+enum Referral {
+    /// the response is the final answer
+    Final(Packet),
+    /// the zone's nameservers, with addresses from glue
+    Resolved(Vec<IpAddr>, String),
+    /// a nameserver for the zone whose address has to be looked up first
+    Glueless(Packet, String),
+}
+
+/// a glueless referral waiting on the address of one of its nameservers
+struct Glueless {
+    referral: Packet,
+    ns_name: String,
+    /// the address type being looked up for the nameserver
+    qtype: QueryType,
+}
+
+/// the resolution of one name, from the root down
+struct Frame {
+    qname: String,
+    qtype: QueryType,
+    /// referrals followed to get here, counting those of the lookups above
+    depth: u8,
+    /// the zone whose nameservers are being queried
+    zone: String,
+    /// every nameserver that has been queried for this name
+    tried: HashSet<IpAddr>,
+    /// nameservers for the zone that have not been queried yet, fastest first
+    pending: VecDeque<IpAddr>,
+    /// set while a sub-lookup for a nameserver address is in progress
+    glueless: Option<Glueless>,
+}
+
+impl Frame {
+    fn enter(&mut self, servers: Vec<IpAddr>, zone: String, config: &ResolverConfig) {
+        self.tried.extend(servers.iter().copied());
+        self.pending = config.rtt.order(&servers).into();
+        self.zone = zone;
+    }
+
+    fn refer(&mut self, servers: Vec<IpAddr>, zone: String, config: &ResolverConfig) -> Result<()> {
+        self.depth += 1;
+        check_depth(self.depth)?;
+
+        self.enter(servers, zone, config);
+
+        Ok(())
+    }
+}
+
+/// Iterative resolution as a state machine that only decides what to ask
+/// next, so that the blocking and the async resolvers share every decision
+/// and differ only in how a query is sent.
+pub struct Recursion<'a> {
+    qname: String,
+    qtype: QueryType,
+    config: &'a ResolverConfig,
+    budget: RecursionBudget,
+    /// the name being resolved, above the sub-lookups it is waiting on
+    frames: Vec<Frame>,
+    /// the nameserver the outstanding query was sent to
+    server: Option<IpAddr>,
+    /// the answer for the original name while a CNAME target is resolved
+    response: Option<Packet>,
+    /// the last name in the CNAME chain followed so far
+    cname: String,
+    visited: HashSet<String>,
+}
+
+impl<'a> Recursion<'a> {
+    pub fn new(qname: &str, qtype: QueryType, config: &'a ResolverConfig) -> Self {
+        Self {
+            qname: qname.to_string(),
+            qtype,
+            config,
+            budget: RecursionBudget::new(),
+            frames: Vec::new(),
+            server: None,
+            response: None,
+            cname: qname.to_lowercase(),
+            visited: HashSet::new(),
+        }
+    }
+
+    pub fn start(&mut self) -> Result<Step> {
+        let qname = self.qname.clone();
+        self.push_frame(&qname, self.qtype, 0)?;
+
+        self.next_query()
+    }
+
+    pub fn resume(&mut self, result: Result<Packet>, elapsed: Duration) -> Result<Step> {
+        let ns = self
+            .server
+            .take()
+            .ok_or_else(|| DnsError::Resolution("no query outstanding".to_string()))?;
+        self.config.rtt.record(ns, elapsed);
+
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("skipping unreachable ns {}: {}", ns, e);
+                return self.next_query();
+            }
+        };
+
+        let frame = self.frame()?;
+
+        match screen_response(response, ns, &frame.qname, &frame.zone) {
+            Some(response) => self.follow(response),
+            None => self.next_query(),
+        }
+    }
+
+    fn frame(&mut self) -> Result<&mut Frame> {
+        self.frames
+            .last_mut()
+            .ok_or_else(|| DnsError::Resolution("no lookup in progress".to_string()))
+    }
+
+    fn push_frame(&mut self, qname: &str, qtype: QueryType, depth: u8) -> Result<()> {
+        check_depth(depth)?;
+
+        let mut frame = Frame {
+            qname: qname.to_string(),
+            qtype,
+            depth,
+            zone: String::new(),
+            tried: HashSet::new(),
+            pending: VecDeque::new(),
+            glueless: None,
+        };
+        frame.enter(root_servers(self.config)?, String::new(), self.config);
+
+        self.frames.push(frame);
+
+        Ok(())
+    }
+
+    fn next_query(&mut self) -> Result<Step> {
+        let frame = self.frame()?;
+
+        let ns = match frame.pending.pop_front() {
+            Some(ns) => ns,
+            None => return Err(no_usable_ns(&frame.zone)),
+        };
+
+        debug!(
+            "attempting lookup of {:?} {} with ns {}",
+            frame.qtype, frame.qname, ns
+        );
+
+        let query = Query {
+            qname: frame.qname.clone(),
+            qtype: frame.qtype,
+            server: ns,
+        };
+
+        self.budget.contact(ns)?;
+        self.server = Some(ns);
+
+        Ok(Step::Query(query))
+    }
+
+    fn follow(&mut self, response: Packet) -> Result<Step> {
+        let config = self.config;
+        let frame = self.frame()?;
+
+        match follow_referral(response, &frame.qname, &frame.tried)? {
+            Referral::Final(response) => self.finish(response),
+            Referral::Resolved(servers, zone) => {
+                frame.refer(servers, zone, config)?;
+                self.next_query()
+            }
+            Referral::Glueless(referral, ns_name) => {
+                let depth = frame.depth + 1;
+                frame.glueless = Some(Glueless {
+                    referral,
+                    ns_name: ns_name.clone(),
+                    qtype: QueryType::A,
+                });
+
+                self.sub_lookup(&ns_name, QueryType::A, depth)
+            }
+        }
+    }
+
+    fn sub_lookup(&mut self, ns_name: &str, qtype: QueryType, depth: u8) -> Result<Step> {
+        self.budget.sub_lookup()?;
+        self.push_frame(ns_name, qtype, depth)?;
+
+        self.next_query()
+    }
+
+    // hands a completed lookup to whatever was waiting on it
+    fn finish(&mut self, mut response: Packet) -> Result<Step> {
+        loop {
+            self.frames.pop();
+
+            let config = self.config;
+            let parent = match self.frames.last_mut() {
+                Some(parent) => parent,
+                None => return self.chase_cname(response),
+            };
+
+            let glueless = parent.glueless.take().ok_or_else(|| {
+                DnsError::Resolution("sub-lookup finished with nothing waiting".to_string())
+            })?;
+
+            let new_ns = if glueless.qtype == QueryType::A {
+                match response.get_random_a() {
+                    Some(address) => Some(IpAddr::V4(address)),
+                    None => {
+                        debug!("no A record for ns {}, trying AAAA", glueless.ns_name);
+
+                        let depth = parent.depth + 1;
+                        let ns_name = glueless.ns_name.clone();
+                        parent.glueless = Some(Glueless {
+                            qtype: QueryType::AAAA,
+                            ..glueless
+                        });
+
+                        return self.sub_lookup(&ns_name, QueryType::AAAA, depth);
+                    }
+                }
+            } else {
+                response.get_random_aaaa().map(IpAddr::V6)
+            };
+
+            match new_ns {
+                Some(new_ns) if parent.tried.contains(&new_ns) => {
+                    return Err(referral_loop(&parent.qname))
+                }
+                Some(new_ns) => {
+                    let zone = glueless
+                        .referral
+                        .get_ns_zone(&parent.qname)
+                        .unwrap_or_default()
+                        .to_string();
+                    parent.refer(vec![new_ns], zone, config)?;
+
+                    return self.next_query();
+                }
+                // without an address for its nameserver, the referral is
+                // all there is to answer with
+                None => response = glueless.referral,
+            }
+        }
+    }
+
+    fn chase_cname(&mut self, result: Packet) -> Result<Step> {
+        let mut response = match self.response.take() {
+            Some(mut response) => {
+                extend_cname_chain(&mut response, result);
+                response
+            }
+            None => result,
+        };
+
+        if self.qtype == QueryType::CNAME {
+            return Ok(Step::Done(response));
+        }
+
+        while let Some(target) = next_cname(&mut response, &self.cname, &mut self.visited)? {
+            let name = mem::replace(&mut self.cname, target.clone());
+
+            if !response.has_answer_for(&target) {
+                debug!("following cname {} -> {}", name, target);
+
+                self.response = Some(response);
+                self.push_frame(&target, self.qtype, 1)?;
+
+                return self.next_query();
+            }
+        }
+
+        Ok(Step::Done(response))
+    }
+}
+
+fn screen_response(mut response: Packet, ns: IpAddr, qname: &str, zone: &str) -> Option<Packet> {
+    response.retain_in_bailiwick(zone);
+
+    if response.is_lame(qname) {
+        debug!("skipping lame ns {} for zone {:?}", ns, zone);
+        return None;
+    }
+
+    Some(response)
+}
+
+fn no_usable_ns(zone: &str) -> DnsError {
+    DnsError::Resolution(format!("no usable ns for zone {:?}", zone))
+}
+
+fn next_cname(
+    response: &mut Packet,
+    name: &str,
+    visited: &mut HashSet<String>,
+) -> Result<Option<String>> {
+    let target = match response
+        .get_cname(name)
+        .map(str::to_lowercase)
+        .or_else(|| response.synthesize_cname(name))
+    {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+
+    if !visited.insert(name.to_string()) {
+        return Err(DnsError::Resolution(format!(
+            "cname loop detected at {}",
+            name
+        )));
+    }
+
+    if visited.len() > MAX_CNAME_CHAIN {
+        return Err(DnsError::Resolution(format!(
+            "cname chain exceeds {} links",
+            MAX_CNAME_CHAIN
+        )));
+    }
+
+    Ok(Some(target))
+}
+
+fn extend_cname_chain(response: &mut Packet, result: Packet) {
+    response.header.rcode = result.header.rcode;
+    response.answers.extend(result.answers);
+    response.authorities = result.authorities;
+}
+
+fn check_depth(depth: u8) -> Result<()> {
+    if depth > MAX_RECURSION_DEPTH {
+        return Err(DnsError::Resolution(format!(
+            "recursion depth exceeds {}",
+            MAX_RECURSION_DEPTH
+        )));
+    }
+
+    Ok(())
+}
+
+fn root_servers(config: &ResolverConfig) -> Result<Vec<IpAddr>> {
+    if config.root_hints.is_empty() {
+        return Err(DnsError::Resolution("no root hints configured".to_string()));
+    }
+
+    // shuffled so that roots without a measured rtt share the load
+    let mut servers = config
+        .root_hints
+        .iter()
+        .copied()
+        .map(IpAddr::V4)
+        .collect::<Vec<_>>();
+    servers.shuffle(&mut rand::thread_rng());
+
+    Ok(servers)
+}
+
+fn follow_referral(response: Packet, qname: &str, tried: &HashSet<IpAddr>) -> Result<Referral> {
+    if !response.answers.is_empty() && response.header.rcode == ResponseCode::NOERROR {
+        return Ok(Referral::Final(response));
+    }
+
+    if response.header.rcode == ResponseCode::NXDOMAIN {
+        return Ok(Referral::Final(response));
+    }
+
+    let mut resolved_ns = response.get_resolved_ns(qname).collect::<Vec<_>>();
+    resolved_ns.sort_by_key(IpAddr::is_ipv6);
+
+    if !resolved_ns.is_empty() {
+        resolved_ns.retain(|ns| !tried.contains(ns));
+
+        if resolved_ns.is_empty() {
+            return Err(referral_loop(qname));
+        }
+
+        let zone = response.get_ns_zone(qname).unwrap_or_default().to_string();
+
+        return Ok(Referral::Resolved(resolved_ns, zone));
+    }
+
+    match response.get_unresolved_ns(qname) {
+        Some(ns_name) => {
+            let ns_name = ns_name.to_string();
+
+            Ok(Referral::Glueless(response, ns_name))
+        }
+        None => Ok(Referral::Final(response)),
+    }
+}
+
+fn referral_loop(qname: &str) -> DnsError {
+    DnsError::Resolution(format!("referral loop while resolving {}", qname))
+}
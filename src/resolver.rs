@@ -0,0 +1,39 @@
+use crate::config::ResolverConfig;
+use crate::dns::{Packet, QueryClass, QueryType};
+use crate::utils::Result;
+use crate::{exchange, lookup, upstream_qname};
+use std::net::{IpAddr, SocketAddr};
+
+pub trait Resolver {
+    fn query(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet>;
+}
+
+pub struct UdpResolver<'a> {
+    /// timeouts, retries and source ports used for every query
+    config: &'a ResolverConfig,
+}
+
+impl<'a> UdpResolver<'a> {
+    pub fn new(config: &'a ResolverConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn resolve_from(
+        &self,
+        server: SocketAddr,
+        name: &str,
+        qtype: QueryType,
+        recursion_desired: bool,
+    ) -> Result<Packet> {
+        let mut packet = Packet::query(&upstream_qname(name, self.config), qtype);
+        packet.header.rd = recursion_desired;
+
+        exchange(packet, name, (server.ip(), server.port()), self.config)
+    }
+}
+
+impl Resolver for UdpResolver<'_> {
+    fn query(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet> {
+        lookup(qname, qtype, QueryClass::IN, server, self.config)
+    }
+}
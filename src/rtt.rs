@@ -0,0 +1,85 @@
+use crate::utils::lock;
+use log::debug;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const PROBE_CHANCE: f64 = 0.1;
+
+#[derive(Debug, Default)]
+pub struct RttTable {
+    /// smoothed round-trip time of each nameserver contacted so far
+    srtts: Mutex<HashMap<IpAddr, Duration>>,
+}
+
+impl RttTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, ns: IpAddr) -> Option<Duration> {
+        lock(&self.srtts).get(&ns).copied()
+    }
+
+    pub fn record(&self, ns: IpAddr, rtt: Duration) {
+        let mut srtts = lock(&self.srtts);
+
+        // weight each new sample by 1/8, as TCP does for its own srtt
+        let srtt = match srtts.get(&ns) {
+            Some(srtt) => (*srtt * 7 + rtt) / 8,
+            None => rtt,
+        };
+
+        srtts.insert(ns, srtt);
+    }
+
+    pub fn order(&self, servers: &[IpAddr]) -> Vec<IpAddr> {
+        let srtts = lock(&self.srtts);
+
+        let mut servers = servers.to_vec();
+        servers.sort_by_key(|ns| srtts.get(ns).copied().unwrap_or(Duration::MAX));
+
+        // now and then try a server that has never answered, so that a fast
+        // newcomer is not starved by the first server that happened to work
+        let unmeasured = (0..servers.len())
+            .filter(|&index| !srtts.contains_key(&servers[index]))
+            .collect::<Vec<_>>();
+
+        let mut rng = rand::thread_rng();
+
+        if let Some(&index) = unmeasured.choose(&mut rng) {
+            if index > 0 && rng.gen_bool(PROBE_CHANCE) {
+                debug!("probing unmeasured ns {}", servers[index]);
+                servers[..=index].rotate_right(1);
+            }
+        }
+
+        servers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn faster_ns_is_ordered_first() {
+        let slow = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let fast = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+        let rtt = RttTable::new();
+        rtt.record(slow, Duration::from_millis(200));
+        rtt.record(fast, Duration::from_millis(10));
+
+        assert_eq!(rtt.order(&[slow, fast]), vec![fast, slow]);
+
+        // a single fast sample only pulls the smoothed rtt down by an eighth
+        rtt.record(slow, Duration::from_millis(0));
+        assert_eq!(rtt.get(slow), Some(Duration::from_millis(175)));
+        assert_eq!(rtt.order(&[slow, fast]), vec![fast, slow]);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::utils::Result;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchList {
+    /// domains appended to names with fewer than ndots dots
+    pub domains: Vec<String>,
+    /// the number of dots a name needs before it is tried as-is first
+    pub ndots: usize,
+}
+
+impl Default for SearchList {
+    fn default() -> Self {
+        Self {
+            domains: Vec::new(),
+            ndots: 1,
+        }
+    }
+}
+
+impl SearchList {
+    pub fn new(domains: Vec<String>, ndots: usize) -> Self {
+        Self { domains, ndots }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::from_resolv_conf(&fs::read_to_string(path)?))
+    }
+
+    pub fn from_resolv_conf(contents: &str) -> Self {
+        let mut result = Self::default();
+        let mut domain = None;
+
+        for line in contents.lines() {
+            let mut fields = line.split_whitespace();
+
+            match fields.next() {
+                Some("search") => {
+                    result.domains = fields.map(|field| field.to_string()).collect();
+                }
+                Some("domain") => domain = fields.next().map(|field| field.to_string()),
+                Some("options") => {
+                    for option in fields {
+                        if let Some(Ok(ndots)) = option.strip_prefix("ndots:").map(str::parse) {
+                            result.ndots = ndots;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if result.domains.is_empty() {
+            result.domains.extend(domain);
+        }
+
+        result
+    }
+
+    pub fn candidates(&self, qname: &str) -> Vec<String> {
+        if let Some(qname) = qname.strip_suffix('.') {
+            return vec![qname.to_string()];
+        }
+
+        let searched = self
+            .domains
+            .iter()
+            .map(|domain| format!("{}.{}", qname, domain.trim_end_matches('.')));
+
+        if qname.matches('.').count() >= self.ndots {
+            std::iter::once(qname.to_string()).chain(searched).collect()
+        } else {
+            searched.chain(std::iter::once(qname.to_string())).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidates_follow_ndots() {
+        let search = SearchList::new(vec!["example.com".to_string()], 1);
+
+        assert_eq!(search.candidates("www"), vec!["www.example.com", "www"]);
+        assert_eq!(
+            search.candidates("www.test"),
+            vec!["www.test", "www.test.example.com"]
+        );
+        assert_eq!(search.candidates("www."), vec!["www"]);
+    }
+
+    #[test]
+    fn resolv_conf_is_parsed() {
+        let search = SearchList::from_resolv_conf(
+            "domain corp.example\nsearch a.example b.example.\noptions ndots:2 timeout:1\n",
+        );
+
+        assert_eq!(search.domains, vec!["a.example", "b.example."]);
+        assert_eq!(search.ndots, 2);
+    }
+}
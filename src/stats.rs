@@ -0,0 +1,258 @@
+use crate::error::UpstreamError;
+use crate::utils::lock;
+use crate::SHUTDOWN_POLL_INTERVAL;
+use log::debug;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// upper bounds in bytes of the response size histogram's buckets, with one
+/// more bucket past the last for anything larger
+pub const RESPONSE_SIZE_BUCKETS: [usize; 6] = [64, 128, 256, 512, 1232, 4096];
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// queries received from clients
+    pub queries_total: AtomicU64,
+    /// queries answered from the cache
+    pub cache_hits: AtomicU64,
+    /// queries that had to go upstream
+    pub cache_misses: AtomicU64,
+    /// responses sent with NXDOMAIN
+    pub nxdomain: AtomicU64,
+    /// responses sent with SERVFAIL
+    pub servfail: AtomicU64,
+    /// upstream queries that went unanswered
+    pub upstream_timeouts: AtomicU64,
+    /// expired cache entries served because upstream failed
+    pub served_stale: AtomicU64,
+    /// responses sent, by size bucket
+    pub response_sizes: [AtomicU64; RESPONSE_SIZE_BUCKETS.len() + 1],
+    /// bytes sent in all responses
+    pub response_bytes: AtomicU64,
+    /// failed upstream queries by upstream and failure class
+    upstream_errors: Mutex<HashMap<(SocketAddr, UpstreamError), u64>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub queries_total: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub nxdomain: u64,
+    pub servfail: u64,
+    pub upstream_timeouts: u64,
+    pub served_stale: u64,
+    pub response_sizes: [u64; RESPONSE_SIZE_BUCKETS.len() + 1],
+    pub response_bytes: u64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            queries_total: self.queries_total.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            nxdomain: self.nxdomain.load(Ordering::Relaxed),
+            servfail: self.servfail.load(Ordering::Relaxed),
+            upstream_timeouts: self.upstream_timeouts.load(Ordering::Relaxed),
+            served_stale: self.served_stale.load(Ordering::Relaxed),
+            response_sizes: self
+                .response_sizes
+                .each_ref()
+                .map(|bucket| bucket.load(Ordering::Relaxed)),
+            response_bytes: self.response_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Zeroes every counter, returning what they held. Each counter is read
+    /// and zeroed in one step, so no count is lost to a query racing it.
+    pub fn reset(&self) -> StatsSnapshot {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+
+        lock(&self.upstream_errors).clear();
+
+        StatsSnapshot {
+            queries_total: take(&self.queries_total),
+            cache_hits: take(&self.cache_hits),
+            cache_misses: take(&self.cache_misses),
+            nxdomain: take(&self.nxdomain),
+            servfail: take(&self.servfail),
+            upstream_timeouts: take(&self.upstream_timeouts),
+            served_stale: take(&self.served_stale),
+            response_sizes: self.response_sizes.each_ref().map(take),
+            response_bytes: take(&self.response_bytes),
+        }
+    }
+
+    /// Resets the counters every `interval` until `running` is cleared,
+    /// logging what each period counted.
+    pub fn run_rollover(&self, interval: Duration, running: &AtomicBool) {
+        let mut rolled = Instant::now();
+
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(interval.min(SHUTDOWN_POLL_INTERVAL));
+
+            if rolled.elapsed() >= interval {
+                rolled = Instant::now();
+
+                let period = self.reset();
+                debug!(
+                    "stats for the last {:?}: {} queries, {} cache hits, {} cache misses, \
+                     {} nxdomain, {} servfail, {} upstream timeouts, {} served stale",
+                    interval,
+                    period.queries_total,
+                    period.cache_hits,
+                    period.cache_misses,
+                    period.nxdomain,
+                    period.servfail,
+                    period.upstream_timeouts,
+                    period.served_stale
+                );
+            }
+        }
+    }
+
+    pub fn record_response_size(&self, len: usize) {
+        let bucket = RESPONSE_SIZE_BUCKETS
+            .iter()
+            .position(|&bound| len <= bound)
+            .unwrap_or(RESPONSE_SIZE_BUCKETS.len());
+
+        Self::increment(&self.response_sizes[bucket]);
+        self.response_bytes.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_upstream_error(&self, upstream: SocketAddr, error: UpstreamError) {
+        *lock(&self.upstream_errors)
+            .entry((upstream, error))
+            .or_default() += 1;
+    }
+
+    /// failure counts by upstream and class, ordered by upstream
+    pub fn upstream_errors(&self) -> Vec<(SocketAddr, UpstreamError, u64)> {
+        let mut errors = lock(&self.upstream_errors)
+            .iter()
+            .map(|(&(upstream, error), &count)| (upstream, error, count))
+            .collect::<Vec<_>>();
+        errors.sort();
+
+        errors
+    }
+
+    /// the snapshot's counters followed by the per-upstream failure counts
+    pub fn to_prometheus(&self) -> String {
+        let mut output = self.snapshot().to_prometheus();
+
+        let _ = writeln!(
+            output,
+            "# HELP dns_upstream_errors_total failed upstream queries by upstream and class"
+        );
+        let _ = writeln!(output, "# TYPE dns_upstream_errors_total counter");
+
+        for (upstream, error, count) in self.upstream_errors() {
+            let _ = writeln!(
+                output,
+                "dns_upstream_errors_total{{upstream=\"{}\",class=\"{}\"}} {}",
+                upstream,
+                error.label(),
+                count
+            );
+        }
+
+        output
+    }
+}
+
+impl StatsSnapshot {
+    pub fn to_prometheus(&self) -> String {
+        let counters = [
+            (
+                "queries_total",
+                "queries received from clients",
+                self.queries_total,
+            ),
+            (
+                "cache_hits_total",
+                "queries answered from the cache",
+                self.cache_hits,
+            ),
+            (
+                "cache_misses_total",
+                "queries that had to go upstream",
+                self.cache_misses,
+            ),
+            (
+                "nxdomain_total",
+                "responses sent with NXDOMAIN",
+                self.nxdomain,
+            ),
+            (
+                "servfail_total",
+                "responses sent with SERVFAIL",
+                self.servfail,
+            ),
+            (
+                "upstream_timeouts_total",
+                "upstream queries that went unanswered",
+                self.upstream_timeouts,
+            ),
+            (
+                "served_stale_total",
+                "expired cache entries served because upstream failed",
+                self.served_stale,
+            ),
+        ];
+
+        let mut output = String::new();
+
+        for (name, help, value) in counters {
+            let _ = writeln!(output, "# HELP dns_{} {}", name, help);
+            let _ = writeln!(output, "# TYPE dns_{} counter", name);
+            let _ = writeln!(output, "dns_{} {}", name, value);
+        }
+
+        let _ = writeln!(
+            output,
+            "# HELP dns_response_size_bytes sizes of responses sent"
+        );
+        let _ = writeln!(output, "# TYPE dns_response_size_bytes histogram");
+
+        // prometheus buckets count everything up to their bound
+        let mut cumulative = 0;
+
+        for (bucket, count) in self.response_sizes.iter().enumerate() {
+            cumulative += count;
+
+            let bound = RESPONSE_SIZE_BUCKETS
+                .get(bucket)
+                .map_or("+Inf".to_string(), usize::to_string);
+            let _ = writeln!(
+                output,
+                "dns_response_size_bytes_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "dns_response_size_bytes_sum {}",
+            self.response_bytes
+        );
+        let _ = writeln!(output, "dns_response_size_bytes_count {}", cumulative);
+
+        output
+    }
+}
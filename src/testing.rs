@@ -0,0 +1,358 @@
+// built for the crate's own tests too, which do not use every helper
+#![cfg_attr(not(feature = "testing"), allow(dead_code))]
+
+use crate::dns::{Packet, QueryClass, QueryType, Question, Record, ResponseCode};
+use crate::error::DnsError;
+use crate::packet::BytePacketBuffer;
+use crate::resolver::Resolver;
+use crate::utils::{lock, Result};
+use crate::{is_poll_timeout, read_message, write_message};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+const HEADER_LEN: usize = 12;
+const MAX_BIND_ATTEMPTS: usize = 8;
+
+type Responses = HashMap<(String, QueryType), Packet>;
+type Query = (IpAddr, String, QueryType);
+
+pub struct MockUpstream {
+    /// the local address the mock answers on, over both UDP and TCP
+    address: SocketAddr,
+    /// canned responses keyed by question
+    responses: Arc<Mutex<Responses>>,
+    /// how many queries the mock has received
+    queries: Arc<AtomicUsize>,
+    /// the most recent query the mock has received
+    last_request: Arc<Mutex<Option<Packet>>>,
+    /// cleared to stop the answering threads
+    running: Arc<AtomicBool>,
+    /// the UDP and TCP answering threads
+    handles: Vec<JoinHandle<()>>,
+}
+
+struct Answerer {
+    responses: Arc<Mutex<Responses>>,
+    queries: Arc<AtomicUsize>,
+    last_request: Arc<Mutex<Option<Packet>>>,
+    running: Arc<AtomicBool>,
+}
+
+impl MockUpstream {
+    pub fn start() -> Result<Self> {
+        let (socket, listener) = bind_udp_and_tcp()?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        listener.set_nonblocking(true)?;
+
+        let address = socket.local_addr()?;
+        let responses = Arc::new(Mutex::new(HashMap::new()));
+        let queries = Arc::new(AtomicUsize::new(0));
+        let last_request = Arc::new(Mutex::new(None));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let answerer = || Answerer {
+            responses: Arc::clone(&responses),
+            queries: Arc::clone(&queries),
+            last_request: Arc::clone(&last_request),
+            running: Arc::clone(&running),
+        };
+
+        let udp = answerer();
+        let tcp = answerer();
+
+        let handles = vec![
+            thread::spawn(move || {
+                while udp.running.load(Ordering::SeqCst) {
+                    if let Err(e) = udp.answer_datagram(&socket) {
+                        error!("mock upstream failed to answer: {}", e);
+                    }
+                }
+            }),
+            thread::spawn(move || {
+                while tcp.running.load(Ordering::SeqCst) {
+                    if let Err(e) = tcp.answer_connection(&listener) {
+                        error!("mock upstream failed to answer over tcp: {}", e);
+                    }
+                }
+            }),
+        ];
+
+        Ok(Self {
+            address,
+            responses,
+            queries,
+            last_request,
+            running,
+            handles,
+        })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Sets the response to a question. A canned response with TC set is
+    /// sent truncated over UDP and in full over TCP.
+    pub fn respond(&self, qname: &str, qtype: QueryType, response: Packet) {
+        lock(&self.responses).insert((qname.to_lowercase(), qtype), response);
+    }
+
+    pub fn queries(&self) -> usize {
+        self.queries.load(Ordering::SeqCst)
+    }
+
+    pub fn last_request(&self) -> Option<Packet> {
+        lock(&self.last_request).clone()
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+// the port is picked for UDP, so it may already be taken for TCP
+fn bind_udp_and_tcp() -> Result<(UdpSocket, TcpListener)> {
+    for _ in 0..MAX_BIND_ATTEMPTS {
+        let socket = UdpSocket::bind(("127.0.0.1", 0))?;
+
+        match TcpListener::bind(socket.local_addr()?) {
+            Ok(listener) => return Ok((socket, listener)),
+            Err(e) if e.kind() == ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Err(DnsError::Resolution(
+        "no port free for both udp and tcp".to_string(),
+    ))
+}
+
+impl Answerer {
+    fn answer_datagram(&self, socket: &UdpSocket) -> Result<()> {
+        let mut data = [0; 512];
+
+        let (len, src) = match socket.recv_from(&mut data) {
+            Ok(received) => received,
+            Err(_) => return Ok(()),
+        };
+
+        socket.send_to(&self.reply(&data[..len], true)?, src)?;
+
+        Ok(())
+    }
+
+    fn answer_connection(&self, listener: &TcpListener) -> Result<()> {
+        let mut stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        };
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        while self.running.load(Ordering::SeqCst) {
+            let data = match read_message(&mut stream) {
+                Ok(Some(data)) => data,
+                Ok(None) => break,
+                Err(e) if is_poll_timeout(&e) => continue,
+                Err(e) => return Err(e),
+            };
+
+            write_message(&mut stream, &self.reply(&data, false)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn reply(&self, data: &[u8], udp: bool) -> Result<Vec<u8>> {
+        self.queries.fetch_add(1, Ordering::SeqCst);
+
+        let mut request = BytePacketBuffer::from_slice(data);
+        let request = Packet::from_buffer(&mut request)?;
+        *lock(&self.last_request) = Some(request.clone());
+
+        let canned = request.questions.first().and_then(|question| {
+            lock(&self.responses)
+                .get(&(question.qname.to_lowercase(), question.qtype))
+                .cloned()
+        });
+
+        // the canned packet only supplies the records and rcode, everything
+        // that ties it to the request is taken from the request itself
+        let mut response = Packet::response_to(&request);
+
+        // echo the name with the case it was sent in, as a real server would
+        if let Some(question) = response.questions.first_mut() {
+            let mut qname = BytePacketBuffer::from_slice(data);
+            qname.step(HEADER_LEN)?;
+            question.qname.clear();
+            qname.read_qname_exact(&mut question.qname)?;
+        }
+
+        match canned {
+            Some(canned) if udp && canned.header.tc => response.truncate(),
+            Some(canned) => {
+                response.header.aa = canned.header.aa;
+                response.header.rcode = canned.header.rcode;
+                response.answers = canned.answers;
+                response.authorities = canned.authorities;
+                response.additions = canned.additions;
+            }
+            None => {
+                debug!("mock upstream has no response for {:?}", request.questions);
+                response.header.rcode = ResponseCode::REFUSED;
+            }
+        }
+
+        let mut buffer = BytePacketBuffer::new();
+        response.write(&mut buffer)?;
+
+        Ok(buffer.get_range(0, buffer.position)?.to_vec())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MockResolver {
+    /// canned responses keyed by nameserver and question
+    responses: Mutex<HashMap<Query, Packet>>,
+    /// every query made so far, in order
+    queries: Mutex<Vec<Query>>,
+}
+
+impl MockResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn respond(&self, server: IpAddr, qname: &str, qtype: QueryType, response: Packet) {
+        lock(&self.responses).insert((server, qname.to_lowercase(), qtype), response);
+    }
+
+    pub fn queries(&self) -> Vec<Query> {
+        lock(&self.queries).clone()
+    }
+}
+
+impl Resolver for MockResolver {
+    fn query(&self, qname: &str, qtype: QueryType, server: (IpAddr, u16)) -> Result<Packet> {
+        let key = (server.0, qname.to_lowercase(), qtype);
+        lock(&self.queries).push(key.clone());
+
+        let mut response = lock(&self.responses).get(&key).cloned().ok_or_else(|| {
+            DnsError::Resolution(format!(
+                "no mock response for {:?} {} at {}",
+                qtype, qname, server.0
+            ))
+        })?;
+
+        response.header.response = true;
+        response.questions = vec![Question::new(qname.to_string(), qtype)];
+
+        Ok(response)
+    }
+}
+
+pub fn a_record(domain: &str, address: Ipv4Addr, ttl: u32) -> Record {
+    Record::A {
+        domain: domain.to_string(),
+        address,
+        class: QueryClass::IN,
+        ttl,
+    }
+}
+
+pub fn aaaa_record(domain: &str, address: Ipv6Addr, ttl: u32) -> Record {
+    Record::AAAA {
+        domain: domain.to_string(),
+        address,
+        class: QueryClass::IN,
+        ttl,
+    }
+}
+
+pub fn ns_record(domain: &str, host: &str) -> Record {
+    Record::NS {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        class: QueryClass::IN,
+        ttl: 3600,
+    }
+}
+
+pub fn cname_record(domain: &str, host: &str, ttl: u32) -> Record {
+    Record::CNAME {
+        domain: domain.to_string(),
+        host: host.to_string(),
+        class: QueryClass::IN,
+        ttl,
+    }
+}
+
+pub fn mx_record(domain: &str, priority: u16, host: &str) -> Record {
+    Record::MX {
+        domain: domain.to_string(),
+        priority,
+        host: host.to_string(),
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+pub fn srv_record(domain: &str, priority: u16, weight: u16, port: u16, target: &str) -> Record {
+    Record::SRV {
+        domain: domain.to_string(),
+        priority,
+        weight,
+        port,
+        target: target.to_string(),
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+pub fn txt_record(domain: &str, strings: Vec<String>) -> Record {
+    Record::TXT {
+        domain: domain.to_string(),
+        strings,
+        class: QueryClass::IN,
+        ttl: 300,
+    }
+}
+
+/// `count` distinct 100-byte TXT records, for filling up a response
+pub fn txt_records(domain: &str, count: usize) -> Vec<Record> {
+    (0..count)
+        .map(|i| txt_record(domain, vec![format!("{:0>100}", i)]))
+        .collect()
+}
+
+pub fn soa_record(domain: &str, ttl: u32, minimum: u32) -> Record {
+    Record::SOA {
+        domain: domain.to_string(),
+        mname: format!("ns.{}", domain),
+        rname: format!("hostmaster.{}", domain),
+        serial: 1,
+        refresh: 7200,
+        retry: 3600,
+        expire: 1209600,
+        minimum,
+        class: QueryClass::IN,
+        ttl,
+    }
+}
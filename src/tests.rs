@@ -0,0 +1,2370 @@
+use super::*;
+use crate::recursion::{MAX_NS_CONTACTED, MAX_RECURSION_DEPTH, MAX_SUB_LOOKUPS};
+use crate::testing::{
+    a_record, aaaa_record, cname_record, mx_record, ns_record, soa_record, txt_records,
+    MockResolver, MockUpstream,
+};
+use crate::utils::lock;
+use std::collections::HashSet;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex, Once};
+use std::thread::{self, ThreadId};
+
+const ROOT: Ipv4Addr = Ipv4Addr::new(198, 41, 0, 4);
+
+fn recursing_from_root() -> ResolverConfig {
+    ResolverConfig {
+        root_hints: vec![ROOT],
+        ..ResolverConfig::default()
+    }
+}
+
+fn forwarding_to(mock: &MockUpstream) -> ResolverConfig {
+    ResolverConfig {
+        forwarders: vec![mock.address()],
+        timeout: Duration::from_millis(200),
+        retries: 0,
+        ..ResolverConfig::default()
+    }
+}
+
+fn exchange(request: &mut Packet, cache: &Cache, config: &ResolverConfig) -> Packet {
+    let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut data = [0; 512];
+    let len = request.write_into(&mut data).unwrap();
+    client
+        .send_to(&data[..len], server.local_addr().unwrap())
+        .unwrap();
+
+    handle_query(&server, cache, &RateLimiter::unlimited(), config).unwrap();
+
+    let mut data = [0; MAX_UDP_SIZE as usize];
+    let len = client.recv(&mut data).unwrap();
+
+    Packet::from_bytes(&data[..len]).unwrap()
+}
+
+#[test]
+fn lookup_returns_mock_upstream_response() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let config = forwarding_to(&mock);
+    let server = (mock.address().ip(), mock.address().port());
+    let response = lookup("example.com", QueryType::A, QueryClass::IN, server, &config).unwrap();
+
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(address));
+    assert_eq!(mock.queries(), 1);
+}
+
+#[test]
+fn burst_at_one_upstream_is_paced_to_configured_rate() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300)),
+    );
+
+    let config = ResolverConfig {
+        upstream_rate: 10,
+        timeout: Duration::from_secs(1),
+        ..forwarding_to(&mock)
+    };
+    let server = (mock.address().ip(), mock.address().port());
+
+    // a second's worth goes out at once, the rest a tenth of a second apart
+    let started = Instant::now();
+    for _ in 0..15 {
+        lookup("example.com", QueryType::A, QueryClass::IN, server, &config).unwrap();
+    }
+
+    assert!(started.elapsed() >= Duration::from_millis(450));
+    assert_eq!(mock.queries(), 15);
+
+    // a send that could not go out before the timeout skips the server
+    let config = ResolverConfig {
+        upstream_rate: 2,
+        timeout: Duration::from_millis(200),
+        ..forwarding_to(&mock)
+    };
+    let results = (0..3)
+        .map(|_| lookup("example.com", QueryType::A, QueryClass::IN, server, &config))
+        .collect::<Vec<_>>();
+
+    assert!(results[..2].iter().all(Result::is_ok));
+    assert!(matches!(results[2], Err(DnsError::Resolution(_))));
+    assert_eq!(mock.queries(), 17);
+}
+
+#[test]
+fn second_query_is_answered_from_cache() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+
+    let first = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    let second = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(mock.queries(), 1);
+    assert_eq!(first.get_random_a(), Some(address));
+    assert_eq!(second.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(second.get_random_a(), Some(address));
+}
+
+#[test]
+fn handle_query_forwards_to_mock_upstream() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let mut request = Packet::query("example.com", QueryType::A);
+    let response = exchange(&mut request, &Cache::new(), &forwarding_to(&mock));
+
+    assert_eq!(response.header.id, request.header.id);
+    assert!(response.header.response);
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(
+        response.answers,
+        vec![a_record("example.com", address, 300)]
+    );
+}
+
+#[test]
+fn recursive_lookup_chases_glueless_ns() {
+    let root = IpAddr::V4(ROOT);
+    let ns = Ipv4Addr::new(192, 0, 2, 53);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_authority(ns_record("example.com", "ns1.example.net")),
+    );
+    resolver.respond(
+        root,
+        "ns1.example.net",
+        QueryType::A,
+        Packet::new().with_answer(a_record("ns1.example.net", ns, 3600)),
+    );
+    resolver.respond(
+        IpAddr::V4(ns),
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www.example.com", address, 300)),
+    );
+
+    let config = recursing_from_root();
+    let response = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![a_record("www.example.com", address, 300)]
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![
+            (root, "www.example.com".to_string(), QueryType::A),
+            (root, "ns1.example.net".to_string(), QueryType::A),
+            (IpAddr::V4(ns), "www.example.com".to_string(), QueryType::A),
+        ]
+    );
+}
+
+const GTLD: Ipv4Addr = Ipv4Addr::new(192, 5, 6, 30);
+const ORG_TLD: Ipv4Addr = Ipv4Addr::new(199, 19, 56, 1);
+const EXAMPLE_COM_NS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 53);
+const EXAMPLE_NET_NS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 54);
+
+// responses as recorded from the root, the TLD servers and the zones'
+// authoritative servers; example.org is served by ns1.example.net, for which
+// no glue is given, so resolving it needs a lookup of its own
+fn recorded_delegations() -> MockResolver {
+    let resolver = MockResolver::new();
+    let root = IpAddr::V4(ROOT);
+
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("com", "a.gtld-servers.net"))
+            .with_addition(a_record("a.gtld-servers.net", GTLD, 172800)),
+    );
+    resolver.respond(
+        root,
+        "www.example.org",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("org", "a0.org.afilias-nst.info"))
+            .with_addition(a_record("a0.org.afilias-nst.info", ORG_TLD, 172800)),
+    );
+    resolver.respond(
+        root,
+        "ns1.example.net",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("net", "a.gtld-servers.net"))
+            .with_addition(a_record("a.gtld-servers.net", GTLD, 172800)),
+    );
+
+    resolver.respond(
+        IpAddr::V4(GTLD),
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("example.com", "ns1.example.com"))
+            .with_addition(a_record("ns1.example.com", EXAMPLE_COM_NS, 172800)),
+    );
+    resolver.respond(
+        IpAddr::V4(GTLD),
+        "ns1.example.net",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("example.net", "ns1.example.net"))
+            .with_addition(a_record("ns1.example.net", EXAMPLE_NET_NS, 172800)),
+    );
+    resolver.respond(
+        IpAddr::V4(ORG_TLD),
+        "www.example.org",
+        QueryType::A,
+        Packet::new().with_authority(ns_record("example.org", "ns1.example.net")),
+    );
+
+    resolver.respond(
+        IpAddr::V4(EXAMPLE_COM_NS),
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record(
+            "www.example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )),
+    );
+    resolver.respond(
+        IpAddr::V4(EXAMPLE_NET_NS),
+        "ns1.example.net",
+        QueryType::A,
+        Packet::new().with_answer(a_record("ns1.example.net", EXAMPLE_NET_NS, 3600)),
+    );
+    resolver.respond(
+        IpAddr::V4(EXAMPLE_NET_NS),
+        "www.example.org",
+        QueryType::A,
+        Packet::new().with_answer(a_record(
+            "www.example.org",
+            Ipv4Addr::new(192, 0, 2, 2),
+            300,
+        )),
+    );
+
+    resolver
+}
+
+fn query_to(server: Ipv4Addr, qname: &str) -> (IpAddr, String, QueryType) {
+    (IpAddr::V4(server), qname.to_string(), QueryType::A)
+}
+
+#[test]
+fn recorded_referrals_are_followed_from_root_to_answer() {
+    let resolver = recorded_delegations();
+    let config = recursing_from_root();
+
+    let response = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![a_record(
+            "www.example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300
+        )]
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![
+            query_to(ROOT, "www.example.com"),
+            query_to(GTLD, "www.example.com"),
+            query_to(EXAMPLE_COM_NS, "www.example.com"),
+        ]
+    );
+}
+
+#[test]
+fn recorded_out_of_bailiwick_nameserver_is_resolved_first() {
+    let resolver = recorded_delegations();
+    let config = recursing_from_root();
+
+    let response = recursive_lookup("www.example.org", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![a_record(
+            "www.example.org",
+            Ipv4Addr::new(192, 0, 2, 2),
+            300
+        )]
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![
+            query_to(ROOT, "www.example.org"),
+            query_to(ORG_TLD, "www.example.org"),
+            query_to(ROOT, "ns1.example.net"),
+            query_to(GTLD, "ns1.example.net"),
+            query_to(EXAMPLE_NET_NS, "ns1.example.net"),
+            query_to(EXAMPLE_NET_NS, "www.example.org"),
+        ]
+    );
+}
+
+fn reply(request: Packet, config: &ResolverConfig) -> Packet {
+    let src = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let limit = reply_limit(&request);
+    let response = build_reply(
+        request,
+        src,
+        Transport::Udp(limit),
+        &Cache::new(),
+        &RateLimiter::unlimited(),
+        config,
+    )
+    .unwrap();
+
+    Packet::from_bytes(&response).unwrap()
+}
+
+fn edns_query(qname: &str, qtype: QueryType, udp_size: u16) -> Packet {
+    Packet::query(qname, qtype).with_addition(Record::OPT {
+        domain: String::new(),
+        udp_size,
+        extended_rcode: 0,
+        version: 0,
+        flags: 0x8000,
+        data: Vec::new(),
+    })
+}
+
+#[test]
+fn oversized_reply_is_truncated() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(txt_records("big.example.com", 10))],
+        ..ResolverConfig::default()
+    };
+
+    let response = reply(Packet::query("big.example.com", QueryType::TXT), &config);
+
+    assert!(response.header.tc);
+    assert!(response.answers.is_empty());
+    assert!(response.has_question("big.example.com", QueryType::TXT));
+}
+
+// log lines recorded by the thread that logged them, so that each test only
+// sees its own
+static LOG_LINES: Mutex<Vec<(ThreadId, String)>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl log::Log for CapturingLogger {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let line = record.args().to_string();
+        lock(&LOG_LINES).push((thread::current().id(), line));
+    }
+
+    fn flush(&self) {}
+}
+
+fn captured_log_lines(f: impl FnOnce()) -> Vec<String> {
+    static LOGGER: CapturingLogger = CapturingLogger;
+    static INSTALL: Once = Once::new();
+
+    INSTALL.call_once(|| {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+    });
+
+    let id = thread::current().id();
+    lock(&LOG_LINES).retain(|(thread, _)| *thread != id);
+
+    f();
+
+    lock(&LOG_LINES)
+        .iter()
+        .filter(|(thread, _)| *thread == id)
+        .map(|(_, line)| line.clone())
+        .collect()
+}
+
+#[test]
+fn unsampled_queries_are_counted_but_not_logged() {
+    let mut config = ResolverConfig {
+        zones: vec![Zone::new(vec![a_record(
+            "example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )])],
+        log_sample_rate: 0.0,
+        ..ResolverConfig::default()
+    };
+    let detailed = |line: &String| line.starts_with("question:") || line.starts_with("response:");
+
+    let lines = captured_log_lines(|| {
+        reply(Packet::query("example.com", QueryType::A), &config);
+    });
+    assert!(!lines.iter().any(detailed), "{:?}", lines);
+    assert_eq!(config.stats.snapshot().queries_total, 1);
+
+    config.log_sample_rate = 1.0;
+    let lines = captured_log_lines(|| {
+        reply(Packet::query("example.com", QueryType::A), &config);
+    });
+    assert_eq!(lines.iter().filter(|line| detailed(line)).count(), 2);
+    assert_eq!(config.stats.snapshot().queries_total, 2);
+}
+
+#[test]
+fn response_sizes_land_in_their_buckets() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(
+            txt_records("big.example.com", 10)
+                .into_iter()
+                .chain([a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300)])
+                .collect(),
+        )],
+        ..ResolverConfig::default()
+    };
+
+    reply(Packet::query("example.com", QueryType::A), &config);
+    reply(edns_query("big.example.com", QueryType::TXT, 4096), &config);
+
+    let stats = config.stats.snapshot();
+    // up to 64 bytes, and between 1232 and 4096 bytes
+    assert_eq!(stats.response_sizes, [1, 0, 0, 0, 0, 1, 0]);
+
+    let metrics = stats.to_prometheus();
+    assert!(metrics.contains("dns_response_size_bytes_bucket{le=\"64\"} 1\n"));
+    assert!(metrics.contains("dns_response_size_bytes_bucket{le=\"1232\"} 1\n"));
+    assert!(metrics.contains("dns_response_size_bytes_bucket{le=\"4096\"} 2\n"));
+    assert!(metrics.contains("dns_response_size_bytes_bucket{le=\"+Inf\"} 2\n"));
+    assert!(metrics.contains("dns_response_size_bytes_count 2\n"));
+}
+
+#[test]
+fn reply_drops_whole_rrsets_before_truncating() {
+    let glue = a_record("ns1.example.com", Ipv4Addr::new(192, 0, 2, 53), 3600);
+    let mut packet = Packet::response_to(&Packet::query("www.example.com", QueryType::A));
+    packet.answers = vec![
+        a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 1), 300),
+        a_record("www.example.com", Ipv4Addr::new(192, 0, 2, 2), 300),
+    ];
+    packet.authorities = vec![
+        ns_record("example.com", "ns1.example.com"),
+        ns_record("example.com", "ns2.example.com"),
+    ];
+    packet.additions = vec![glue.clone()];
+    packet.additions.extend(txt_records("extra.example.com", 5));
+
+    let response = Packet::from_bytes(&encode_reply(packet.clone(), 512).unwrap()).unwrap();
+
+    // the TXT RRset does not fit and goes as a whole, the glue stays
+    assert!(!response.header.tc);
+    assert_eq!(response.answers, packet.answers);
+    assert_eq!(response.authorities, packet.authorities);
+    assert_eq!(response.additions, vec![glue]);
+
+    // with the answers alone too large, the response is truncated
+    packet.answers.extend(txt_records("www.example.com", 5));
+    let response = Packet::from_bytes(&encode_reply(packet, 512).unwrap()).unwrap();
+
+    assert!(response.header.tc);
+    assert!(response.answers.is_empty());
+    assert!(response.authorities.is_empty());
+    assert!(response.additions.is_empty());
+}
+
+#[test]
+fn acme_challenge_is_answered_until_cleared() {
+    let config = ResolverConfig::default();
+    let query = || Packet::query("_acme-challenge.Example.com", QueryType::TXT);
+
+    config
+        .challenges
+        .set_challenge("example.com.", "token-value");
+    let response = reply(query(), &config);
+
+    assert!(response.header.aa);
+    assert_eq!(
+        response.answers,
+        vec![Record::TXT {
+            domain: "_acme-challenge.example.com".to_string(),
+            strings: vec!["token-value".to_string()],
+            class: QueryClass::IN,
+            ttl: 60,
+        }]
+    );
+
+    config.challenges.clear_challenge("example.com");
+    let response = reply(query(), &config);
+
+    // answered locally as NODATA rather than looked up again
+    assert!(response.header.aa);
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert!(response.answers.is_empty());
+    assert_eq!(config.stats.snapshot().cache_misses, 0);
+}
+
+#[test]
+fn local_records_are_raised_to_zone_min_ttl() {
+    let address = Ipv4Addr::new(192, 168, 1, 1);
+    let config = ResolverConfig {
+        zones: vec![Zone::new(vec![
+            a_record("router.lan", address, 30),
+            a_record("printer.lan", address, 7200),
+        ])],
+        zone_min_ttl: 3600,
+        // the upstream bounds do not apply to local records
+        max_ttl: 60,
+        ..ResolverConfig::default()
+    };
+
+    let response = reply(Packet::query("router.lan", QueryType::A), &config);
+    assert_eq!(
+        response.answers,
+        vec![a_record("router.lan", address, 3600)]
+    );
+
+    let response = reply(Packet::query("printer.lan", QueryType::A), &config);
+    assert_eq!(
+        response.answers,
+        vec![a_record("printer.lan", address, 7200)]
+    );
+}
+
+#[test]
+fn ptr_is_synthesized_from_local_forward_records() {
+    let config = ResolverConfig {
+        zones: vec![
+            Zone::parse("host.example.com 300 A 10.0.0.5\n").unwrap(),
+            Zone::parse("$ORIGIN 10.in-addr.arpa.\n").unwrap(),
+        ],
+        synthesize_ptr: true,
+        ..ResolverConfig::default()
+    };
+
+    let response = reply(
+        Packet::query("5.0.0.10.in-addr.arpa", QueryType::PTR),
+        &config,
+    );
+
+    assert!(response.header.aa);
+    assert_eq!(
+        response.answers,
+        vec![Record::PTR {
+            domain: "5.0.0.10.in-addr.arpa".to_string(),
+            host: "host.example.com".to_string(),
+            class: QueryClass::IN,
+            ttl: 300,
+        }]
+    );
+}
+
+#[test]
+fn edns_reply_uses_advertised_size() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(txt_records("big.example.com", 10))],
+        ..ResolverConfig::default()
+    };
+
+    let response = reply(edns_query("big.example.com", QueryType::TXT, 4096), &config);
+
+    assert!(!response.header.tc);
+    assert_eq!(response.answers.len(), 10);
+    assert_eq!(response.edns_udp_size(), Some(MAX_UDP_SIZE));
+
+    let response = reply(edns_query("big.example.com", QueryType::TXT, 512), &config);
+
+    assert!(response.header.tc);
+    assert_eq!(response.edns_udp_size(), Some(MAX_UDP_SIZE));
+}
+
+#[test]
+fn tcp_reply_is_not_truncated() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(txt_records("big.example.com", 10))],
+        ..ResolverConfig::default()
+    };
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut request = Packet::query("big.example.com", QueryType::TXT);
+    let mut data = [0; 512];
+    let len = request.write_into(&mut data).unwrap();
+    client.write_all(&(len as u16).to_be_bytes()).unwrap();
+    client.write_all(&data[..len]).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    handle_query_tcp(
+        &mut stream,
+        &Cache::new(),
+        &RateLimiter::unlimited(),
+        &config,
+    )
+    .unwrap();
+
+    let response = Packet::from_bytes(&read_message(&mut client).unwrap().unwrap()).unwrap();
+
+    assert!(!response.header.tc);
+    assert_eq!(response.answers.len(), 10);
+}
+
+#[test]
+fn tcp_connection_answers_pipelined_queries_until_idle() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(vec![
+            a_record("one.example.com", Ipv4Addr::new(192, 0, 2, 1), 300),
+            a_record("two.example.com", Ipv4Addr::new(192, 0, 2, 2), 300),
+        ])],
+        tcp_keepalive: Duration::from_millis(100),
+        ..ResolverConfig::default()
+    };
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let mut requests = [
+        Packet::query("one.example.com", QueryType::A),
+        Packet::query("two.example.com", QueryType::A),
+    ];
+    for request in &mut requests {
+        write_message(&mut client, &encode(request)).unwrap();
+    }
+
+    // the client never closes, so the connection ends on the idle timeout
+    handle_query_tcp(
+        &mut stream,
+        &Cache::new(),
+        &RateLimiter::unlimited(),
+        &config,
+    )
+    .unwrap();
+
+    for request in &requests {
+        let response = Packet::from_bytes(&read_message(&mut client).unwrap().unwrap()).unwrap();
+
+        assert_eq!(response.header.id, request.header.id);
+        assert_eq!(response.questions, request.questions);
+        assert_eq!(response.answers.len(), 1);
+    }
+}
+
+#[test]
+fn tcp_connection_idle_past_advertised_keepalive_is_closed() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(vec![a_record(
+            "one.example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )])],
+        tcp_keepalive: Duration::from_millis(300),
+        ..ResolverConfig::default()
+    };
+
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0)).unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let (mut stream, _) = listener.accept().unwrap();
+
+    let server = {
+        let config = config.clone();
+
+        thread::spawn(move || {
+            handle_query_tcp(
+                &mut stream,
+                &Cache::new(),
+                &RateLimiter::unlimited(),
+                &config,
+            )
+        })
+    };
+
+    let mut request = edns_query("one.example.com", QueryType::A, 1232);
+    write_message(&mut client, &encode(&mut request)).unwrap();
+    let response = Packet::from_bytes(&read_message(&mut client).unwrap().unwrap()).unwrap();
+    let idle = Instant::now();
+
+    // option 11, two bytes long, three units of 100 milliseconds
+    assert!(matches!(
+        response.opt(),
+        Some(Record::OPT { data, .. }) if data[..] == [0, 11, 0, 2, 0, 3]
+    ));
+
+    assert!(read_message(&mut client).unwrap().is_none());
+    assert!(idle.elapsed() >= Duration::from_millis(250));
+    assert!(server.join().unwrap().is_ok());
+
+    // never advertised over UDP
+    let response = reply(edns_query("one.example.com", QueryType::A, 1232), &config);
+    assert!(matches!(response.opt(), Some(Record::OPT { data, .. }) if data.is_empty()));
+}
+
+#[test]
+fn tcp_reply_over_message_limit_is_truncated() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(txt_records("big.example.com", 600))],
+        ..ResolverConfig::default()
+    };
+
+    let request = Packet::query("big.example.com", QueryType::TXT);
+    let response = build_reply(
+        request,
+        IpAddr::V4(Ipv4Addr::LOCALHOST),
+        Transport::Tcp(Duration::from_secs(5)),
+        &Cache::new(),
+        &RateLimiter::unlimited(),
+        &config,
+    )
+    .unwrap();
+
+    assert!(response.len() <= MAX_TCP_MESSAGE_LEN);
+
+    let response = Packet::from_bytes(&response).unwrap();
+    assert!(response.header.tc);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn truncated_upstream_response_is_retried_over_tcp() {
+    let mock = MockUpstream::start().unwrap();
+    let mut canned = Packet::new();
+    canned.answers = txt_records("big.example.com", 20);
+    canned.header.tc = true;
+    mock.respond("big.example.com", QueryType::TXT, canned.clone());
+
+    let config = forwarding_to(&mock);
+    let cache = Cache::new();
+    let response = exchange(
+        &mut edns_query("big.example.com", QueryType::TXT, MAX_UDP_SIZE),
+        &cache,
+        &config,
+    );
+
+    assert!(!response.header.tc);
+    assert_eq!(response.answers, canned.answers);
+    assert_eq!(mock.queries(), 2);
+    assert_eq!(
+        mock.last_request().unwrap().edns_udp_size(),
+        Some(MAX_UDP_SIZE)
+    );
+    assert_eq!(
+        cache.get("big.example.com", QueryType::TXT, Instant::now()),
+        Some(canned.answers)
+    );
+}
+
+#[test]
+fn truncated_upstream_response_is_not_cached() {
+    let request = Packet::query("example.com", QueryType::A);
+    let mut result = Packet::response_to(&request).with_answer(a_record(
+        "example.com",
+        Ipv4Addr::new(192, 0, 2, 1),
+        300,
+    ));
+    result.header.tc = true;
+
+    let cache = Cache::new();
+    let response = complete_answer(
+        Packet::response_to(&request),
+        &request.questions[0],
+        Ok(result),
+        &cache,
+        &ResolverConfig::default(),
+    );
+
+    assert_eq!(response.answers.len(), 1);
+    assert_eq!(cache.get("example.com", QueryType::A, Instant::now()), None);
+}
+
+#[test]
+fn resolve_uses_injected_resolver_and_config() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let records = resolve(
+        "example.com",
+        QueryType::A,
+        &resolver,
+        &recursing_from_root(),
+    )
+    .unwrap();
+
+    assert_eq!(records, vec![a_record("example.com", address, 300)]);
+    assert_eq!(
+        resolver.queries(),
+        vec![(root, "example.com".to_string(), QueryType::A)]
+    );
+}
+
+#[test]
+fn flushed_entry_is_resolved_again_and_others_kept() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300)),
+    );
+    mock.respond(
+        "example.com",
+        QueryType::MX,
+        Packet::new().with_answer(mx_record("example.com", 10, "mail.example.com")),
+    );
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+    let query = |qtype| exchange(&mut Packet::query("example.com", qtype), &cache, &config);
+
+    query(QueryType::A);
+    query(QueryType::MX);
+    assert_eq!(mock.queries(), 2);
+
+    assert!(cache.remove("Example.com.", QueryType::A));
+    assert!(!cache.remove("example.com", QueryType::A));
+
+    assert_eq!(query(QueryType::A).answers.len(), 1);
+    assert_eq!(query(QueryType::MX).answers.len(), 1);
+    assert_eq!(mock.queries(), 3);
+
+    // every type cached for the name goes at once
+    assert_eq!(cache.remove_name("example.com"), 2);
+
+    query(QueryType::A);
+    query(QueryType::MX);
+    assert_eq!(mock.queries(), 5);
+}
+
+#[test]
+fn expired_entry_in_window_is_served_stale_and_refreshed() {
+    let mock = MockUpstream::start().unwrap();
+    let old = Ipv4Addr::new(192, 0, 2, 1);
+    let new = Ipv4Addr::new(192, 0, 2, 2);
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", new, 300)),
+    );
+
+    let cache = Cache::new();
+    let config = ResolverConfig {
+        stale_while_revalidate: Duration::from_secs(60),
+        ..forwarding_to(&mock)
+    };
+
+    // expired ten seconds ago
+    cache.insert(
+        "example.com",
+        QueryType::A,
+        vec![a_record("example.com", old, 5)],
+        Instant::now() - Duration::from_secs(15),
+    );
+
+    let stale = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(stale.answers, vec![a_record("example.com", old, 5)]);
+    assert_eq!(config.stats.snapshot().served_stale, 1);
+    // refreshed once the stale answer was sent
+    assert_eq!(mock.queries(), 1);
+
+    let fresh = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(fresh.get_random_a(), Some(new));
+    assert_eq!(mock.queries(), 1);
+}
+
+#[test]
+fn cached_nxdomain_is_served_with_soa() {
+    let mock = MockUpstream::start().unwrap();
+    let mut nxdomain = Packet::new().with_authority(soa_record("example.com", 3600, 300));
+    nxdomain.header.rcode = ResponseCode::NXDOMAIN;
+    mock.respond("nx.example.com", QueryType::A, nxdomain);
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+
+    let first = exchange(
+        &mut Packet::query("nx.example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    let second = exchange(
+        &mut Packet::query("nx.example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(mock.queries(), 1);
+    assert_eq!(first.header.rcode, ResponseCode::NXDOMAIN);
+    assert_eq!(second.header.rcode, ResponseCode::NXDOMAIN);
+    assert_eq!(second.authorities.len(), 1);
+    assert!(matches!(
+        second.authorities[0],
+        Record::SOA { ttl, .. } if ttl <= 300 && ttl > 290
+    ));
+}
+
+#[test]
+fn repeatedly_empty_aaaa_is_answered_with_fast_nodata() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond("v4.example.com", QueryType::AAAA, Packet::new());
+
+    let cache = Cache::new();
+    let config = ResolverConfig {
+        learn_ipv4_only: true,
+        ..forwarding_to(&mock)
+    };
+
+    for _ in 0..3 {
+        let response = exchange(
+            &mut Packet::query("v4.example.com", QueryType::AAAA),
+            &cache,
+            &config,
+        );
+
+        assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+        assert!(response.answers.is_empty());
+    }
+
+    // the empty answer itself is not cached, so only the learned name
+    // keeps the third query from going upstream
+    assert_eq!(mock.queries(), 2);
+    assert_eq!(config.stats.snapshot().cache_hits, 1);
+}
+
+#[test]
+fn pre_hook_rewrites_name_before_lookup() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    mock.respond(
+        "new.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("new.example.com", address, 300)),
+    );
+
+    let mut config = forwarding_to(&mock);
+    config.add_pre_hook(Box::new(|question| {
+        if question.qname.eq_ignore_ascii_case("old.example.com") {
+            HookAction::Rewrite("new.example.com".to_string())
+        } else {
+            HookAction::Allow
+        }
+    }));
+
+    let response = exchange(
+        &mut Packet::query("old.example.com", QueryType::A),
+        &Cache::new(),
+        &config,
+    );
+
+    assert_eq!(
+        mock.last_request().unwrap().questions[0]
+            .qname
+            .to_lowercase(),
+        "new.example.com"
+    );
+    assert_eq!(response.questions[0].qname, "old.example.com");
+    assert_eq!(
+        response.answers,
+        vec![a_record("old.example.com", address, 300)]
+    );
+}
+
+#[test]
+fn resolve_from_sends_requested_rd_bit() {
+    let mock = MockUpstream::start().unwrap();
+    let mut canned = Packet::new()
+        .with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300))
+        .with_authority(ns_record("example.com", "ns1.example.com"))
+        .with_addition(a_record(
+            "ns1.example.com",
+            Ipv4Addr::new(192, 0, 2, 53),
+            3600,
+        ));
+    canned.header.aa = true;
+    mock.respond("example.com", QueryType::A, canned.clone());
+
+    let config = forwarding_to(&mock);
+    let resolver = UdpResolver::new(&config);
+
+    for recursion_desired in [false, true] {
+        let response = resolver
+            .resolve_from(
+                mock.address(),
+                "example.com",
+                QueryType::A,
+                recursion_desired,
+            )
+            .unwrap();
+
+        assert_eq!(mock.last_request().unwrap().header.rd, recursion_desired);
+        assert_eq!(response.header.rd, recursion_desired);
+        assert!(response.header.aa);
+        assert_eq!(response.answers, canned.answers);
+        assert_eq!(response.authorities, canned.authorities);
+        assert_eq!(response.additions, canned.additions);
+    }
+}
+
+#[test]
+fn search_completes_single_label() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    // nothing is registered for the first domain, so resolving it fails
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www.example.com", address, 300)),
+    );
+
+    let search = SearchList::new(
+        vec!["broken.test".to_string(), "example.com".to_string()],
+        1,
+    );
+    let outcome = resolve_records_with_search(
+        "www",
+        QueryType::A,
+        &search,
+        &resolver,
+        &recursing_from_root(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        outcome,
+        ResolveOutcome::Records(vec![a_record("www.example.com", address, 300)])
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![
+            (root, "www.broken.test".to_string(), QueryType::A),
+            (root, "www.example.com".to_string(), QueryType::A),
+        ]
+    );
+}
+
+#[test]
+fn search_is_bypassed_by_trailing_dot() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www", address, 300)),
+    );
+
+    let search = SearchList::new(vec!["example.com".to_string()], 1);
+    let outcome = resolve_records_with_search(
+        "www.",
+        QueryType::A,
+        &search,
+        &resolver,
+        &recursing_from_root(),
+    )
+    .unwrap();
+
+    assert_eq!(
+        outcome,
+        ResolveOutcome::Records(vec![a_record("www", address, 300)])
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![(root, "www".to_string(), QueryType::A)]
+    );
+}
+
+#[test]
+fn referral_with_only_aaaa_glue_is_followed() {
+    let root = IpAddr::V4(ROOT);
+    let ns = "2001:db8::53".parse::<Ipv6Addr>().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("example.com", "ns1.example.com"))
+            .with_addition(aaaa_record("ns1.example.com", ns, 3600)),
+    );
+    resolver.respond(
+        IpAddr::V6(ns),
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www.example.com", address, 300)),
+    );
+
+    let config = recursing_from_root();
+    let response = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![a_record("www.example.com", address, 300)]
+    );
+    assert_eq!(
+        resolver.queries(),
+        vec![
+            (root, "www.example.com".to_string(), QueryType::A),
+            (IpAddr::V6(ns), "www.example.com".to_string(), QueryType::A),
+        ]
+    );
+}
+
+fn encode(packet: &mut Packet) -> Vec<u8> {
+    let mut buffer = BytePacketBuffer::new();
+    packet.write(&mut buffer).unwrap();
+    buffer.get_range(0, buffer.position).unwrap().to_vec()
+}
+
+#[test]
+fn response_must_echo_query_name_case() {
+    let server = (IpAddr::V4(Ipv4Addr::LOCALHOST), 53);
+    let src = SocketAddr::from(server);
+    let request = Packet::query("ExAmPlE.cOm", QueryType::A);
+
+    let mut echoed = Packet::response_to(&request);
+    assert!(accept_response(&encode(&mut echoed), src, server, &request).is_some());
+
+    let mut lowered = Packet::response_to(&request);
+    lowered.questions[0].qname = "example.com".to_string();
+    assert!(accept_response(&encode(&mut lowered), src, server, &request).is_none());
+}
+
+#[test]
+fn dname_redirects_names_below_owner() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let dname = Record::DNAME {
+        domain: "old.example.com".to_string(),
+        target: "new.example.com".to_string(),
+        class: QueryClass::IN,
+        ttl: 3600,
+    };
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "a.old.example.com",
+        QueryType::A,
+        Packet::new().with_answer(dname.clone()),
+    );
+    resolver.respond(
+        root,
+        "a.new.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("a.new.example.com", address, 300)),
+    );
+
+    let config = recursing_from_root();
+    let response = recursive_lookup("a.old.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![
+            dname,
+            cname_record("a.old.example.com", "a.new.example.com", 3600),
+            a_record("a.new.example.com", address, 300),
+        ]
+    );
+}
+
+#[test]
+fn any_returns_every_cached_type() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let a = a_record("example.com", address, 300);
+    let mx = mx_record("example.com", 10, "mail.example.com");
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a.clone()),
+    );
+    mock.respond(
+        "example.com",
+        QueryType::MX,
+        Packet::new().with_answer(mx.clone()),
+    );
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+
+    for qtype in [QueryType::A, QueryType::MX] {
+        exchange(&mut Packet::query("example.com", qtype), &cache, &config);
+    }
+
+    let response = exchange(
+        &mut Packet::query("example.com", QueryType::ANY),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(mock.queries(), 2);
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert!(response.has_question("example.com", QueryType::ANY));
+    assert_eq!(response.answers.len(), 2);
+    assert!(response.answers.contains(&a));
+    assert!(response.answers.contains(&mx));
+}
+
+#[test]
+fn first_query_goes_to_configured_root() {
+    let root = Ipv4Addr::new(192, 0, 2, 10);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        IpAddr::V4(root),
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let config = ResolverConfig {
+        root_hints: vec![root],
+        ..ResolverConfig::default()
+    };
+    let response = recursive_lookup("example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(response.get_random_a(), Some(address));
+    assert_eq!(
+        resolver.queries().first(),
+        Some(&(IpAddr::V4(root), "example.com".to_string(), QueryType::A))
+    );
+
+    let config = ResolverConfig {
+        root_hints: Vec::new(),
+        ..ResolverConfig::default()
+    };
+    assert!(recursive_lookup("example.com", QueryType::A, &resolver, &config).is_err());
+    assert_eq!(resolver.queries().len(), 1);
+}
+
+#[cfg(unix)]
+#[test]
+fn reuseport_sockets_share_a_port() {
+    let first = bind_reuseport(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    let address = first.local_addr().unwrap();
+    let second = bind_reuseport(address).unwrap();
+
+    assert_eq!(second.local_addr().unwrap(), address);
+    assert!(UdpSocket::bind(address).is_err());
+}
+
+#[test]
+fn dns64_synthesizes_aaaa_from_a() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond("v4only.example.com", QueryType::AAAA, Packet::new());
+    mock.respond(
+        "v4only.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record(
+            "v4only.example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )),
+    );
+
+    let config = ResolverConfig {
+        dns64_prefix: Some(DNS64_PREFIX),
+        ..forwarding_to(&mock)
+    };
+    let response = exchange(
+        &mut Packet::query("v4only.example.com", QueryType::AAAA),
+        &Cache::new(),
+        &config,
+    );
+
+    assert_eq!(
+        response.answers,
+        vec![aaaa_record(
+            "v4only.example.com",
+            "64:ff9b::c000:201".parse().unwrap(),
+            300
+        )]
+    );
+}
+
+#[test]
+fn faster_root_is_queried_first() {
+    let slow = Ipv4Addr::new(192, 0, 2, 10);
+    let fast = Ipv4Addr::new(192, 0, 2, 11);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    for root in [slow, fast] {
+        resolver.respond(
+            IpAddr::V4(root),
+            "example.com",
+            QueryType::A,
+            Packet::new().with_answer(a_record("example.com", address, 300)),
+        );
+    }
+
+    let config = ResolverConfig {
+        root_hints: vec![slow, fast],
+        ..ResolverConfig::default()
+    };
+    config
+        .rtt
+        .record(IpAddr::V4(slow), Duration::from_millis(200));
+    config
+        .rtt
+        .record(IpAddr::V4(fast), Duration::from_millis(10));
+
+    recursive_lookup("example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        resolver.queries(),
+        vec![(IpAddr::V4(fast), "example.com".to_string(), QueryType::A)]
+    );
+}
+
+#[test]
+fn zero_ttl_is_raised_to_min_ttl() {
+    let mock = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 0)),
+    );
+
+    let cache = Cache::new();
+    let config = ResolverConfig {
+        min_ttl: 60,
+        ..forwarding_to(&mock)
+    };
+
+    let first = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    let second = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(first.answers, vec![a_record("example.com", address, 60)]);
+    assert!(matches!(second.answers[..], [Record::A { ttl, .. }] if ttl > 0 && ttl <= 60));
+    assert_eq!(mock.queries(), 1);
+}
+
+#[test]
+fn stale_answer_is_served_when_upstream_fails() {
+    let mock = MockUpstream::start().unwrap();
+    let mut servfail = Packet::new();
+    servfail.header.rcode = ResponseCode::SERVFAIL;
+    mock.respond("example.com", QueryType::A, servfail);
+
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let cache = Cache::new();
+    cache.insert(
+        "example.com",
+        QueryType::A,
+        vec![a_record("example.com", address, 60)],
+        Instant::now() - Duration::from_secs(120),
+    );
+
+    let config = forwarding_to(&mock);
+    let response = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+
+    assert_eq!(mock.queries(), 1);
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(response.answers, vec![a_record("example.com", address, 30)]);
+    assert_eq!(config.stats.snapshot().served_stale, 1);
+}
+
+#[test]
+fn serve_udp_stops_when_flag_is_cleared() {
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let address = socket.local_addr().unwrap();
+    let running = Arc::new(AtomicBool::new(true));
+    let (sender, receiver) = std::sync::mpsc::channel();
+
+    let server = {
+        let running = Arc::clone(&running);
+        std::thread::spawn(move || serve_udp(&socket, sender, &running))
+    };
+
+    let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let mut request = Packet::query("example.com", QueryType::A);
+    let mut data = [0; 512];
+    let len = request.write_into(&mut data).unwrap();
+    client.send_to(&data[..len], address).unwrap();
+
+    let (query, src) = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(query.header.id, request.header.id);
+    assert_eq!(src, client.local_addr().unwrap());
+
+    let stopped = Instant::now();
+    running.store(false, Ordering::SeqCst);
+    server.join().unwrap().unwrap();
+
+    assert!(stopped.elapsed() < SHUTDOWN_POLL_INTERVAL * 2);
+}
+
+#[test]
+fn reset_returns_accumulated_counts_and_zeroes_them() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(vec![a_record(
+            "router.lan",
+            Ipv4Addr::new(192, 168, 1, 1),
+            300,
+        )])],
+        blocklist: Blocklist::from_lines("ads.example.com", false),
+        ..ResolverConfig::default()
+    };
+
+    for qname in ["router.lan", "router.lan", "ads.example.com"] {
+        reply(Packet::query(qname, QueryType::A), &config);
+    }
+    config.stats.record_upstream_error(
+        SocketAddr::from(([192, 0, 2, 53], 53)),
+        UpstreamError::Timeout,
+    );
+
+    let period = config.stats.reset();
+
+    assert_eq!(period.queries_total, 3);
+    assert_eq!(period.nxdomain, 1);
+    assert_eq!(period.response_sizes.iter().sum::<u64>(), 3);
+    assert!(period.response_bytes > 0);
+
+    assert_eq!(config.stats.snapshot(), StatsSnapshot::default());
+    assert!(config.stats.upstream_errors().is_empty());
+}
+
+#[test]
+fn cache_hit_is_counted_separately_from_miss() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", Ipv4Addr::new(192, 0, 2, 1), 300)),
+    );
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+
+    exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    let stats = config.stats.snapshot();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (0, 1));
+
+    exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    let stats = config.stats.snapshot();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+    assert_eq!(stats.queries_total, 2);
+}
+
+#[test]
+fn burst_beyond_rate_is_refused() {
+    let config = ResolverConfig {
+        zones: vec![Zone::new(vec![a_record(
+            "example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )])],
+        ..ResolverConfig::default()
+    };
+    let cache = Cache::new();
+    let limiter = RateLimiter::new(3);
+    let client = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 100));
+    let other = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 101));
+
+    let rcode = |src| {
+        let request = Packet::query("example.com", QueryType::A);
+        let limit = reply_limit(&request);
+        let response = build_reply(
+            request,
+            src,
+            Transport::Udp(limit),
+            &cache,
+            &limiter,
+            &config,
+        )
+        .unwrap();
+        Packet::from_bytes(&response).unwrap().header.rcode
+    };
+
+    for _ in 0..3 {
+        assert_eq!(rcode(client), ResponseCode::NOERROR);
+    }
+    assert_eq!(rcode(client), ResponseCode::REFUSED);
+    assert_eq!(rcode(other), ResponseCode::NOERROR);
+}
+
+#[test]
+fn blocked_name_is_answered_locally() {
+    let mock = MockUpstream::start().unwrap();
+    mock.respond(
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record(
+            "www.example.com",
+            Ipv4Addr::new(192, 0, 2, 1),
+            300,
+        )),
+    );
+
+    let cache = Cache::new();
+    let config = ResolverConfig {
+        blocklist: Blocklist::from_lines("ads.example.com", true),
+        ..forwarding_to(&mock)
+    };
+
+    let blocked = exchange(
+        &mut Packet::query("x.ads.example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    assert_eq!(blocked.header.rcode, ResponseCode::NXDOMAIN);
+    assert_eq!(mock.queries(), 0);
+
+    let allowed = exchange(
+        &mut Packet::query("www.example.com", QueryType::A),
+        &cache,
+        &config,
+    );
+    assert_eq!(allowed.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(allowed.answers.len(), 1);
+    assert_eq!(mock.queries(), 1);
+}
+
+#[test]
+fn zone_records_are_answered_authoritatively() {
+    let config = ResolverConfig {
+        zones: vec![Zone::parse("router.lan 300 A 192.168.1.1\n").unwrap()],
+        ..ResolverConfig::default()
+    };
+
+    let response = reply(Packet::query("router.lan", QueryType::A), &config);
+
+    assert!(response.header.aa);
+    assert_eq!(
+        response.answers,
+        vec![a_record("router.lan", Ipv4Addr::new(192, 168, 1, 1), 300)]
+    );
+}
+
+#[test]
+fn chaos_class_query_is_forwarded_and_echoed() {
+    let mock = MockUpstream::start().unwrap();
+    let version = Record::TXT {
+        domain: "version.bind".to_string(),
+        strings: vec!["mock 1.0".to_string()],
+        class: QueryClass::CH,
+        ttl: 300,
+    };
+    mock.respond(
+        "version.bind",
+        QueryType::TXT,
+        Packet::new().with_answer(version.clone()),
+    );
+
+    let cache = Cache::new();
+    let config = forwarding_to(&mock);
+
+    for queries in 1..=2 {
+        let mut request = Packet::query_with_class("version.bind", QueryType::TXT, QueryClass::CH);
+        let response = exchange(&mut request, &cache, &config);
+
+        assert_eq!(response.questions[0].qclass, QueryClass::CH);
+        assert_eq!(response.answers, vec![version.clone()]);
+        assert_eq!(
+            mock.last_request().unwrap().questions[0].qclass,
+            QueryClass::CH
+        );
+        // only the Internet class is cached
+        assert_eq!(mock.queries(), queries);
+    }
+}
+
+#[test]
+fn unsupported_opcode_is_notimp() {
+    let mut request = Packet::query("example.com", QueryType::A);
+    request.header.opcode = 5;
+
+    let response = reply(request, &ResolverConfig::default());
+
+    assert_eq!(response.header.rcode, ResponseCode::NOTIMP);
+    assert_eq!(response.header.opcode, 5);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn two_question_query_is_formerr() {
+    let request = Packet::query("example.com", QueryType::A)
+        .with_question(Question::new("example.net".to_string(), QueryType::A));
+
+    let response = reply(request, &ResolverConfig::default());
+
+    assert_eq!(response.header.rcode, ResponseCode::FORMERR);
+    assert_eq!(response.questions.len(), 2);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn endless_delegation_ends_in_servfail() {
+    let nameserver = |i: u8| Ipv4Addr::new(10, 0, 0, i);
+    let referral = |i: u8| {
+        let host = format!("ns{}.example.com", i);
+
+        Packet::new()
+            .with_authority(ns_record("example.com", &host))
+            .with_addition(a_record(&host, nameserver(i), 3600))
+    };
+
+    // every server hands the query on to a server it has not seen yet
+    let resolver = MockResolver::new();
+    resolver.respond(
+        IpAddr::V4(ROOT),
+        "www.example.com",
+        QueryType::A,
+        referral(1),
+    );
+    for i in 1..64 {
+        resolver.respond(
+            IpAddr::V4(nameserver(i)),
+            "www.example.com",
+            QueryType::A,
+            referral(i + 1),
+        );
+    }
+
+    let config = recursing_from_root();
+    let result = recursive_lookup("www.example.com", QueryType::A, &resolver, &config);
+
+    assert!(result.is_err());
+    assert!(resolver.queries().len() <= MAX_NS_CONTACTED + 1);
+
+    let request = Packet::query("www.example.com", QueryType::A);
+    let response = complete_answer(
+        Packet::response_to(&request),
+        &request.questions[0],
+        result,
+        &Cache::new(),
+        &config,
+    );
+    assert_eq!(response.header.rcode, ResponseCode::SERVFAIL);
+}
+
+#[test]
+fn referral_chain_deeper_than_limit_fails_on_depth() {
+    let nameserver = |i: u8| Ipv4Addr::new(10, 0, 0, i);
+    let referral = |i: u8| {
+        let host = format!("ns{}.example.com", i);
+
+        Packet::new()
+            .with_authority(ns_record("example.com", &host))
+            .with_addition(a_record(&host, nameserver(i), 3600))
+    };
+
+    // each referral comes with glue, so neither the sub-lookup nor the
+    // contacted ns budget is spent before the depth limit
+    let resolver = MockResolver::new();
+    resolver.respond(
+        IpAddr::V4(ROOT),
+        "www.example.com",
+        QueryType::A,
+        referral(1),
+    );
+    for i in 1..24 {
+        resolver.respond(
+            IpAddr::V4(nameserver(i)),
+            "www.example.com",
+            QueryType::A,
+            referral(i + 1),
+        );
+    }
+
+    let result = recursive_lookup(
+        "www.example.com",
+        QueryType::A,
+        &resolver,
+        &recursing_from_root(),
+    );
+
+    match result {
+        Err(DnsError::Resolution(reason)) => assert_eq!(
+            reason,
+            format!("recursion depth exceeds {}", MAX_RECURSION_DEPTH)
+        ),
+        result => panic!("expected depth error, got {:?}", result),
+    }
+    assert_eq!(
+        resolver.queries().len(),
+        usize::from(MAX_RECURSION_DEPTH) + 1
+    );
+}
+
+#[test]
+fn cname_chain_is_followed_to_address() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(1, 2, 3, 4);
+    let cname = cname_record("alias.example.com", "canonical.example.com", 300);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "alias.example.com",
+        QueryType::A,
+        Packet::new().with_answer(cname.clone()),
+    );
+    resolver.respond(
+        root,
+        "canonical.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("canonical.example.com", address, 300)),
+    );
+
+    let config = recursing_from_root();
+    let response = recursive_lookup("alias.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![cname, a_record("canonical.example.com", address, 300)]
+    );
+    assert_eq!(response.get_random_a(), Some(address));
+}
+
+#[test]
+fn concurrent_clients_each_get_their_own_answer() {
+    const WORKERS: usize = 4;
+    const CLIENTS: u8 = 8;
+    const QUERIES: usize = 25;
+
+    let records = (1..=CLIENTS)
+        .map(|i| a_record(&format!("host{}.lan", i), Ipv4Addr::new(192, 0, 2, i), 300))
+        .collect();
+    let config = Arc::new(ResolverConfig {
+        zones: vec![Zone::new(records)],
+        ..ResolverConfig::default()
+    });
+    let cache = Arc::new(Cache::new());
+    let limiter = Arc::new(RateLimiter::unlimited());
+    let running = Arc::new(AtomicBool::new(true));
+
+    let socket = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(50)))
+        .unwrap();
+    let address = socket.local_addr().unwrap();
+
+    let workers = (0..WORKERS)
+        .map(|_| {
+            let socket = socket.try_clone().unwrap();
+            let (cache, limiter, config, running) = (
+                Arc::clone(&cache),
+                Arc::clone(&limiter),
+                Arc::clone(&config),
+                Arc::clone(&running),
+            );
+
+            std::thread::spawn(move || {
+                while running.load(Ordering::SeqCst) {
+                    match handle_query(&socket, &cache, &limiter, &config) {
+                        Err(e) if !is_poll_timeout(&e) => panic!("{}", e),
+                        _ => {}
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let clients = (1..=CLIENTS)
+        .map(|i| {
+            std::thread::spawn(move || {
+                let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_secs(5)))
+                    .unwrap();
+                let qname = format!("host{}.lan", i);
+
+                for _ in 0..QUERIES {
+                    let mut request = Packet::query(&qname, QueryType::A);
+                    let mut data = [0; 512];
+                    let len = request.write_into(&mut data).unwrap();
+                    client.send_to(&data[..len], address).unwrap();
+
+                    let len = client.recv(&mut data).unwrap();
+                    let response = Packet::from_bytes(&data[..len]).unwrap();
+
+                    assert_eq!(response.header.id, request.header.id);
+                    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, i)));
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    running.store(false, Ordering::SeqCst);
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    assert_eq!(
+        config.stats.snapshot().queries_total,
+        CLIENTS as u64 * QUERIES as u64
+    );
+}
+
+#[test]
+fn source_ports_are_drawn_from_the_configured_range() {
+    let config = ResolverConfig {
+        source_ports: Some(50000..=50999),
+        ..ResolverConfig::default()
+    };
+    let server = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    // held open together, so every bind has to find a port not in use
+    let sockets = (0..8)
+        .map(|_| bind_socket(server, &config).unwrap())
+        .collect::<Vec<_>>();
+    let ports = sockets
+        .iter()
+        .map(|socket| socket.local_addr().unwrap().port())
+        .collect::<HashSet<_>>();
+
+    assert_eq!(ports.len(), sockets.len());
+    assert!(ports.iter().all(|port| (50000..=50999).contains(port)));
+}
+
+#[test]
+fn concurrent_lookups_use_separate_sockets() {
+    let mock = MockUpstream::start().unwrap();
+    for i in 1..=2 {
+        let qname = format!("host{}.example.com", i);
+        mock.respond(
+            &qname,
+            QueryType::A,
+            Packet::new().with_answer(a_record(&qname, Ipv4Addr::new(192, 0, 2, i), 300)),
+        );
+    }
+
+    let config = ResolverConfig {
+        source_ports: Some(50000..=50999),
+        ..forwarding_to(&mock)
+    };
+    let server = (mock.address().ip(), mock.address().port());
+
+    std::thread::scope(|scope| {
+        let lookups = (1..=2)
+            .map(|i| {
+                let config = &config;
+                scope.spawn(move || {
+                    let qname = format!("host{}.example.com", i);
+                    let response =
+                        lookup(&qname, QueryType::A, QueryClass::IN, server, config).unwrap();
+
+                    assert_eq!(response.get_random_a(), Some(Ipv4Addr::new(192, 0, 2, i)));
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for lookup in lookups {
+            lookup.join().unwrap();
+        }
+    });
+
+    assert_eq!(mock.queries(), 2);
+}
+
+#[test]
+fn response_must_match_id_source_and_question() {
+    let server = (IpAddr::V4(Ipv4Addr::LOCALHOST), 53);
+    let src = SocketAddr::from(server);
+    let request = Packet::query("example.com", QueryType::A);
+
+    let mut matching = Packet::response_to(&request);
+    assert!(accept_response(&encode(&mut matching), src, server, &request).is_some());
+
+    let mut wrong_id = Packet::response_to(&request);
+    wrong_id.header.id = request.header.id.wrapping_add(1);
+    assert!(accept_response(&encode(&mut wrong_id), src, server, &request).is_none());
+
+    let stranger = SocketAddr::from(([127, 0, 0, 2], 53));
+    assert!(accept_response(&encode(&mut matching), stranger, server, &request).is_none());
+
+    let mut wrong_type = Packet::response_to(&request);
+    wrong_type.questions[0].qtype = QueryType::AAAA;
+    assert!(accept_response(&encode(&mut wrong_type), src, server, &request).is_none());
+}
+
+#[test]
+fn refused_and_timed_out_upstreams_are_counted_separately() {
+    // nothing listens on a port once its socket is closed
+    let refused = UdpSocket::bind(("127.0.0.1", 0))
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let silent_address = silent.local_addr().unwrap();
+
+    let config = ResolverConfig {
+        timeout: Duration::from_millis(100),
+        retries: 0,
+        ..ResolverConfig::default()
+    };
+
+    for server in [refused, silent_address] {
+        let result = lookup(
+            "example.com",
+            QueryType::A,
+            QueryClass::IN,
+            (server.ip(), server.port()),
+            &config,
+        );
+        assert!(result.is_err());
+    }
+
+    let mut expected = vec![
+        (refused, UpstreamError::ConnectionRefused, 1),
+        (silent_address, UpstreamError::Timeout, 1),
+    ];
+    expected.sort();
+    assert_eq!(config.stats.upstream_errors(), expected);
+
+    let metrics = config.stats.to_prometheus();
+    assert!(metrics.contains(&format!(
+        "dns_upstream_errors_total{{upstream=\"{}\",class=\"connection_refused\"}} 1",
+        refused
+    )));
+    assert!(metrics.contains(&format!(
+        "dns_upstream_errors_total{{upstream=\"{}\",class=\"timeout\"}} 1",
+        silent_address
+    )));
+}
+
+#[test]
+fn malformed_and_failing_upstreams_are_classified() {
+    let garbled = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let garbled_address = garbled.local_addr().unwrap();
+    let responder = thread::spawn(move || {
+        let mut data = [0; 512];
+        let (_, src) = garbled.recv_from(&mut data).unwrap();
+        garbled.send_to(b"\x12\x34\x81", src).unwrap();
+    });
+
+    let mock = MockUpstream::start().unwrap();
+    let mut failure = Packet::new();
+    failure.header.rcode = ResponseCode::SERVFAIL;
+    mock.respond("example.com", QueryType::A, failure);
+
+    let config = ResolverConfig {
+        timeout: Duration::from_millis(200),
+        retries: 0,
+        ..ResolverConfig::default()
+    };
+
+    let result = lookup(
+        "example.com",
+        QueryType::A,
+        QueryClass::IN,
+        (garbled_address.ip(), garbled_address.port()),
+        &config,
+    );
+    assert!(matches!(
+        result,
+        Err(DnsError::Upstream {
+            kind: UpstreamError::MalformedResponse,
+            ..
+        })
+    ));
+    responder.join().unwrap();
+
+    let server = (mock.address().ip(), mock.address().port());
+    let response = lookup("example.com", QueryType::A, QueryClass::IN, server, &config).unwrap();
+    assert_eq!(response.header.rcode, ResponseCode::SERVFAIL);
+
+    let mut expected = vec![
+        (garbled_address, UpstreamError::MalformedResponse, 1),
+        (mock.address(), UpstreamError::Rcode, 1),
+    ];
+    expected.sort();
+    assert_eq!(config.stats.upstream_errors(), expected);
+}
+
+#[test]
+fn silent_upstream_times_out_after_retries() {
+    let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let address = silent.local_addr().unwrap();
+
+    let config = ResolverConfig {
+        timeout: Duration::from_millis(100),
+        retries: 2,
+        ..ResolverConfig::default()
+    };
+
+    let started = Instant::now();
+    let result = lookup(
+        "example.com",
+        QueryType::A,
+        QueryClass::IN,
+        (address.ip(), address.port()),
+        &config,
+    );
+    let elapsed = started.elapsed();
+
+    assert!(result.is_err());
+    assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    assert_eq!(config.stats.snapshot().upstream_timeouts, 3);
+
+    // one send per attempt
+    silent.set_nonblocking(true).unwrap();
+    let mut data = [0; 512];
+    let attempts = std::iter::from_fn(|| silent.recv(&mut data).ok()).count();
+    assert_eq!(attempts, 3);
+}
+
+#[test]
+fn forwarding_falls_over_to_next_forwarder() {
+    let silent = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let failing = MockUpstream::start().unwrap();
+    let working = MockUpstream::start().unwrap();
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let mut servfail = Packet::new();
+    servfail.header.rcode = ResponseCode::SERVFAIL;
+    failing.respond("example.com", QueryType::A, servfail);
+    working.respond(
+        "example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("example.com", address, 300)),
+    );
+
+    let config = ResolverConfig {
+        forwarders: vec![
+            silent.local_addr().unwrap(),
+            failing.address(),
+            working.address(),
+        ],
+        ..forwarding_to(&working)
+    };
+    assert!(config.is_forward_only());
+
+    let response = exchange(
+        &mut Packet::query("example.com", QueryType::A),
+        &Cache::new(),
+        &config,
+    );
+
+    assert_eq!(response.header.rcode, ResponseCode::NOERROR);
+    assert_eq!(response.get_random_a(), Some(address));
+    assert_eq!(failing.queries(), 1);
+    assert_eq!(working.queries(), 1);
+}
+
+#[test]
+fn glueless_delegation_chain_is_capped_by_budget() {
+    let root = IpAddr::V4(ROOT);
+    let host = |i: usize| format!("ns.zone{}.test", i);
+
+    // resolving each nameserver name needs yet another glueless nameserver
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_authority(ns_record("example.com", &host(0))),
+    );
+    for i in 0..32 {
+        resolver.respond(
+            root,
+            &host(i),
+            QueryType::A,
+            Packet::new().with_authority(ns_record(&format!("zone{}.test", i), &host(i + 1))),
+        );
+    }
+
+    let config = recursing_from_root();
+    let error = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap_err();
+
+    assert!(error.to_string().contains("sub-lookups"), "{}", error);
+    assert_eq!(resolver.queries().len(), MAX_SUB_LOOKUPS + 1);
+}
+
+#[test]
+fn lame_nameserver_is_skipped() {
+    let root = IpAddr::V4(ROOT);
+    let lame = Ipv4Addr::new(192, 0, 2, 53);
+    let working = Ipv4Addr::new(192, 0, 2, 54);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("example.com", "ns1.example.com"))
+            .with_authority(ns_record("example.com", "ns2.example.com"))
+            .with_addition(a_record("ns1.example.com", lame, 3600))
+            .with_addition(a_record("ns2.example.com", working, 3600)),
+    );
+    // neither an answer nor a referral, nor authoritative
+    resolver.respond(
+        IpAddr::V4(lame),
+        "www.example.com",
+        QueryType::A,
+        Packet::new(),
+    );
+    resolver.respond(
+        IpAddr::V4(working),
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www.example.com", address, 300)),
+    );
+
+    let config = recursing_from_root();
+    // measured as the faster server, so that it is tried first
+    config
+        .rtt
+        .record(IpAddr::V4(lame), Duration::from_millis(1));
+    config
+        .rtt
+        .record(IpAddr::V4(working), Duration::from_millis(50));
+
+    let response = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(response.get_random_a(), Some(address));
+    assert_eq!(
+        resolver.queries()[1..],
+        [
+            (
+                IpAddr::V4(lame),
+                "www.example.com".to_string(),
+                QueryType::A
+            ),
+            (
+                IpAddr::V4(working),
+                "www.example.com".to_string(),
+                QueryType::A
+            ),
+        ]
+    );
+}
+
+#[test]
+fn resolve_records_distinguishes_outcomes() {
+    let root = IpAddr::V4(ROOT);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let with_rcode = |rcode| {
+        let mut packet = Packet::new();
+        packet.header.aa = true;
+        packet.header.rcode = rcode;
+        packet
+    };
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new().with_answer(a_record("www.example.com", address, 300)),
+    );
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::AAAA,
+        with_rcode(ResponseCode::NOERROR),
+    );
+    resolver.respond(
+        root,
+        "nx.example.com",
+        QueryType::A,
+        with_rcode(ResponseCode::NXDOMAIN).with_authority(soa_record("example.com", 3600, 300)),
+    );
+    resolver.respond(
+        root,
+        "broken.example.com",
+        QueryType::A,
+        with_rcode(ResponseCode::SERVFAIL),
+    );
+
+    let config = recursing_from_root();
+    let outcome = |qname, qtype| resolve_records(qname, qtype, &resolver, &config).unwrap();
+
+    assert_eq!(
+        outcome("www.example.com", QueryType::A),
+        ResolveOutcome::Records(vec![a_record("www.example.com", address, 300)])
+    );
+    assert_eq!(
+        outcome("www.example.com", QueryType::AAAA),
+        ResolveOutcome::NoData
+    );
+    assert_eq!(
+        outcome("nx.example.com", QueryType::A),
+        ResolveOutcome::NxDomain
+    );
+    assert_eq!(
+        outcome("broken.example.com", QueryType::A),
+        ResolveOutcome::ServFail
+    );
+
+    assert_eq!(
+        resolve("www.example.com", QueryType::AAAA, &resolver, &config).unwrap(),
+        Vec::new()
+    );
+    assert!(resolve("nx.example.com", QueryType::A, &resolver, &config).is_err());
+}
+
+#[test]
+fn out_of_bailiwick_records_are_stripped() {
+    let root = IpAddr::V4(ROOT);
+    let ns = Ipv4Addr::new(192, 0, 2, 53);
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let poison = a_record("bank.test", Ipv4Addr::new(203, 0, 113, 66), 86400);
+
+    let resolver = MockResolver::new();
+    resolver.respond(
+        root,
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_authority(ns_record("example.com", "ns1.example.com"))
+            .with_addition(a_record("ns1.example.com", ns, 3600)),
+    );
+    // the example.com servers try to slip in an address for another zone
+    resolver.respond(
+        IpAddr::V4(ns),
+        "www.example.com",
+        QueryType::A,
+        Packet::new()
+            .with_answer(a_record("www.example.com", address, 300))
+            .with_answer(poison.clone())
+            .with_addition(poison),
+    );
+
+    let config = recursing_from_root();
+    let response = recursive_lookup("www.example.com", QueryType::A, &resolver, &config).unwrap();
+
+    assert_eq!(
+        response.answers,
+        vec![a_record("www.example.com", address, 300)]
+    );
+    assert!(response.additions.is_empty());
+}
+
+// a 300-byte query whose additional record claims `overrun` more bytes of
+// rdata than the datagram holds
+fn datagram_with_overrun(overrun: usize) -> Vec<u8> {
+    let mut data = encode(&mut Packet::query("example.com", QueryType::A));
+    // ARCOUNT
+    data[11] = 1;
+    // root owner, TYPE99, IN, TTL 300
+    data.extend_from_slice(b"\x00\x00\x63\x00\x01\x00\x00\x01\x2c");
+
+    let rdlength = 300 - data.len() - 2;
+    data.extend_from_slice(&((rdlength + overrun) as u16).to_be_bytes());
+    data.resize(300, 0xAB);
+
+    data
+}
+
+#[test]
+fn reads_stop_at_end_of_datagram() {
+    let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    server
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let address = server.local_addr().unwrap();
+
+    client.send_to(&datagram_with_overrun(0), address).unwrap();
+    let (query, _) = recv_query(&server).unwrap();
+    assert_eq!(query.additions.len(), 1);
+
+    // the receive buffer is far larger than the datagram, but its unused
+    // tail must not be read as rdata
+    client.send_to(&datagram_with_overrun(1), address).unwrap();
+    assert!(matches!(recv_query(&server), Err(DnsError::EndOfBuffer)));
+}
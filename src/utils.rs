@@ -1,3 +1,66 @@
-type Error = Box<dyn std::error::Error>;
+use crate::error::DnsError;
+use rand::Rng;
+use std::iter;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
-pub type Result<T> = std::result::Result<T, Error>;
+pub type Result<T> = std::result::Result<T, DnsError>;
+
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// the name itself followed by each of its parents, up to the top-level domain
+pub fn suffixes(name: &str) -> impl Iterator<Item = &str> {
+    let name = name.trim_end_matches('.');
+
+    iter::successors((!name.is_empty()).then_some(name), |name| {
+        name.split_once('.').map(|(_, parent)| parent)
+    })
+}
+
+pub fn is_subdomain_of(name: &str, zone: &str) -> bool {
+    let zone = zone.trim_end_matches('.');
+
+    zone.is_empty() || suffixes(name).any(|suffix| suffix.eq_ignore_ascii_case(zone))
+}
+
+pub fn randomize_case(name: &str) -> String {
+    let mut rng = rand::thread_rng();
+
+    name.chars()
+        .map(|c| {
+            if rng.gen() {
+                c.to_ascii_uppercase()
+            } else {
+                c.to_ascii_lowercase()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subdomain_match_is_label_wise_and_case_insensitive() {
+        assert!(is_subdomain_of("www.example.com", "example.com"));
+        assert!(is_subdomain_of("example.com.", "EXAMPLE.com"));
+        assert!(is_subdomain_of("WWW.Example.COM", "example.com."));
+        assert!(is_subdomain_of("example.com", ""));
+
+        // a shared string suffix is not a shared label suffix
+        assert!(!is_subdomain_of("example.com", "ample.com"));
+        assert!(!is_subdomain_of("www.example.com", "le.com"));
+        assert!(!is_subdomain_of("com", "example.com"));
+    }
+
+    #[test]
+    fn suffixes_walk_up_to_the_top_level_domain() {
+        assert_eq!(
+            suffixes("www.example.com.").collect::<Vec<_>>(),
+            vec!["www.example.com", "example.com", "com"]
+        );
+        assert_eq!(suffixes(".").count(), 0);
+    }
+}
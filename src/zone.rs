@@ -0,0 +1,466 @@
+use crate::dns::{QueryClass, QueryType, Record};
+use crate::error::DnsError;
+use crate::utils::{is_subdomain_of, Result};
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Zone {
+    /// the name the zone is rooted at, empty for a zone holding any name
+    pub apex: String,
+    /// records answered authoritatively without consulting upstream
+    pub records: Vec<Record>,
+}
+
+impl Zone {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self::with_apex("", records)
+    }
+
+    pub fn with_apex(apex: &str, records: Vec<Record>) -> Self {
+        Self {
+            apex: apex.trim_end_matches('.').to_lowercase(),
+            records,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut zone = Self::default();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(origin) = line.strip_prefix("$ORIGIN") {
+                zone.apex = origin.trim().trim_end_matches('.').to_lowercase();
+                continue;
+            }
+
+            let record = parse_record(number + 1, line)?;
+
+            if !is_subdomain_of(record.domain(), &zone.apex) {
+                return Err(DnsError::ZoneSyntax {
+                    line: number + 1,
+                    reason: format!("{} is outside the zone {}", record.domain(), zone.apex),
+                });
+            }
+
+            zone.records.push(record);
+        }
+
+        Ok(zone)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        is_subdomain_of(name, &self.apex)
+    }
+
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<Vec<Record>> {
+        let qname = qname.trim_end_matches('.');
+
+        let mut owned = self
+            .records
+            .iter()
+            .filter(|record| record.domain().eq_ignore_ascii_case(qname))
+            .peekable();
+
+        owned.peek()?;
+
+        Some(
+            owned
+                .filter(|record| {
+                    qtype == QueryType::ANY
+                        || record.qtype() == qtype
+                        || record.qtype() == QueryType::CNAME
+                })
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Answers from the most specific zone holding the name, so that a zone
+/// nested inside another one takes precedence over it.
+pub fn answer(zones: &[Zone], qname: &str, qtype: QueryType) -> Option<Vec<Record>> {
+    let mut best: Option<&Zone> = None;
+
+    for zone in zones.iter().filter(|zone| zone.contains(qname)) {
+        if best.is_none_or(|best| labels(&zone.apex) > labels(&best.apex)) {
+            best = Some(zone);
+        }
+    }
+
+    best?.answer(qname, qtype)
+}
+
+/// Answers a reverse lookup with the names whose A or AAAA records hold the
+/// address, once a zone holding the reverse name is declared.
+pub fn answer_ptr(zones: &[Zone], qname: &str) -> Option<Vec<Record>> {
+    let qname = qname.trim_end_matches('.').to_lowercase();
+    let address = reverse_address(&qname)?;
+
+    // a zone holding any name does not make every reverse name local
+    if !zones
+        .iter()
+        .any(|zone| !zone.apex.is_empty() && zone.contains(&qname))
+    {
+        return None;
+    }
+
+    let mut records = Vec::new();
+
+    for record in zones.iter().flat_map(|zone| &zone.records) {
+        let (domain, ttl) = match record {
+            Record::A {
+                domain,
+                address: a,
+                ttl,
+                ..
+            } if IpAddr::V4(*a) == address => (domain, *ttl),
+            Record::AAAA {
+                domain,
+                address: a,
+                ttl,
+                ..
+            } if IpAddr::V6(*a) == address => (domain, *ttl),
+            _ => continue,
+        };
+
+        let ptr = Record::PTR {
+            domain: qname.clone(),
+            host: domain.clone(),
+            class: QueryClass::IN,
+            ttl,
+        };
+
+        if !records.contains(&ptr) {
+            records.push(ptr);
+        }
+    }
+
+    (!records.is_empty()).then_some(records)
+}
+
+// the address a full in-addr.arpa or ip6.arpa name stands for
+fn reverse_address(name: &str) -> Option<IpAddr> {
+    if let Some(octets) = name.strip_suffix(".in-addr.arpa") {
+        let octets = octets
+            .rsplit('.')
+            .map(|octet| octet.parse::<u8>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+
+        return Some(IpAddr::V4(Ipv4Addr::from(octets)));
+    }
+
+    let nibbles = name.strip_suffix(".ip6.arpa")?;
+    let mut address = 0u128;
+    let mut count = 0;
+
+    for nibble in nibbles.rsplit('.') {
+        let mut chars = nibble.chars();
+        let digit = chars.next()?.to_digit(16)?;
+
+        if chars.next().is_some() {
+            return None;
+        }
+
+        address = (address << 4) | u128::from(digit);
+        count += 1;
+    }
+
+    (count == 32).then(|| IpAddr::V6(Ipv6Addr::from(address)))
+}
+
+fn labels(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+fn parse_record(number: usize, line: &str) -> Result<Record> {
+    let invalid = |reason: String| DnsError::ZoneSyntax {
+        line: number,
+        reason,
+    };
+
+    let (domain, rest) = next_field(line);
+    let (ttl, rest) = next_field(rest);
+    let (rtype, value) = next_field(rest);
+
+    if rtype.is_empty() {
+        return Err(invalid("expected name, ttl, type and value".to_string()));
+    }
+
+    // kept as written, as quoted TXT strings may contain runs of spaces
+    let value = value.trim();
+
+    if value.is_empty() {
+        return Err(invalid(format!("missing value for {}", domain)));
+    }
+
+    let domain = domain.trim_end_matches('.').to_lowercase();
+    let ttl = ttl
+        .parse::<u32>()
+        .map_err(|_| invalid(format!("invalid ttl {:?}", ttl)))?;
+    let class = QueryClass::IN;
+
+    let qtype = rtype
+        .parse::<QueryType>()
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let record = match qtype {
+        QueryType::A => Record::A {
+            domain,
+            address: value
+                .parse()
+                .map_err(|_| invalid(format!("invalid IPv4 address {:?}", value)))?,
+            class,
+            ttl,
+        },
+        QueryType::AAAA => Record::AAAA {
+            domain,
+            address: value
+                .parse()
+                .map_err(|_| invalid(format!("invalid IPv6 address {:?}", value)))?,
+            class,
+            ttl,
+        },
+        QueryType::CNAME => Record::CNAME {
+            domain,
+            host: value.trim_end_matches('.').to_lowercase(),
+            class,
+            ttl,
+        },
+        QueryType::TXT => Record::TXT {
+            domain,
+            strings: character_strings(value).map_err(invalid)?,
+            class,
+            ttl,
+        },
+        _ => return Err(invalid(format!("unsupported record type {}", qtype))),
+    };
+
+    Ok(record)
+}
+
+fn next_field(line: &str) -> (&str, &str) {
+    let line = line.trim_start();
+    let end = line.find(char::is_whitespace).unwrap_or(line.len());
+
+    line.split_at(end)
+}
+
+// a TXT value is one or more character-strings, each either quoted (so that
+// it can hold spaces, with \" and \\ escapes) or a bare word
+fn character_strings(value: &str) -> std::result::Result<Vec<String>, String> {
+    let mut strings = Vec::new();
+    let mut chars = value.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut string = String::new();
+
+        match chars.next() {
+            Some('"') => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some(c) => string.push(c),
+                        None => return Err(format!("unterminated string in {}", value)),
+                    },
+                    Some(c) => string.push(c),
+                    None => return Err(format!("unterminated string in {}", value)),
+                }
+            },
+            Some(c) => {
+                string.push(c);
+
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    string.push(c);
+                }
+            }
+            None => return Ok(strings),
+        }
+
+        if string.len() > 0xFF {
+            return Err(format!(
+                "character-string of {} bytes exceeds 255 bytes",
+                string.len()
+            ));
+        }
+
+        strings.push(string);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{a_record, cname_record, txt_record};
+    use std::net::Ipv4Addr;
+
+    const ZONE: &str = "\
+# local names
+router.lan.     300  A      192.168.1.1
+Printer.LAN     300  AAAA   fd00::10
+nas.lan         60   CNAME  router.lan.
+router.lan      300  TXT    \"home router\"
+";
+
+    #[test]
+    fn zone_text_is_parsed_and_answered() {
+        let zone = Zone::parse(ZONE).unwrap();
+        assert_eq!(zone.records.len(), 4);
+
+        assert_eq!(
+            zone.answer("ROUTER.lan.", QueryType::A),
+            Some(vec![a_record(
+                "router.lan",
+                Ipv4Addr::new(192, 168, 1, 1),
+                300
+            )])
+        );
+        assert_eq!(zone.answer("router.lan", QueryType::ANY).unwrap().len(), 2);
+        assert_eq!(
+            zone.answer("nas.lan", QueryType::A),
+            Some(vec![cname_record("nas.lan", "router.lan", 60)])
+        );
+        // a name in the zone without the type is NODATA, not a miss
+        assert_eq!(zone.answer("printer.lan", QueryType::A), Some(Vec::new()));
+        assert_eq!(zone.answer("missing.lan", QueryType::A), None);
+    }
+
+    #[test]
+    fn txt_value_is_split_into_character_strings() {
+        let zone = Zone::parse(
+            "spf.lan 300 TXT \"v=spf1  -all\" \"a \\\"quoted\\\" word\" bare\n\
+             pair.lan 300 TXT \"a\" \"b\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            zone.answer("spf.lan", QueryType::TXT),
+            Some(vec![txt_record(
+                "spf.lan",
+                vec![
+                    "v=spf1  -all".to_string(),
+                    "a \"quoted\" word".to_string(),
+                    "bare".to_string(),
+                ]
+            )])
+        );
+        assert_eq!(
+            zone.answer("pair.lan", QueryType::TXT),
+            Some(vec![txt_record(
+                "pair.lan",
+                vec!["a".to_string(), "b".to_string()]
+            )])
+        );
+        assert!(matches!(
+            Zone::parse("open.lan 300 TXT \"a\" \"b"),
+            Err(DnsError::ZoneSyntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            Zone::parse(&format!("long.lan 300 TXT {}", "x".repeat(256))),
+            Err(DnsError::ZoneSyntax { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn most_specific_overlapping_zone_answers() {
+        let parent = Zone::parse(
+            "$ORIGIN example.com.\n\
+             www.sub.example.com 300 A 192.0.2.1\n\
+             www.example.com 300 A 192.0.2.2\n",
+        )
+        .unwrap();
+        let child = Zone::with_apex(
+            "Sub.Example.com",
+            vec![a_record(
+                "www.sub.example.com",
+                Ipv4Addr::new(192, 0, 2, 3),
+                60,
+            )],
+        );
+        assert_eq!(parent.apex, "example.com");
+
+        // the order zones are configured in does not matter
+        for zones in [vec![parent.clone(), child.clone()], vec![child, parent]] {
+            assert_eq!(
+                answer(&zones, "www.sub.example.com", QueryType::A),
+                Some(vec![a_record(
+                    "www.sub.example.com",
+                    Ipv4Addr::new(192, 0, 2, 3),
+                    60
+                )])
+            );
+            assert_eq!(
+                answer(&zones, "www.example.com", QueryType::A),
+                Some(vec![a_record(
+                    "www.example.com",
+                    Ipv4Addr::new(192, 0, 2, 2),
+                    300
+                )])
+            );
+            assert_eq!(answer(&zones, "www.example.net", QueryType::A), None);
+        }
+
+        assert!(matches!(
+            Zone::parse("$ORIGIN example.com\nwww.example.net 300 A 192.0.2.1\n"),
+            Err(DnsError::ZoneSyntax { line: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn ptr_is_synthesized_only_under_a_declared_reverse_zone() {
+        let forward = Zone::parse(
+            "host.example.com 300 A 10.0.0.5\n\
+             host.example.com 300 AAAA 2001:db8::5\n",
+        )
+        .unwrap();
+        let reverse_v4 = Zone::with_apex("10.in-addr.arpa", Vec::new());
+        let reverse_v6 = Zone::with_apex("8.b.d.0.1.0.0.2.ip6.arpa", Vec::new());
+        let v6_name = "5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+
+        let zones = [forward.clone(), reverse_v4, reverse_v6];
+
+        assert_eq!(
+            answer_ptr(&zones, "5.0.0.10.in-addr.arpa."),
+            Some(vec![Record::PTR {
+                domain: "5.0.0.10.in-addr.arpa".to_string(),
+                host: "host.example.com".to_string(),
+                class: QueryClass::IN,
+                ttl: 300,
+            }])
+        );
+        assert_eq!(
+            answer_ptr(&zones, v6_name).map(|records| records.len()),
+            Some(1)
+        );
+        assert_eq!(answer_ptr(&zones, "6.0.0.10.in-addr.arpa"), None);
+        assert_eq!(answer_ptr(&zones, "0.10.in-addr.arpa"), None);
+
+        // without the reverse zone the name is left to upstream
+        assert_eq!(answer_ptr(&[forward], "5.0.0.10.in-addr.arpa"), None);
+    }
+
+    #[test]
+    fn syntax_errors_report_the_line() {
+        let error =
+            Zone::parse("ok.lan 300 A 10.0.0.1\n\nbad.lan 300 A not-an-address\n").unwrap_err();
+
+        assert!(matches!(error, DnsError::ZoneSyntax { line: 3, .. }));
+        assert!(matches!(
+            Zone::parse("mx.lan 300 MX 10 mail.lan"),
+            Err(DnsError::ZoneSyntax { line: 1, .. })
+        ));
+    }
+}